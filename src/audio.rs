@@ -1,18 +1,36 @@
 // audio.rs - handling music playback
-use crate::config::{Config, Database};
+use crate::config::{Config, Database, GainMode, ReplayGainConfig, Station};
+use crate::filter::{Filter, ParseError};
+use crate::history::{self, Entry, Listen};
 use crate::playlist::PlayList;
-use crate::track::{Tag, Track};
-use crate::util::form_library_tree;
+use crate::playlist_file;
+use crate::podcast::{self, Feed};
+use crate::scrobble::{self, Scrobble, Scrobbler};
+use crate::session::{Playback, Session};
+use crate::track::{Bookmark, Chapter, Range, ReplayGain, Stats, Tag, Track, COLUMNS};
+use crate::util::{
+    datefmt, expand_path, find_audio, form_library_tree, format_history_entry, is_file, now,
+    search, timefmt, uri_to_path,
+};
 use gstreamer::prelude::*;
 use gstreamer::ClockTime;
 use gstreamer_player::{Player, PlayerGMainContextSignalDispatcher, PlayerSignalDispatcher};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::sync::{
+    atomic::{AtomicBool, Ordering},
     mpsc::{self, Receiver, Sender},
     Arc, Mutex,
 };
 use std::time::Duration;
 
+// Time into a chapter, in milliseconds, after which going back restarts it instead
+const CHAPTER_RESTART: u64 = 3000;
+// Time in milliseconds that playback can fall short of the start of a track's part of its file
+const RANGE_SLACK: u64 = 250;
+// Largest gain and headroom the ReplayGain volume element accepts, in dB
+const MAX_GAIN: f64 = 60.0;
+
 // Represents playback status
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PlaybackStatus {
@@ -22,13 +40,38 @@ pub enum PlaybackStatus {
 }
 
 // Represents loop status
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub enum LoopStatus {
     None,
     Track,
     Playlist,
 }
 
+impl Default for LoopStatus {
+    fn default() -> Self {
+        // Don't loop by default
+        Self::None
+    }
+}
+
+// Represents events emitted by the player
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    EndOfStream,
+    TrackChanged,
+    Error(String),
+}
+
+// Progress of importing files into the library
+#[derive(Debug, Default, Clone)]
+pub struct Import {
+    pub total: usize,
+    pub done: usize,
+    pub added: usize,
+    pub failed: Vec<String>,
+    pub finished: bool,
+}
+
 // Stores metadata to be transmitted between threads
 #[derive(Debug, Clone)]
 pub struct Metadata {
@@ -37,7 +80,25 @@ pub struct Metadata {
     pub shuffle_status: bool,
     pub volume: f64,
     pub position: (u64, u64, f64),
+    pub position_micros: u64,
+    pub seeked: Option<u64>,
     pub tag: Tag,
+    pub stats: Stats,
+    pub path: Option<String>,
+    pub art: Option<String>,
+    pub length: Option<u64>,
+    pub track_id: Option<usize>,
+    pub track_list: Vec<(usize, Track)>,
+    // Stored playlists by ID, and the one being played
+    pub playlist: Option<(usize, String)>,
+    pub playlists: Vec<(usize, String)>,
+    // Name of the radio station playing, and the title it gives for what it's playing
+    pub station: Option<String>,
+    pub stream_title: Option<String>,
+    // Error from the background threads, waiting to be shown
+    pub last_error: Option<String>,
+    // Playlists that have been renamed, waiting to be announced
+    pub changed_playlists: Vec<(usize, String)>,
 }
 
 // Main manager struct that handles everything
@@ -47,11 +108,32 @@ pub struct Manager {
     pub metadata: Arc<Mutex<Metadata>>,
     pub update_transmit: Sender<()>,
     pub mpris: Receiver<crate::mpris::Event>,
+    pub events: Receiver<PlayerEvent>,
     pub config: Config,
     pub database: Database,
     pub library_tree: BTreeMap<String, BTreeMap<String, Vec<usize>>>,
     // TODO: Replace use of channels with mutexes on this variable.
     pub updated: bool,
+    pub library_updated: bool,
+    // Everything that has been listened to, oldest first, and the listen going on now
+    pub history: Vec<Entry>,
+    listen: Option<Listen>,
+    // Index of the radio station playing, which takes over from the play queue
+    pub station: Option<usize>,
+    // Feed and episode of the podcast playing, which also take over from the play queue
+    pub episode: Option<(usize, usize)>,
+    // Submits listens to the scrobbling services in the config
+    pub scrobbler: Scrobbler,
+    // URI of the track to switch to seamlessly once the current one is about to finish
+    gapless: Arc<Mutex<Option<String>>>,
+    // Position to jump to once the player knows how long the episode or audiobook it is loading is
+    resume: Arc<Mutex<Option<ClockTime>>>,
+    // Part of its file the current track takes up, which positions are measured from
+    range: Arc<Mutex<Option<Range>>>,
+    // Whether playback has passed the end of that part, so the next track is only moved onto once
+    ended: Arc<AtomicBool>,
+    // ReplayGain volume element in front of the audio sink, left out when ReplayGain is off
+    replay_gain: Option<gstreamer::Element>,
 }
 
 impl Manager {
@@ -60,15 +142,23 @@ impl Manager {
         gstreamer::init().unwrap();
         let dispatcher = PlayerGMainContextSignalDispatcher::new(None);
         let player = Player::new(None, Some(&dispatcher.upcast::<PlayerSignalDispatcher>()));
+        // Seek to exact positions, so tracks split out of a file start right where they should
+        let mut player_config = player.config();
+        player_config.set_seek_accurate(true);
+        player.set_config(player_config).ok();
         // Set up channel to recieve and send events
         let (_, rx) = mpsc::sync_channel(32);
-        // Placeholder channel
+        // Placeholder channels
         let (tx2, _) = mpsc::channel();
+        let (_, rx3) = mpsc::channel();
         // Get config and generate library tree
         let database = Database::open();
         let library_tree = form_library_tree(&database.tracks);
+        let config = Config::open();
+        let scrobbler = Scrobbler::new(&config.scrobbling);
+        let replay_gain = replay_gain_filter(&player, &config.replay_gain);
         // Initiate player
-        Self {
+        let mut manager = Self {
             // Create player
             player,
             // Initialise an empty playlist
@@ -80,23 +170,58 @@ impl Manager {
                 shuffle_status: false,
                 volume: 1.0,
                 position: (0, 0, 0.0),
+                position_micros: 0,
+                seeked: None,
                 tag: Tag::default(),
+                stats: Stats::default(),
+                path: None,
+                art: None,
+                length: None,
+                track_id: None,
+                track_list: vec![],
+                playlist: None,
+                playlists: vec![],
+                station: None,
+                stream_title: None,
+                last_error: None,
+                changed_playlists: vec![],
             })),
             // Add in mpris information channels
             mpris: rx,
             update_transmit: tx2,
+            // Add in player event channel
+            events: rx3,
             // Load in config file and library database
-            config: Config::open(),
+            config,
             database,
             library_tree,
-            // Updated flag for UI
+            // Updated flags for UI
             updated: false,
+            library_updated: false,
+            // Load in the listening history
+            history: history::read(),
+            listen: None,
+            station: None,
+            episode: None,
+            scrobbler,
+            // Nothing to play next yet
+            gapless: Arc::new(Mutex::new(None)),
+            resume: Arc::new(Mutex::new(None)),
+            range: Arc::new(Mutex::new(None)),
+            ended: Arc::new(AtomicBool::new(false)),
+            replay_gain,
+        };
+        // Carry on from where the last run left off
+        if let Some(playback) = Session::read().playback {
+            manager.restore(playback);
         }
+        manager
     }
 
     pub fn init(&mut self) {
-        // Initialise this manager
-        self.player.set_volume(1.0);
+        // Initialise this manager, at the volume it was left at
+        let volume = self.metadata.lock().unwrap().volume;
+        self.player.set_volume(volume);
         // Set up channels
         let (tx, rx) = mpsc::sync_channel(32);
         let (tx2, rx2) = mpsc::channel();
@@ -109,25 +234,201 @@ impl Manager {
         // Spawn mpris thread
         let md = self.metadata.clone();
         std::thread::spawn(move || crate::mpris::connect(ev, &md, &rx2));
+        // Listen for player signals
+        let (tx3, rx3) = mpsc::channel();
+        self.events = rx3;
+        self.connect_signals(&tx3);
+        // Get the restored track ready to carry on, playing it straight away if the config asks to
+        if self.metadata.lock().unwrap().playback_status == PlaybackStatus::Paused {
+            if self.config.auto_play {
+                self.play();
+            } else {
+                self.pause();
+            }
+        } else if self.playlist.is_ready() {
+            self.update();
+        }
     }
 
-    pub fn open(&mut self, track: Track) {
-        // If the track is already in the library, load it, otherwise, add it and then load it
-        let mut found = None;
+    fn restore(&mut self, playback: Playback) {
+        // Put back the play queue and where playback was, without starting the player yet
+        let shuffle = playback.queue.shuffle;
+        self.playlist.restore(playback.queue, &self.database.tracks);
+        let mut md = self.metadata.lock().unwrap();
+        md.loop_status = self.playlist.loop_status;
+        md.shuffle_status = shuffle;
+        md.volume = playback.volume;
+        if let Some(current) = self.playlist.current() {
+            md.tag = current.tag;
+            self.player.set_uri(&current.path);
+            self.resume_current();
+            // A stopped track starts over, while anything else waits paused where it was
+            if !playback.stopped {
+                md.playback_status = PlaybackStatus::Paused;
+                md.position_micros = playback.position * 1000;
+                *self.resume.lock().unwrap() =
+                    (playback.position > 0).then(|| ClockTime::from_mseconds(playback.position));
+            }
+        }
+    }
+
+    pub fn playback(&self) -> Playback {
+        // Note down the play queue and where playback is, to carry on from next time
+        let md = self.metadata.lock().unwrap();
+        let stopped = md.playback_status == PlaybackStatus::Stopped;
+        // Until the player has jumped to where it was left, its position is wrong
+        let position = self
+            .resume
+            .lock()
+            .unwrap()
+            .or_else(|| self.position())
+            .filter(|_| !stopped)
+            .map_or(0, ClockTime::mseconds);
+        Playback {
+            queue: self.playlist.snapshot(),
+            position,
+            volume: md.volume,
+            stopped,
+        }
+    }
+
+    fn connect_signals(&self, tx: &Sender<PlayerEvent>) {
+        // Run a main loop for the player to dispatch its signals on
+        std::thread::spawn(|| gstreamer::glib::MainLoop::new(None, false).run());
+        // Track finished with nothing lined up after it
+        let eos = tx.clone();
+        self.player.connect_end_of_stream(move |_| {
+            eos.send(PlayerEvent::EndOfStream).ok();
+        });
+        // Track failed to play
+        let error = tx.clone();
+        self.player.connect_error(move |_, e| {
+            error.send(PlayerEvent::Error(e.to_string())).ok();
+        });
+        // Line up the next track just before the current one finishes, for gapless playback
+        let pending = Arc::new(AtomicBool::new(false));
+        let gapless = self.gapless.clone();
+        let about_to_finish = pending.clone();
+        self.player
+            .pipeline()
+            .connect("about-to-finish", false, move |values| {
+                if let Some(uri) = gapless.lock().unwrap().take() {
+                    if let Ok(playbin) = values[0].get::<gstreamer::Element>() {
+                        if playbin.set_property("uri", uri.as_str()).is_ok() {
+                            about_to_finish.store(true, Ordering::SeqCst);
+                        }
+                    }
+                }
+                None
+            })
+            .ok();
+        // Keep track of the exact position for mpris to read, from the start of the track's part
+        let md = self.metadata.clone();
+        let range = self.range.clone();
+        let resume = self.resume.clone();
+        let ended = self.ended.clone();
+        let finished = tx.clone();
+        self.player
+            .connect_position_updated(move |player, position| {
+                if let Some(position) = position {
+                    let range = *range.lock().unwrap();
+                    let (start, end) = range.map_or((0, None), |r| (r.start, r.end));
+                    md.lock().unwrap().position_micros =
+                        position.useconds().saturating_sub(start * 1000);
+                    // Until the player has jumped to the track's part, its position is from before
+                    if range.is_none() || resume.lock().unwrap().is_some() {
+                        return;
+                    }
+                    // Keep playback within the track's part, and finish the track at its end
+                    let now = position.mseconds();
+                    if now + RANGE_SLACK < start {
+                        player.seek(ClockTime::from_mseconds(start));
+                    } else if matches!(end, Some(end) if now >= end) {
+                        if !ended.swap(true, Ordering::SeqCst) {
+                            finished.send(PlayerEvent::EndOfStream).ok();
+                        }
+                    } else {
+                        ended.store(false, Ordering::SeqCst);
+                    }
+                }
+            });
+        // Keep track of the length of the current track
+        let md = self.metadata.clone();
+        let range = self.range.clone();
+        let update = self.update_transmit.clone();
+        self.player.connect_duration_changed(move |_, duration| {
+            let range = *range.lock().unwrap();
+            md.lock().unwrap().length = duration.map(|d| length(range, d).useconds());
+            update.send(()).ok();
+        });
+        // Pick episodes and audiobooks up where they were left, once the player is able to seek
+        let md = self.metadata.clone();
+        let resume = self.resume.clone();
+        let range = self.range.clone();
+        self.player
+            .connect_duration_changed(move |player, duration| {
+                let position = duration.and_then(|_| resume.lock().unwrap().take());
+                if let Some(position) = position {
+                    // Positions are measured from the start of the track's part of its file
+                    let start = range.lock().unwrap().map_or(0, |r| r.start);
+                    player.seek(position + ClockTime::from_mseconds(start));
+                    let mut md = md.lock().unwrap();
+                    md.position_micros = position.useconds();
+                    md.seeked = Some(position.useconds());
+                }
+            });
+        // Media info is refreshed once the lined up track has actually started
+        let changed = tx.clone();
+        let md = self.metadata.clone();
+        let update = self.update_transmit.clone();
+        self.player.connect_media_info_updated(move |_, info| {
+            if pending.swap(false, Ordering::SeqCst) {
+                changed.send(PlayerEvent::TrackChanged).ok();
+            }
+            let title = stream_title(info.tags().as_deref());
+            let mut md = md.lock().unwrap();
+            if md.station.is_some() && title.is_some() && md.stream_title != title {
+                md.stream_title = title;
+                update.send(()).ok();
+            }
+        });
+    }
+
+    fn find_or_add(&mut self, track: Track) -> usize {
+        // Find the ID of a track in the library, adding it if it isn't there yet
         for (id, value) in &self.database.tracks {
-            if value == &track {
-                found = Some(*id);
-                break;
+            if value.path == track.path && value.range == track.range {
+                return *id;
             }
         }
-        if let Some(id) = found {
+        self.add_library(track)
+    }
+
+    pub fn add_track(&mut self, track: Track, after: Option<usize>, current: bool) {
+        // Add a track to the play queue after another one, or at the start without one
+        let id = self.find_or_add(track);
+        if current {
             self.load(id);
-        } else {
-            let idx = self.add_library(track);
-            self.load(idx);
+            self.play();
+        } else if let Some(track) = self.database.tracks.get(&id) {
+            self.playlist.insert(track.clone(), id, after);
+            self.prepare_queue();
         }
     }
 
+    pub fn remove_track(&mut self, id: usize) {
+        // Remove a track from the play queue
+        self.playlist.remove(id);
+        self.update();
+    }
+
+    pub fn go_to(&mut self, id: usize) -> Option<()> {
+        // Skip to a particular track in the play queue
+        let track = self.playlist.go_to(id)?;
+        self.switch(track);
+        Some(())
+    }
+
     pub fn load(&mut self, id: usize) {
         // Load a track into this player
         if let Some(track) = self.database.tracks.get(&id) {
@@ -137,6 +438,7 @@ impl Manager {
             self.playlist.play(track.clone(), id);
             self.player
                 .set_uri(self.playlist.current().unwrap().path.as_str());
+            self.resume_current();
             std::mem::drop(md);
             self.update();
         } else {
@@ -148,20 +450,21 @@ impl Manager {
         // Load a playlist in
         let mut md = self.metadata.lock().unwrap();
         if let Some(load) = self.database.playlists.get(playlist) {
-            let mut playlist = vec![];
+            let mut tracks = vec![];
             for id in load {
-                playlist.push(self.database.tracks[id].clone());
+                tracks.push(self.database.tracks[id].clone());
             }
-            self.playlist.set(0, playlist, load.clone());
+            self.playlist.set(0, tracks, load.clone());
+            self.playlist.name = Some(playlist.to_string());
             md.playback_status = PlaybackStatus::Stopped;
-            if self.playlist.is_empty() {
+            if let Some(current) = self.playlist.current() {
+                md.tag = current.tag;
+                self.player.set_uri(current.path.as_str());
+            } else {
                 md.tag = Tag::default();
                 self.player.set_uri("");
-            } else {
-                md.tag = self.playlist.current().unwrap().tag;
-                self.player
-                    .set_uri(self.playlist.current().unwrap().path.as_str());
             }
+            self.resume_current();
             std::mem::drop(md);
             self.update();
         } else {
@@ -173,6 +476,92 @@ impl Manager {
         // Create a new playlist
         self.database.playlists.insert(name.to_string(), vec![]);
         self.database.display.playlists.push(name.to_string());
+        self.update();
+    }
+
+    pub fn new_smart_playlist(&mut self, name: &str, rule: &str) -> Result<(), ParseError> {
+        // Create a playlist of the tracks that pass a filter expression, kept up to date
+        let tracks = Filter::parse(rule)?.apply(&self.database.tracks);
+        if !self.database.playlists.contains_key(name) {
+            self.database.display.playlists.push(name.to_string());
+        }
+        self.database.playlists.insert(name.to_string(), tracks);
+        self.database
+            .smart_playlists
+            .insert(name.to_string(), rule.to_string());
+        self.update();
+        Ok(())
+    }
+
+    pub fn refresh_smart_playlists(&mut self) {
+        // Re-evaluate the smart playlists, after the library has changed
+        if self.database.smart_playlists.is_empty() {
+            return;
+        }
+        for (name, rule) in &self.database.smart_playlists {
+            if let Ok(filter) = Filter::parse(rule) {
+                let tracks = filter.apply(&self.database.tracks);
+                self.database.playlists.insert(name.to_string(), tracks);
+            }
+        }
+        self.library_updated = true;
+    }
+
+    pub fn export_playlist(&self, name: &str, path: &str) -> Result<(), String> {
+        // Write a playlist out to a file, in the format its extension asks for
+        let ids = self
+            .database
+            .playlists
+            .get(name)
+            .ok_or_else(|| format!("Couldn't find playlist: {}", name))?;
+        let entries: Vec<playlist_file::Entry> = ids
+            .iter()
+            .filter_map(|id| self.database.tracks.get(id))
+            .map(|track| {
+                let tag = &track.tag;
+                playlist_file::Entry {
+                    location: playlist_file::relative(path, &Track::format_path(&track.path)),
+                    title: Some(tag.title.clone()).filter(|t| !t.is_empty()),
+                    artist: Some(tag.artist.clone()).filter(|a| !a.is_empty()),
+                    duration: tag.duration,
+                }
+            })
+            .collect();
+        playlist_file::write(path, name, &entries)
+    }
+
+    pub fn import_playlist(&mut self, path: &str) -> Result<(String, Vec<String>), String> {
+        // Read a playlist from a file, adding tracks that aren't in the library yet
+        // Returns the name of the new playlist, and the entries that couldn't be found
+        let entries = playlist_file::read(path)?;
+        let mut tracks = vec![];
+        let mut unresolved = vec![];
+        for entry in entries {
+            let file = playlist_file::resolve(path, &entry.location);
+            let found = file.map(|f| self.find_or_load(&f)).unwrap_or_default();
+            if found.is_empty() {
+                unresolved.push(entry.location);
+            } else {
+                tracks.extend(found);
+            }
+        }
+        // Name the playlist after its file, without replacing an existing playlist
+        let stem = std::path::Path::new(path).file_stem().map_or_else(
+            || "Imported".to_string(),
+            |s| s.to_string_lossy().to_string(),
+        );
+        let mut name = stem.clone();
+        let mut n = 2;
+        while self.database.playlists.contains_key(&name) {
+            name = format!("{} {}", stem, n);
+            n += 1;
+        }
+        self.database.playlists.insert(name.clone(), tracks);
+        self.database.display.playlists.push(name.clone());
+        self.refresh_smart_playlists();
+        self.library_updated = true;
+        self.update();
+        Ok((name, unresolved))
     }
 
     pub fn list_playlist(&mut self, name: &str) -> String {
@@ -192,7 +581,12 @@ impl Manager {
         // List all the playlists
         let mut result = String::new();
         for i in self.database.playlists.keys() {
-            result.push_str(&format!("{}\n", i));
+            if let Some(rule) = self.database.smart_playlists.get(i) {
+                // Show the rule that smart playlists are made from
+                result.push_str(&format!("{} [{}]\n", i, rule));
+            } else {
+                result.push_str(&format!("{}\n", i));
+            }
         }
         result
     }
@@ -201,6 +595,9 @@ impl Manager {
         // Rename a playlist to something else
         if let Some(val) = self.database.playlists.remove(old) {
             self.database.playlists.insert(new.to_string(), val);
+            if let Some(rule) = self.database.smart_playlists.remove(old) {
+                self.database.smart_playlists.insert(new.to_string(), rule);
+            }
             // Update playlist display
             let idx = self
                 .database
@@ -210,6 +607,19 @@ impl Manager {
                 .position(|x| x == old)
                 .unwrap();
             *self.database.display.playlists.get_mut(idx).unwrap() = new.to_string();
+            // Update the name of the playlist being played
+            if self.playlist.name.as_deref() == Some(old) {
+                self.playlist.name = Some(new.to_string());
+            }
+            // Keep the ID of the playlist, so it is known to be the same one
+            if let Some(id) = self.database.playlist_ids.remove(old) {
+                self.database.playlist_ids.insert(new.to_string(), id);
+            }
+            let id = self.database.playlist_id(new);
+            let mut md = self.metadata.lock().unwrap();
+            md.changed_playlists.push((id, new.to_string()));
+            std::mem::drop(md);
+            self.update();
         } else {
             println!("ERROR: Couldn't find playlist: {}", old);
         }
@@ -217,6 +627,8 @@ impl Manager {
 
     pub fn delete_playlist(&mut self, name: &str) {
         // Delete a playlist
+        self.database.smart_playlists.remove(name);
+        self.database.playlist_ids.remove(name);
         if self.database.playlists.remove(name).is_none() {
             println!("ERROR: Couldn't find playlist: {}", name);
         } else if let Some(idx) = self
@@ -227,12 +639,15 @@ impl Manager {
             .position(|x| x == name)
         {
             self.database.display.playlists.remove(idx);
+            self.update();
         }
     }
 
     pub fn add_to_playlist(&mut self, playlist: &str, track: usize) {
-        if let Some(load) = self.database.playlists.get_mut(playlist) {
-            if self.database.tracks.len() > track {
+        if self.database.smart_playlists.contains_key(playlist) {
+            println!("ERROR: Can't edit smart playlist: {}", playlist);
+        } else if let Some(load) = self.database.playlists.get_mut(playlist) {
+            if self.database.tracks.contains_key(&track) {
                 load.push(track);
             } else {
                 println!("ERROR: Track ID out of range: {}", track);
@@ -243,18 +658,380 @@ impl Manager {
     }
 
     pub fn remove_from_playlist(&mut self, playlist: &str, idx: usize) {
-        if let Some(load) = self.database.playlists.get_mut(playlist) {
-            load.remove(idx);
+        if self.database.smart_playlists.contains_key(playlist) {
+            println!("ERROR: Can't edit smart playlist: {}", playlist);
+        } else if let Some(load) = self.database.playlists.get_mut(playlist) {
+            if idx < load.len() {
+                load.remove(idx);
+            } else {
+                println!("ERROR: Position out of range: {}", idx);
+            }
         } else {
             println!("ERROR: Couldn't find playlist: {}", playlist);
         }
     }
 
+    pub fn add_station(&mut self, name: &str, url: &str) {
+        // Add an internet radio station, streamed over http(s)
+        if url.starts_with("http://") || url.starts_with("https://") {
+            self.database.stations.push(Station {
+                name: name.to_string(),
+                url: url.to_string(),
+            });
+            self.update();
+        } else {
+            println!("ERROR: Expected an http(s) URL: {}", url);
+        }
+    }
+
+    pub fn remove_station(&mut self, idx: usize) {
+        // Remove a radio station, stopping it if it is playing
+        if idx < self.database.stations.len() {
+            self.database.stations.remove(idx);
+            match self.station {
+                Some(playing) if playing == idx => {
+                    self.stop();
+                    self.station = None;
+                }
+                Some(playing) if playing > idx => self.station = Some(playing - 1),
+                _ => (),
+            }
+            self.update();
+        } else {
+            println!("ERROR: Couldn't find station: {}", idx);
+        }
+    }
+
+    pub fn play_station(&mut self, idx: usize) {
+        // Tune into a radio station, clearing the play queue
+        if let Some(station) = self.database.stations.get(idx) {
+            self.playlist.clear();
+            self.station = Some(idx);
+            self.episode = None;
+            *self.resume.lock().unwrap() = None;
+            *self.range.lock().unwrap() = None;
+            let mut md = self.metadata.lock().unwrap();
+            md.tag = Tag {
+                title: station.name.clone(),
+                ..Tag::default()
+            };
+            md.stream_title = None;
+            md.playback_status = PlaybackStatus::Stopped;
+            self.player.set_uri(&station.url);
+            std::mem::drop(md);
+            self.play();
+        } else {
+            println!("ERROR: Couldn't find station: {}", idx);
+        }
+    }
+
+    pub fn list_stations(&self) -> String {
+        // List the radio stations, marking the one playing
+        let mut result = String::new();
+        for (i, station) in self.database.stations.iter().enumerate() {
+            let playing = if self.station == Some(i) { "*" } else { " " };
+            result.push_str(&format!(
+                "{}{}: {} ({})\n",
+                playing, i, station.name, station.url
+            ));
+        }
+        result
+    }
+
+    pub fn add_feed(m: &Arc<Mutex<Self>>, url: &str) -> Result<String, String> {
+        // Follow a podcast feed, fetching it without holding onto the manager
+        let url = podcast::resolve(url).ok_or_else(|| format!("Couldn't find feed: {}", url))?;
+        let mgmt = m.lock().unwrap();
+        if mgmt.database.podcasts.iter().any(|f| f.url == url) {
+            return Err(format!("Already following: {}", url));
+        }
+        std::mem::drop(mgmt);
+        let feed = Feed::fetch(&url)?;
+        let title = feed.title.clone();
+        let mut mgmt = m.lock().unwrap();
+        mgmt.database.podcasts.push(feed);
+        mgmt.update();
+        Ok(title)
+    }
+
+    pub fn remove_feed(&mut self, idx: usize) {
+        // Stop following a podcast feed, stopping it if one of its episodes is playing
+        if idx < self.database.podcasts.len() {
+            self.database.podcasts.remove(idx);
+            match self.episode {
+                Some((feed, _)) if feed == idx => {
+                    self.stop();
+                    self.episode = None;
+                }
+                Some((feed, episode)) if feed > idx => self.episode = Some((feed - 1, episode)),
+                _ => (),
+            }
+            self.update();
+        } else {
+            println!("ERROR: Couldn't find podcast: {}", idx);
+        }
+    }
+
+    pub fn refresh_feeds(m: &Arc<Mutex<Self>>) -> (usize, Vec<String>) {
+        // Fetch every feed again, returning the number of new episodes and the feeds that failed
+        let urls: Vec<String> = m
+            .lock()
+            .unwrap()
+            .database
+            .podcasts
+            .iter()
+            .map(|f| f.url.clone())
+            .collect();
+        let mut new = 0;
+        let mut failed = vec![];
+        for url in urls {
+            let fresh = match Feed::fetch(&url) {
+                Ok(fresh) => fresh,
+                Err(e) => {
+                    failed.push(e);
+                    continue;
+                }
+            };
+            // Feeds may have been removed while this one was being fetched
+            let mut mgmt = m.lock().unwrap();
+            if let Some(idx) = mgmt.database.podcasts.iter().position(|f| f.url == url) {
+                // Episodes may move around, so find the playing one again afterwards
+                let playing = mgmt.playing_episode().map(|e| e.guid.clone());
+                new += mgmt.database.podcasts[idx].merge(fresh);
+                if let (Some((feed, _)), Some(guid)) = (mgmt.episode, playing) {
+                    if feed == idx {
+                        let episodes = &mgmt.database.podcasts[idx].episodes;
+                        let episode = episodes.iter().position(|e| e.guid == guid);
+                        mgmt.episode = episode.map(|e| (idx, e));
+                    }
+                }
+            }
+        }
+        m.lock().unwrap().update();
+        (new, failed)
+    }
+
+    pub fn download_episode(
+        m: &Arc<Mutex<Self>>,
+        feed: usize,
+        episode: usize,
+    ) -> Result<String, String> {
+        // Download an episode without holding onto the manager, returning where it was saved
+        let mgmt = m.lock().unwrap();
+        let podcast = mgmt
+            .database
+            .podcasts
+            .get(feed)
+            .ok_or_else(|| format!("Couldn't find podcast: {}", feed))?
+            .clone();
+        let folder = mgmt.config.podcast_dir.clone();
+        std::mem::drop(mgmt);
+        let found = podcast
+            .episodes
+            .get(episode)
+            .ok_or_else(|| format!("Couldn't find episode: {}", episode))?;
+        let path = podcast::download(&podcast, found, &folder)?;
+        // Feeds may have changed during the download, so find the episode again
+        let mut mgmt = m.lock().unwrap();
+        let download = mgmt
+            .database
+            .podcasts
+            .iter_mut()
+            .filter(|f| f.url == podcast.url)
+            .flat_map(|f| f.episodes.iter_mut())
+            .find(|e| e.guid == found.guid);
+        if let Some(download) = download {
+            download.download = Some(path.clone());
+        }
+        mgmt.update();
+        Ok(path)
+    }
+
+    pub fn play_episode(&mut self, feed: usize, episode: usize) {
+        // Play a podcast episode from where it was left, clearing the play queue
+        let podcast = self.database.podcasts.get(feed);
+        if let Some((podcast, found)) = podcast.and_then(|f| Some((f, f.episodes.get(episode)?))) {
+            if let Some(location) = found.location(&podcast.url) {
+                // Finished episodes start again from the beginning
+                let position = if found.played { 0 } else { found.position };
+                let tag = Tag {
+                    title: found.title.clone(),
+                    artist: podcast.title.clone(),
+                    album: podcast.title.clone(),
+                    duration: found.duration,
+                    ..Tag::default()
+                };
+                self.playlist.clear();
+                self.station = None;
+                self.episode = Some((feed, episode));
+                *self.resume.lock().unwrap() =
+                    (position > 0).then(|| ClockTime::from_seconds(position));
+                *self.range.lock().unwrap() = None;
+                let mut md = self.metadata.lock().unwrap();
+                md.tag = tag;
+                md.playback_status = PlaybackStatus::Stopped;
+                self.player.set_uri(&location);
+                std::mem::drop(md);
+                self.play();
+            } else {
+                println!("ERROR: Couldn't find episode audio: {}", found.url);
+            }
+        } else {
+            println!("ERROR: Couldn't find episode: {} {}", feed, episode);
+        }
+    }
+
+    pub fn set_played(&mut self, feed: usize, episode: usize, played: bool) {
+        // Mark an episode as played or unplayed, forgetting where it was left either way
+        let found = self
+            .database
+            .podcasts
+            .get_mut(feed)
+            .and_then(|f| f.episodes.get_mut(episode));
+        if let Some(found) = found {
+            found.played = played;
+            found.position = 0;
+            self.update();
+        } else {
+            println!("ERROR: Couldn't find episode: {} {}", feed, episode);
+        }
+    }
+
+    fn playing_episode(&self) -> Option<&podcast::Episode> {
+        let (feed, episode) = self.episode?;
+        self.database.podcasts.get(feed)?.episodes.get(episode)
+    }
+
+    fn playing_episode_mut(&mut self) -> Option<&mut podcast::Episode> {
+        let (feed, episode) = self.episode?;
+        self.database
+            .podcasts
+            .get_mut(feed)?
+            .episodes
+            .get_mut(episode)
+    }
+
+    pub fn keep_position(&mut self) {
+        // Follow along with the listen going on now, so the history knows how much was played
+        self.follow_listen();
+        // Note down how far into the playing episode or audiobook playback is, for next time
+        let playing = self.metadata.lock().unwrap().playback_status == PlaybackStatus::Playing;
+        // Until the player has jumped to where it was left, its position is wrong
+        if !playing || self.resume.lock().unwrap().is_some() {
+            return;
+        }
+        if let Some(position) = self.position() {
+            if let Some(episode) = self.playing_episode_mut() {
+                episode.position = position.seconds();
+            } else if let Some(track) = self.current_track_mut() {
+                if track.is_audiobook() {
+                    track.stats.position = position.seconds();
+                }
+            }
+        }
+    }
+
+    fn resume_current(&self) {
+        // Audiobooks pick up where they were left, while anything else starts from the top
+        let current = self.current_track();
+        let position = current
+            .filter(|t| t.is_audiobook())
+            .map_or(0, |t| t.stats.position);
+        // Tracks split out of a file need jumping to their part of it, even from the top
+        let range = current.and_then(|t| t.range);
+        *self.range.lock().unwrap() = range;
+        self.ended.store(false, Ordering::SeqCst);
+        *self.resume.lock().unwrap() =
+            (position > 0 || range.is_some()).then(|| ClockTime::from_seconds(position));
+    }
+
+    fn finish_episode(&mut self) {
+        // The playing episode has been listened to the end
+        if let Some(episode) = self.playing_episode_mut() {
+            episode.played = true;
+            episode.position = 0;
+        }
+    }
+
+    pub fn list_feeds(&self) -> String {
+        // List the podcasts that are followed, with how many episodes haven't been played
+        let mut result = String::new();
+        for (i, feed) in self.database.podcasts.iter().enumerate() {
+            result.push_str(&format!(
+                "{}: {} ({}/{} unplayed)\n",
+                i,
+                feed.title,
+                feed.unplayed(),
+                feed.episodes.len()
+            ));
+        }
+        result
+    }
+
+    pub fn list_episodes(&self, feed: usize) -> String {
+        // List the episodes of a podcast, marking the one playing and those not played yet
+        let mut result = String::new();
+        if let Some(podcast) = self.database.podcasts.get(feed) {
+            for (i, episode) in podcast.episodes.iter().enumerate() {
+                let playing = if self.episode == Some((feed, i)) {
+                    "*"
+                } else {
+                    " "
+                };
+                let status = if episode.played {
+                    "played".to_string()
+                } else if episode.position > 0 {
+                    format!("at {}", timefmt(episode.position))
+                } else {
+                    "new".to_string()
+                };
+                let downloaded = if episode.download.is_some() {
+                    ", downloaded"
+                } else {
+                    ""
+                };
+                let date = episode.published.map(datefmt).unwrap_or_default();
+                result.push_str(&format!(
+                    "{}{}: {} {} ({}{})\n",
+                    playing, i, date, episode.title, status, downloaded
+                ));
+            }
+        } else {
+            println!("ERROR: Couldn't find podcast: {}", feed);
+        }
+        result
+    }
+
     pub fn queue(&mut self, id: usize) {
-        // Queue a track
+        // Add a track to the end of the immediate queue
         if let Some(track) = self.database.tracks.get(&id) {
             self.playlist.queue(track.clone(), id);
+            self.prepare_queue();
+        } else {
+            println!("ERROR: Track ID out of range: {}", id);
+        }
+    }
+
+    pub fn queue_next(&mut self, id: usize) {
+        // Add a track to the front of the immediate queue
+        if let Some(track) = self.database.tracks.get(&id) {
+            self.playlist.queue_next(track.clone(), id);
+            self.prepare_queue();
+        } else {
+            println!("ERROR: Track ID out of range: {}", id);
+        }
+    }
+
+    fn prepare_queue(&mut self) {
+        // If nothing is loaded, take the first queued track so that play can start it
+        if !self.playlist.is_ready() {
+            if let Some(track) = self.playlist.next() {
+                self.player.set_uri(&track.path);
+                self.resume_current();
+                self.metadata.lock().unwrap().tag = track.tag;
+            }
         }
+        self.update();
     }
 
     pub fn clear_queue(&mut self) {
@@ -265,7 +1042,7 @@ impl Manager {
 
     pub fn play(&mut self) {
         // Play the current track
-        if !self.playlist.is_empty() {
+        if !self.playlist.is_empty() || self.station.is_some() || self.episode.is_some() {
             let mut md = self.metadata.lock().unwrap();
             if md.playback_status == PlaybackStatus::Stopped {
                 self.player.stop();
@@ -296,89 +1073,283 @@ impl Manager {
     }
 
     pub fn stop(&mut self) {
-        // Stop the currently playing track
+        // Stop the current track, counting what was listened to before the position resets
+        self.follow_listen();
         let mut md = self.metadata.lock().unwrap();
         md.playback_status = PlaybackStatus::Stopped;
+        md.position_micros = 0;
         self.player.stop();
         std::mem::drop(md);
         self.update();
     }
 
     pub fn next(&mut self) -> Option<()> {
-        // Move to the next track
+        // Move to the next track, counting the current one as skipped
+        if self.metadata.lock().unwrap().playback_status != PlaybackStatus::Stopped {
+            if let Some(track) = self.current_track_mut() {
+                track.stats.skips += 1;
+            }
+        }
+        self.skip_failed()
+    }
+
+    pub fn skip_failed(&mut self) -> Option<()> {
+        // Move past a track that couldn't be played, without counting it as skipped
         let next = self.playlist.next()?;
-        self.player.set_uri(&next.path);
-        self.metadata.lock().unwrap().tag = next.tag;
-        self.play();
-        self.update();
+        self.switch(next);
         Some(())
     }
 
     pub fn previous(&mut self) -> Option<()> {
         // Move to the previous track
         let previous = self.playlist.previous()?;
-        self.player.set_uri(&previous.path);
-        self.metadata.lock().unwrap().tag = previous.tag;
+        self.switch(previous);
+        Some(())
+    }
+
+    pub fn advance(&mut self) -> Option<()> {
+        // Move on after the current track has finished, taking loop status into account
+        self.finish_episode();
+        self.count_play();
+        if let Some(next) = self.playlist.advance() {
+            self.switch(next);
+            Some(())
+        } else {
+            // Tracks that end part way through their file leave the player going
+            self.player.stop();
+            None
+        }
+    }
+
+    pub fn advance_gapless(&mut self) {
+        // The player has already moved onto the lined up track, catch up with it
+        self.finish_episode();
+        self.count_play();
+        if let Some(next) = self.playlist.advance() {
+            self.resume_current();
+            self.switched(next);
+        }
+    }
+
+    pub fn report_error(&mut self, error: String) {
+        // Keep an error from a background thread for the interface to show
+        self.metadata.lock().unwrap().last_error = Some(error);
+        self.updated = true;
+    }
+
+    pub fn take_error(&self) -> Option<String> {
+        // Take the error waiting to be shown, if there is one
+        self.metadata.lock().unwrap().last_error.take()
+    }
+
+    fn count_play(&mut self) {
+        // Count the current track as played, now that it has finished
+        self.record_listen(true);
+        if let Some(track) = self.current_track_mut() {
+            track.stats.plays += 1;
+            track.stats.last_played = Some(now());
+            // Finished audiobooks start again from the beginning
+            track.stats.position = 0;
+            self.refresh_smart_playlists();
+        }
+    }
+
+    pub fn record_listen(&mut self, completed: bool) {
+        // Write the listen going on now into the history
+        if let Some(mut listen) = self.listen.take() {
+            // Take one last look at the position, to count what played since the last look
+            let position = self.metadata.lock().unwrap().position_micros;
+            listen.follow(Duration::from_micros(position));
+            let entry = listen.finish(completed);
+            history::append(&entry);
+            self.scrobble(&entry);
+            self.history.push(entry);
+        }
+    }
+
+    fn scrobble(&self, entry: &Entry) {
+        // Scrobble a listen, if enough of the track was listened to
+        if let Some(track) = self.database.tracks.get(&entry.id) {
+            let tag = &track.tag;
+            if tag.artist.is_empty() || tag.title.is_empty() {
+                return;
+            }
+            if scrobble::eligible(entry.listened, tag.duration) {
+                self.scrobbler.queue(&Scrobble {
+                    artist: tag.artist.clone(),
+                    title: tag.title.clone(),
+                    album: Some(tag.album.clone()).filter(|a| !a.is_empty()),
+                    duration: tag.duration,
+                    timestamp: entry.started,
+                });
+            }
+        }
+    }
+
+    fn follow_listen(&mut self) {
+        // Keep track of how long the current track is listened to, for the history
+        let md = self.metadata.lock().unwrap();
+        let (status, position) = (md.playback_status, md.position_micros);
+        std::mem::drop(md);
+        let id = self.playlist.current_id();
+        if let Some(listen) = &self.listen {
+            if Some(listen.id) != id || status == PlaybackStatus::Stopped {
+                self.record_listen(false);
+            }
+        }
+        if let (None, Some(id), PlaybackStatus::Playing) = (&self.listen, id, status) {
+            self.listen = Some(Listen::new(id, now()));
+        }
+        // Only the played position counts, so a paused track isn't listened to while it waits
+        if let Some(listen) = &mut self.listen {
+            listen.follow(Duration::from_micros(position));
+        }
+    }
+
+    pub fn list_history(&self, n: usize) -> String {
+        // List the most recent listens, newest first and grouped by day
+        let mut result = String::new();
+        let mut day = String::new();
+        for entry in self.history.iter().rev().take(n) {
+            let date = datefmt(entry.started);
+            if date != day {
+                result.push_str(&format!("{}:\n", date));
+                day = date;
+            }
+            result.push_str(&format!(
+                "   {}: {}\n",
+                entry.id,
+                format_history_entry(entry, &self.database.tracks)
+            ));
+        }
+        result
+    }
+
+    pub fn current_track(&self) -> Option<&Track> {
+        // Get the library entry of the current track, which is kept more up to date
+        let id = self.playlist.current_id()?;
+        self.database.tracks.get(&id)
+    }
+
+    fn current_track_mut(&mut self) -> Option<&mut Track> {
+        // Get the library entry of the current track, to update its stats
+        let id = self.playlist.current_id()?;
+        self.database.tracks.get_mut(&id)
+    }
+
+    pub fn set_rating(&mut self, id: usize, rating: u8) {
+        // Give a track a star rating out of 5, where 0 clears the rating
+        if rating > 5 {
+            println!("ERROR: Ratings go from 0 to 5: {}", rating);
+        } else if let Some(track) = self.database.tracks.get_mut(&id) {
+            track.stats.rating = rating;
+            self.refresh_smart_playlists();
+            self.update();
+        } else {
+            println!("ERROR: Track ID out of range: {}", id);
+        }
+    }
+
+    fn switch(&mut self, track: Track) {
+        // Start playing a track that the playlist has moved onto
+        if self.follows_on(&track) {
+            // The player is already at the start of the track, so carry on without a gap
+            *self.range.lock().unwrap() = track.range;
+            self.ended.store(false, Ordering::SeqCst);
+            let length = self.duration().map(ClockTime::useconds);
+            let mut md = self.metadata.lock().unwrap();
+            md.playback_status = PlaybackStatus::Playing;
+            md.length = length;
+        } else {
+            self.player.set_uri(&track.path);
+            self.resume_current();
+        }
+        self.switched(track);
+    }
+
+    fn switched(&mut self, track: Track) {
+        // Bring everything up to date with the track the player has moved onto
+        // The listen to the track moved away from ends before the position goes back to the start
+        self.follow_listen();
+        let mut md = self.metadata.lock().unwrap();
+        md.tag = track.tag;
+        md.position_micros = 0;
+        md.seeked = Some(0);
+        std::mem::drop(md);
         self.play();
         self.update();
-        Some(())
+    }
+
+    fn follows_on(&self, track: &Track) -> bool {
+        // Check if a track starts in the same file right where the one that just finished ends
+        let end = self.range.lock().unwrap().and_then(|r| r.end);
+        let same = self.metadata.lock().unwrap().path.as_deref() == Some(track.path.as_str());
+        same && self.ended.load(Ordering::SeqCst) && end == track.range.map(|r| r.start)
     }
 
     pub fn set_loop(&mut self, s: LoopStatus) {
         // Set the loop status
         let mut md = self.metadata.lock().unwrap();
         md.loop_status = s;
+        self.playlist.loop_status = s;
         std::mem::drop(md);
         self.update();
     }
 
     pub fn cycle_loop(&mut self) {
         // Cycle through the loop statuses
-        let mut md = self.metadata.lock().unwrap();
-        md.loop_status = match md.loop_status {
+        let status = match self.metadata.lock().unwrap().loop_status {
             LoopStatus::None => LoopStatus::Track,
             LoopStatus::Track => LoopStatus::Playlist,
             LoopStatus::Playlist => LoopStatus::None,
         };
-        std::mem::drop(md);
-        self.update();
+        self.set_loop(status);
     }
 
     pub fn set_shuffle(&mut self, s: bool) {
         // Set the shuffle status
         let mut md = self.metadata.lock().unwrap();
         md.shuffle_status = s;
+        self.playlist.set_shuffle(s);
         std::mem::drop(md);
         self.update();
     }
 
     pub fn cycle_shuffle(&mut self) {
         // Toggle the shuffle option
-        let mut md = self.metadata.lock().unwrap();
-        md.shuffle_status = !md.shuffle_status;
-        std::mem::drop(md);
-        self.update();
+        let status = !self.metadata.lock().unwrap().shuffle_status;
+        self.set_shuffle(status);
     }
 
     pub fn seek(&mut self, forwards: bool, s: Duration) {
         // Perform a seek operation
         if self.metadata.lock().unwrap().playback_status != PlaybackStatus::Stopped {
             // Player is not stopped and ready to be seeked
-            if let Some((mut position, duration, _)) = self.get_position() {
+            if let (Some(position), Some(duration), Ok(offset)) =
+                (self.position(), self.duration(), ClockTime::try_from(s))
+            {
                 // Update position
-                position = if forwards {
-                    position + s.as_secs()
+                let position = if forwards {
+                    position.saturating_add(offset)
                 } else {
-                    position.saturating_sub(s.as_secs())
+                    position.saturating_sub(offset)
                 };
-                if position > duration {
-                    position = duration;
-                }
-                self.player.seek(ClockTime::from_seconds(position));
+                self.seek_to(position.min(duration));
             }
         }
     }
 
+    fn seek_to(&mut self, position: ClockTime) {
+        // Move the player to an exact position in the current track, and announce the jump
+        let start = self.range.lock().unwrap().map_or(0, |r| r.start);
+        self.player.seek(position + ClockTime::from_mseconds(start));
+        let mut md = self.metadata.lock().unwrap();
+        md.position_micros = position.useconds();
+        md.seeked = Some(position.useconds());
+        std::mem::drop(md);
+        self.update();
+    }
+
     pub fn set_volume(&mut self, v: f64) {
         // Set the volume of the player
         if v >= 0.0 {
@@ -402,23 +1373,226 @@ impl Manager {
         self.update();
     }
 
-    pub fn set_position(&mut self, p: i64) {
+    fn apply_replay_gain(&self, replay_gain: ReplayGain, album: bool) {
+        // Pick the track or album gain for what is playing
+        if let Some(volume) = &self.replay_gain {
+            let album = match self.config.replay_gain.mode {
+                GainMode::Album => true,
+                GainMode::Auto => album,
+                _ => false,
+            };
+            // Either gain stands in for the other when it is missing, as the volume element does
+            let gain = if album {
+                replay_gain.album_gain.or(replay_gain.track_gain)
+            } else {
+                replay_gain.track_gain.or(replay_gain.album_gain)
+            };
+            // Streams that GStreamer finds no ReplayGain tags in get the gain from the library
+            let fallback = gain.unwrap_or(0.0).clamp(-MAX_GAIN, MAX_GAIN);
+            volume.set_property("album-mode", album).ok();
+            volume.set_property("fallback-gain", fallback).ok();
+        }
+    }
+
+    pub fn gain(&self) -> Option<f64> {
+        // Find the gain the volume element is applying, after the pre-amp and clipping prevention
+        let gain = self.replay_gain.as_ref()?.property("result-gain").ok()?;
+        gain.get().ok()
+    }
+
+    pub fn set_position(&mut self, p: Duration) {
         // Set the position of the player
-        if let Some((_, duration, _)) = self.get_position() {
-            let p = p.try_into().unwrap();
+        if let (Some(duration), Ok(p)) = (self.duration(), ClockTime::try_from(p)) {
             if p > duration {
                 return;
             }
-            self.player.seek(ClockTime::from_seconds(p));
+            self.seek_to(p);
+        }
+    }
+
+    pub fn chapter(&self) -> Option<(usize, &Chapter)> {
+        // Find the chapter of the current track that playback is in
+        let position = self.position()?.mseconds();
+        let chapters = &self.current_track()?.chapters;
+        let index = chapters.iter().rposition(|c| c.start <= position)?;
+        Some((index, &chapters[index]))
+    }
+
+    pub fn next_chapter(&mut self) {
+        // Jump to the start of the next chapter of the current track
+        if let Some(position) = self.chapter_position() {
+            let next = self
+                .current_track()
+                .and_then(|t| t.chapters.iter().find(|c| c.start > position))
+                .map(|c| c.start);
+            if let Some(start) = next {
+                self.seek_to(ClockTime::from_mseconds(start));
+            }
+        }
+    }
+
+    pub fn previous_chapter(&mut self) {
+        // Jump back to the start of the chapter, or to the one before if it has only just begun
+        if let Some(position) = self.chapter_position() {
+            let chapters = self.current_track().map_or(&[][..], |t| &t.chapters);
+            if chapters.is_empty() {
+                return;
+            }
+            let start = chapters
+                .iter()
+                .rev()
+                .find(|c| c.start + CHAPTER_RESTART < position)
+                .map_or(0, |c| c.start);
+            self.seek_to(ClockTime::from_mseconds(start));
+        }
+    }
+
+    fn chapter_position(&self) -> Option<u64> {
+        // Find how far into the current track playback is in milliseconds, if it can be seeked
+        if self.metadata.lock().unwrap().playback_status == PlaybackStatus::Stopped {
+            return None;
+        }
+        self.position().map(ClockTime::mseconds)
+    }
+
+    pub fn list_chapters(&self) -> String {
+        // List the chapters of the current track, marking the one playing
+        let mut result = String::new();
+        let playing = self.chapter().map(|(i, _)| i);
+        if let Some(track) = self.current_track() {
+            for (i, chapter) in track.chapters.iter().enumerate() {
+                let marker = if playing == Some(i) { "*" } else { " " };
+                result.push_str(&format!(
+                    "{}{}: {} {}\n",
+                    marker,
+                    i,
+                    timefmt(chapter.start / 1000),
+                    chapter.title
+                ));
+            }
+        }
+        result
+    }
+
+    pub fn add_bookmark(&mut self, name: &str) {
+        // Bookmark where playback is in the current track
+        let position = self.position().map_or(0, ClockTime::seconds);
+        if let Some(track) = self.current_track_mut() {
+            let index = track
+                .bookmarks
+                .iter()
+                .take_while(|b| b.position <= position)
+                .count();
+            let bookmark = Bookmark {
+                name: name.to_string(),
+                position,
+            };
+            track.bookmarks.insert(index, bookmark);
+            self.update();
+        } else {
+            println!("ERROR: There is no track loaded to bookmark");
+        }
+    }
+
+    pub fn bookmarks(&self) -> Vec<(usize, usize)> {
+        // List every bookmark as the ID of its track and its place among that track's bookmarks
+        let mut ids: Vec<usize> = self
+            .database
+            .tracks
+            .iter()
+            .filter(|(_, t)| !t.bookmarks.is_empty())
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids.into_iter()
+            .flat_map(|id| (0..self.database.tracks[&id].bookmarks.len()).map(move |i| (id, i)))
+            .collect()
+    }
+
+    pub fn bookmark(&self) -> Option<(usize, usize)> {
+        // Find the last bookmark that playback has passed in the current track
+        let id = self.playlist.current_id()?;
+        let position = self.position()?.seconds();
+        let bookmarks = &self.current_track()?.bookmarks;
+        let index = bookmarks.iter().rposition(|b| b.position <= position)?;
+        Some((id, index))
+    }
+
+    pub fn remove_bookmark(&mut self, n: usize) {
+        // Remove a bookmark by its number among every bookmark
+        let found = self.bookmarks().get(n).copied();
+        if let Some((id, index)) = found {
+            if let Some(track) = self.database.tracks.get_mut(&id) {
+                track.bookmarks.remove(index);
+            }
+            self.update();
+        } else {
+            println!("ERROR: Couldn't find bookmark: {}", n);
+        }
+    }
+
+    pub fn play_bookmark(&mut self, n: usize) {
+        // Jump to a bookmark, loading its track if it isn't the current one
+        let found = self.bookmarks().get(n).copied();
+        if let Some((id, index)) = found {
+            let position = self.database.tracks[&id].bookmarks[index].position;
+            let position = ClockTime::from_seconds(position);
+            let stopped = self.metadata.lock().unwrap().playback_status == PlaybackStatus::Stopped;
+            if self.playlist.current_id() == Some(id) && !stopped {
+                self.seek_to(position);
+            } else {
+                self.load(id);
+                *self.resume.lock().unwrap() = Some(position);
+                self.play();
+            }
+        } else {
+            println!("ERROR: Couldn't find bookmark: {}", n);
+        }
+    }
+
+    pub fn list_bookmarks(&self) -> String {
+        // List every bookmark along with its track, numbered for the bookmark commands
+        let mut result = String::new();
+        for (n, (id, index)) in self.bookmarks().into_iter().enumerate() {
+            let track = &self.database.tracks[&id];
+            let bookmark = &track.bookmarks[index];
+            result.push_str(&format!(
+                "{}: {} ({} - {} at {})\n",
+                n,
+                bookmark.name,
+                track.tag.title,
+                track.tag.artist,
+                timefmt(bookmark.position)
+            ));
+        }
+        result
+    }
+
+    fn position(&self) -> Option<ClockTime> {
+        // Find how far into the current track the player is, from the start of its part of the file
+        let start = self.range.lock().unwrap().map_or(0, |r| r.start);
+        Some(
+            self.player
+                .position()?
+                .saturating_sub(ClockTime::from_mseconds(start)),
+        )
+    }
+
+    fn duration(&self) -> Option<ClockTime> {
+        // Find how long the current track is, which may only be part of its file
+        let range = *self.range.lock().unwrap();
+        match range.and_then(|r| Some(r.end?.saturating_sub(r.start))) {
+            Some(part) => Some(ClockTime::from_mseconds(part)),
+            None => Some(length(range, self.player.duration()?)),
         }
     }
 
     #[allow(clippy::cast_precision_loss)]
     pub fn get_position(&self) -> Option<(u64, u64, f64)> {
         // Work out the current position of the player
-        let time_pos = ClockTime::seconds(self.player.position()?);
+        let time_pos = ClockTime::seconds(self.position()?);
         // Work out the duration of the current track
-        let duration = ClockTime::seconds(self.player.duration()?);
+        let duration = ClockTime::seconds(self.duration()?);
         // Tupleize above values, and calculate the percentage way through
         let data = (time_pos, duration, time_pos as f64 / (duration as f64));
         // Update the position for mpris to read
@@ -438,25 +1612,174 @@ impl Manager {
         result
     }
 
+    pub fn search(&self, query: &str) -> Vec<usize> {
+        // Find the tracks in the library that match a query, best matches first
+        search(&self.database.tracks, query)
+    }
+
+    pub fn filter(&self, expression: &str) -> Result<Vec<usize>, ParseError> {
+        // Find the tracks in the library that pass a filter expression
+        Ok(Filter::parse(expression)?.apply(&self.database.tracks))
+    }
+
+    pub fn list_tracks(&self, ids: &[usize]) -> String {
+        // List some tracks in the library, along with their IDs
+        let mut result = String::new();
+        for id in ids {
+            result.push_str(&format!("{}: {}\n", id, self.database.tracks[id].format()));
+        }
+        result
+    }
+
     pub fn add_library(&mut self, track: Track) -> usize {
         // Add a track to the library
-        let mut keys: Vec<usize> = self.database.tracks.keys().copied().collect();
-        keys.sort_unstable();
-        let mut i = 0;
-        let mut result = None;
-        for k in &keys {
-            if i != *k {
-                result = Some(i);
-                break;
-            }
-            i += 1;
-        }
-        let result = result.unwrap_or(i);
+        let id = self.insert_library(track);
+        self.refresh_smart_playlists();
+        id
+    }
+
+    pub fn insert_library(&mut self, mut track: Track) -> usize {
+        // Add a track to the library, leaving smart playlists to be refreshed after a batch
+        track.stats.added.get_or_insert_with(now);
+        // IDs aren't reused, as the listening history refers to tracks by them
+        let result = self.database.next_id;
+        self.database.next_id += 1;
         self.database.tracks.insert(result, track);
         self.database.display.simple.push(result);
         result
     }
 
+    pub fn in_library(&self, path: &str) -> bool {
+        // Check if a file has already been added to the library
+        self.find_path(path).is_some()
+    }
+
+    fn find_or_load(&mut self, path: &str) -> Vec<usize> {
+        // Find the tracks of a file in the library, reading it in if it isn't there yet
+        let found = self.find_paths(path);
+        if !found.is_empty() {
+            return found;
+        }
+        Track::load_all(path)
+            .into_iter()
+            .map(|t| self.insert_library(t))
+            .collect()
+    }
+
+    pub fn open_locations(&mut self, locations: &[String]) -> Vec<String> {
+        // Replace the play queue with files, folders and playlist files, and start playing
+        // Returns the locations that couldn't be opened
+        let mut ids = vec![];
+        let mut failed = vec![];
+        for location in locations {
+            match self.find_location(location) {
+                Some(found) if !found.is_empty() => ids.extend(found),
+                _ => failed.push(location.to_string()),
+            }
+        }
+        self.refresh_smart_playlists();
+        self.library_updated = true;
+        if !ids.is_empty() {
+            let tracks = ids
+                .iter()
+                .map(|id| self.database.tracks[id].clone())
+                .collect();
+            self.playlist.set(0, tracks, ids);
+            self.playlist.name = None;
+            let mut md = self.metadata.lock().unwrap();
+            md.playback_status = PlaybackStatus::Stopped;
+            if let Some(current) = self.playlist.current() {
+                md.tag = current.tag;
+                self.player.set_uri(current.path.as_str());
+            }
+            self.resume_current();
+            std::mem::drop(md);
+            self.play();
+        }
+        failed
+    }
+
+    fn find_location(&mut self, location: &str) -> Option<Vec<usize>> {
+        // Find the tracks in a file, folder or playlist file, adding them to the library if needed
+        let path = expand_path(&uri_to_path(location)?)?;
+        if is_file(&path) && playlist_file::Format::from_path(&path).is_some() {
+            let entries = playlist_file::read(&path).ok()?;
+            let files = entries
+                .iter()
+                .filter_map(|e| playlist_file::resolve(&path, &e.location));
+            return Some(files.flat_map(|f| self.find_or_load(&f)).collect());
+        }
+        let files = find_audio(&path);
+        Some(files.iter().flat_map(|f| self.find_or_load(f)).collect())
+    }
+
+    pub fn find_path(&self, path: &str) -> Option<usize> {
+        // Find the ID of the track in the library at this path
+        let path = format!("file://{}", Track::format_path(path));
+        self.database
+            .tracks
+            .iter()
+            .find(|(_, t)| t.path == path)
+            .map(|(id, _)| *id)
+    }
+
+    fn find_paths(&self, path: &str) -> Vec<usize> {
+        // Find every track in the library from the file at this path, in the order they play in
+        let path = format!("file://{}", Track::format_path(path));
+        let mut found: Vec<(u64, usize)> = self
+            .database
+            .tracks
+            .iter()
+            .filter(|(_, t)| t.path == path)
+            .map(|(id, t)| (t.range.map_or(0, |r| r.start), *id))
+            .collect();
+        found.sort_unstable();
+        found.into_iter().map(|(_, id)| id).collect()
+    }
+
+    pub fn import<F: FnMut(&Import)>(m: &Arc<Mutex<Self>>, path: &str, mut report: F) -> Import {
+        // Add every audio file in a folder to the library, skipping those already in it
+        let files = find_audio(path);
+        let mut progress = Import {
+            total: files.len(),
+            ..Import::default()
+        };
+        for file in files {
+            // Only hold onto the manager while adding, so that it is free while reading tags
+            if !m.lock().unwrap().in_library(&file) {
+                // Files with a cue sheet can hold several tracks
+                let tracks = Track::load_all(&file);
+                if tracks.is_empty() {
+                    progress.failed.push(file);
+                }
+                let mut mgmt = m.lock().unwrap();
+                for track in tracks {
+                    mgmt.insert_library(track);
+                    progress.added += 1;
+                }
+            }
+            progress.done += 1;
+            report(&progress);
+        }
+        // Trigger a rerender now the library has changed
+        let mut mgmt = m.lock().unwrap();
+        mgmt.refresh_smart_playlists();
+        mgmt.updated = true;
+        std::mem::drop(mgmt);
+        progress.finished = true;
+        report(&progress);
+        progress
+    }
+
+    pub fn relink(&mut self, id: usize, mut track: Track) {
+        // Point a track in the library at its file's new location, keeping its ID, stats and bookmarks
+        if let Some(old) = self.database.tracks.get(&id) {
+            track.stats = old.stats.clone();
+            track.bookmarks.clone_from(&old.bookmarks);
+        }
+        self.database.tracks.insert(id, track);
+    }
+
     pub fn remove_library(&mut self, id: usize) {
         // Remove a track from the library
         self.database.tracks.remove(&id);
@@ -475,12 +1798,16 @@ impl Manager {
                 values.remove(idx);
             }
         }
+        self.refresh_smart_playlists();
     }
 
     pub fn set_title(&mut self, id: usize, new: &str) {
         // Set the title of a track
         if let Some(track) = self.database.tracks.get_mut(&id) {
-            track.set_title(new);
+            if let Err(e) = track.set_title(new) {
+                self.report_error(e);
+            }
+            self.refresh_smart_playlists();
         } else {
             println!("ERROR: Track ID out of range: {}", id);
         }
@@ -489,7 +1816,10 @@ impl Manager {
     pub fn set_album(&mut self, id: usize, new: &str) {
         // Set the album of a track
         if let Some(track) = self.database.tracks.get_mut(&id) {
-            track.set_album(new);
+            if let Err(e) = track.set_album(new) {
+                self.report_error(e);
+            }
+            self.refresh_smart_playlists();
         } else {
             println!("ERROR: Track ID out of range: {}", id);
         }
@@ -498,7 +1828,10 @@ impl Manager {
     pub fn set_artist(&mut self, id: usize, new: &str) {
         // Set the artist of a track
         if let Some(track) = self.database.tracks.get_mut(&id) {
-            track.set_artist(new);
+            if let Err(e) = track.set_artist(new) {
+                self.report_error(e);
+            }
+            self.refresh_smart_playlists();
         } else {
             println!("ERROR: Track ID out of range: {}", id);
         }
@@ -507,7 +1840,10 @@ impl Manager {
     pub fn set_year(&mut self, id: usize, new: &str) {
         // Set the year of a track
         if let Some(track) = self.database.tracks.get_mut(&id) {
-            track.set_year(new);
+            if let Err(e) = track.set_year(new) {
+                self.report_error(e);
+            }
+            self.refresh_smart_playlists();
         } else {
             println!("ERROR: Track ID out of range: {}", id);
         }
@@ -517,6 +1853,7 @@ impl Manager {
         // Reread the tags of a track
         if let Some(track) = self.database.tracks.get_mut(&id) {
             track.update();
+            self.refresh_smart_playlists();
         } else {
             println!("ERROR: Track ID out of range: {}", id);
         }
@@ -525,15 +1862,368 @@ impl Manager {
     pub fn view_track(&mut self, id: usize) {
         // View track metadata
         if let Some(track) = self.database.tracks.get_mut(&id) {
-            println!("{}", track.format());
+            for column in COLUMNS {
+                println!("{}: {}", column, track.column(column));
+            }
         } else {
             println!("ERROR: Track ID out of range: {}", id);
         }
     }
 
     pub fn update(&mut self) {
+        // Follow along with the listen going on now
+        self.follow_listen();
+        // Line up the next track for gapless playback
+        // Tracks split out of a file, or audiobooks part way through, need jumping to their place
+        // so they can't be lined up
+        *self.gapless.lock().unwrap() = self
+            .playlist
+            .peek_advance()
+            .filter(|t| t.range.is_none() && !(t.is_audiobook() && t.stats.position > 0))
+            .map(|t| t.path);
+        // Loading a track takes over from the radio and podcasts
+        let current = self.playlist.current();
+        let replay_gain = current.as_ref().map(|t| t.tag.replay_gain);
+        if current.is_some() {
+            self.station = None;
+            self.episode = None;
+        }
+        let station = self
+            .station
+            .and_then(|i| self.database.stations.get(i))
+            .cloned();
+        // Find cover art when the track changes, before mpris is locked out of the metadata
+        let path = current
+            .as_ref()
+            .map(|t| t.path.clone())
+            .or_else(|| station.as_ref().map(|s| s.url.clone()))
+            .or_else(|| self.playing_episode().map(|e| e.url.clone()));
+        let art = if self.metadata.lock().unwrap().path == path {
+            None
+        } else {
+            Some(current.and_then(|t| t.extract_art()))
+        };
+        // Share the play queue for mpris to read
+        let mut md = self.metadata.lock().unwrap();
+        md.track_id = self.playlist.current_id();
+        md.stats = md
+            .track_id
+            .and_then(|id| self.database.tracks.get(&id))
+            .map(|t| t.stats.clone())
+            .unwrap_or_default();
+        // Stats are taken from the library, as the play queue keeps its own copies of tracks
+        md.track_list = self
+            .playlist
+            .track_list()
+            .into_iter()
+            .map(|(mut track, id)| {
+                if let Some(t) = self.database.tracks.get(&id) {
+                    track.stats = t.stats.clone();
+                }
+                (id, track)
+            })
+            .collect();
+        // Albums playing in order keep the differences in loudness between their tracks
+        let album = !md.shuffle_status && in_album(&md.track_list, md.track_id);
+        let name = station.map(|s| s.name);
+        if md.station != name {
+            md.station = name;
+            md.stream_title = None;
+        }
+        if let Some(art) = art {
+            md.art = art;
+            md.path = path;
+        }
+        // Share the playlists for mpris to read, by ID
+        let names = self.database.display.playlists.clone();
+        md.playlists = names
+            .into_iter()
+            .map(|name| (self.database.playlist_id(&name), name))
+            .collect();
+        md.playlist = self
+            .playlist
+            .name
+            .as_ref()
+            .and_then(|name| Some((*self.database.playlist_ids.get(name)?, name.clone())));
+        std::mem::drop(md);
+        self.apply_replay_gain(replay_gain.unwrap_or_default(), album);
         // Send the update signal for mpris to update it's values
         self.updated = true;
         self.update_transmit.send(()).unwrap();
     }
 }
+
+fn replay_gain_filter(player: &Player, config: &ReplayGainConfig) -> Option<gstreamer::Element> {
+    // Put ReplayGain volume and a limiter in front of the audio sink, when ReplayGain is on
+    if config.mode == GainMode::Off {
+        return None;
+    }
+    let description = "rgvolume name=rgvolume ! rglimiter name=rglimiter";
+    let filter = gstreamer::parse_bin_from_description(description, true);
+    if filter.is_err() {
+        println!("ERROR: ReplayGain needs the rgvolume and rglimiter GStreamer elements");
+    }
+    let filter = filter.ok()?;
+    let volume = filter.by_name("rgvolume")?;
+    let limiter = filter.by_name("rglimiter")?;
+    // Either turn tracks down to keep their peaks in, or let them through and limit them
+    let headroom = if config.prevent_clipping {
+        0.0
+    } else {
+        MAX_GAIN
+    };
+    volume
+        .set_property("pre-amp", config.pre_amp.clamp(-MAX_GAIN, MAX_GAIN))
+        .ok();
+    volume.set_property("headroom", headroom).ok();
+    limiter
+        .set_property("enabled", !config.prevent_clipping)
+        .ok();
+    player
+        .pipeline()
+        .set_property("audio-filter", &filter)
+        .ok()?;
+    Some(volume)
+}
+
+fn stream_title(tags: Option<&gstreamer::TagListRef>) -> Option<String> {
+    // Radio stations give the title of what they're playing in the stream's tags
+    let title = tags?.get::<gstreamer::tags::Title>()?;
+    Some(title.get().to_string())
+}
+
+fn in_album(track_list: &[(usize, Track)], id: Option<usize>) -> bool {
+    // Check if a track next to the current one in the play queue is from the same album
+    let position = id.and_then(|id| track_list.iter().position(|(i, _)| *i == id));
+    if let Some(position) = position {
+        let album = &track_list[position].1.tag.album;
+        let neighbours = [position.checked_sub(1), Some(position + 1)];
+        album != "[unknown]"
+            && neighbours
+                .iter()
+                .filter_map(|n| track_list.get((*n)?))
+                .any(|(_, t)| &t.tag.album == album)
+    } else {
+        false
+    }
+}
+
+fn length(range: Option<Range>, duration: ClockTime) -> ClockTime {
+    // Work out how long a track is from the length of its file, of which it may only be part
+    match range {
+        Some(Range { start, end }) => {
+            let end = end.map_or(duration, ClockTime::from_mseconds);
+            end.saturating_sub(ClockTime::from_mseconds(start))
+        }
+        None => duration,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gstreamer::{tags::Title, MessageView, TagList, TagMergeMode};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // How often the station sends its metadata, in bytes of audio
+    const METAINT: usize = 8192;
+
+    #[test]
+    fn stream_titles() {
+        gstreamer::init().unwrap();
+        assert_eq!(stream_title(None), None);
+        let mut tags = TagList::new();
+        assert_eq!(stream_title(Some(&tags)), None);
+        tags.get_mut()
+            .unwrap()
+            .add::<Title>(&"Daft Punk - One More Time", TagMergeMode::Replace);
+        assert_eq!(
+            stream_title(Some(&tags)).as_deref(),
+            Some("Daft Punk - One More Time")
+        );
+    }
+
+    fn serve_icy(title: &'static str) -> String {
+        // Stream silent MP3 frames, with the title in a metadata block every METAINT bytes
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let head = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\nicy-name: Test FM\r\nicy-metaint: {}\r\n\r\n",
+                METAINT
+            );
+            // MPEG-1 layer 3 frames at 128kbps and 44.1kHz are 417 bytes long
+            let mut frame = vec![0; 417];
+            frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+            let audio: Vec<u8> = frame.iter().copied().cycle().take(METAINT).collect();
+            let mut metadata = format!("StreamTitle='{}';", title).into_bytes();
+            metadata.resize(metadata.len().div_ceil(16) * 16, 0);
+            let mut block = vec![u8::try_from(metadata.len() / 16).unwrap()];
+            block.extend(metadata);
+            if stream.write_all(head.as_bytes()).is_err() {
+                return;
+            }
+            // Keep streaming until the player hangs up
+            while stream.write_all(&audio).is_ok() && stream.write_all(&block).is_ok() {}
+        });
+        url
+    }
+
+    #[test]
+    #[ignore = "needs the souphttpsrc and icydemux GStreamer plugins, run with --ignored"]
+    fn icy_stream_titles() {
+        // Read the tags from a local station the way the player does, through souphttpsrc
+        gstreamer::init().unwrap();
+        for element in ["souphttpsrc", "icydemux"] {
+            let found = gstreamer::ElementFactory::find(element);
+            assert!(found.is_some(), "Missing GStreamer element: {}", element);
+        }
+        let url = serve_icy("Daft Punk - One More Time");
+        let pipeline = gstreamer::parse_launch(&format!(
+            "souphttpsrc location={} iradio-mode=true ! icydemux ! fakesink",
+            url
+        ))
+        .unwrap();
+        pipeline.set_state(gstreamer::State::Playing).unwrap();
+        let bus = pipeline.bus().unwrap();
+        let mut title = None;
+        while title.is_none() {
+            let message = bus
+                .timed_pop_filtered(
+                    ClockTime::from_seconds(10),
+                    &[gstreamer::MessageType::Tag, gstreamer::MessageType::Error],
+                )
+                .expect("Timed out waiting for the stream title");
+            match message.view() {
+                MessageView::Tag(tag) => title = stream_title(Some(&tag.tags())),
+                MessageView::Error(e) => panic!("{}", e.error()),
+                _ => (),
+            }
+        }
+        pipeline.set_state(gstreamer::State::Null).unwrap();
+        assert_eq!(title.as_deref(), Some("Daft Punk - One More Time"));
+    }
+
+    fn manager() -> (Manager, mpsc::Receiver<()>) {
+        // A manager of its own, keeping its database and history in a temporary home folder
+        static HOME: std::sync::Once = std::sync::Once::new();
+        HOME.call_once(|| {
+            let home = std::env::temp_dir().join(format!("synchron-home-{}", std::process::id()));
+            std::fs::create_dir_all(home.join(".local/share")).unwrap();
+            std::env::set_var("HOME", home);
+        });
+        let mut m = Manager::new();
+        // Updates would go to mpris, which isn't running
+        let (tx, rx) = mpsc::channel();
+        m.update_transmit = tx;
+        (m, rx)
+    }
+
+    fn track(title: &str, duration: u64) -> Track {
+        Track {
+            path: format!("/music/{}.flac", title),
+            tag: Tag {
+                title: title.to_string(),
+                artist: "Daft Punk".to_string(),
+                album: "Discovery".to_string(),
+                duration: Some(duration),
+                ..Tag::default()
+            },
+            ..Track::default()
+        }
+    }
+
+    fn listen(m: &mut Manager, id: usize, seconds: u64) {
+        // Play a track from the start, with its position looked at every pulse like the main loop
+        m.load(id);
+        m.play();
+        for second in 0..seconds {
+            m.metadata.lock().unwrap().position_micros = second * 1_000_000;
+            m.keep_position();
+        }
+        m.metadata.lock().unwrap().position_micros = seconds * 1_000_000;
+    }
+
+    fn play_through(m: &mut Manager, id: usize) {
+        // Play a whole track, the last of it playing out before the end of the stream is seen
+        let duration = m.database.tracks[&id].tag.duration.unwrap();
+        listen(m, id, duration);
+        m.metadata.lock().unwrap().playback_status = PlaybackStatus::Stopped;
+        m.advance();
+    }
+
+    #[test]
+    fn listens() {
+        // Tracks played straight through are counted right up to their end
+        let (mut m, _updates) = manager();
+        let id = m.insert_library(track("One More Time", 320));
+        play_through(&mut m, id);
+        let entry = m.history.last().unwrap();
+        assert_eq!((entry.id, entry.listened, entry.completed), (id, 320, true));
+        assert_eq!(m.database.tracks[&id].stats.plays, 1);
+        // Stopping or skipping part way through counts only what was played
+        listen(&mut m, id, 45);
+        m.stop();
+        let entry = m.history.last().unwrap();
+        assert_eq!((entry.id, entry.listened, entry.completed), (id, 45, false));
+        let next = m.insert_library(track("Aerodynamic", 212));
+        m.playlist.set(
+            0,
+            vec![track("One More Time", 320), track("Aerodynamic", 212)],
+            vec![id, next],
+        );
+        listen(&mut m, id, 30);
+        m.next();
+        let entry = m.history.last().unwrap();
+        assert_eq!((entry.id, entry.listened, entry.completed), (id, 30, false));
+        assert_eq!(m.database.tracks[&id].stats.skips, 1);
+    }
+
+    // Backend that keeps what is submitted to it
+    struct Recorder(Arc<Mutex<Vec<Scrobble>>>);
+
+    impl scrobble::Backend for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn submit(&self, scrobbles: &[Scrobble]) -> Result<(), scrobble::SubmitError> {
+            self.0.lock().unwrap().extend_from_slice(scrobbles);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn scrobbles() {
+        // Tracks played through are scrobbled, while tracks skipped early on aren't
+        let (mut m, _updates) = manager();
+        let submitted = Arc::new(Mutex::new(vec![]));
+        let cache = std::env::temp_dir().join(format!("synchron-listens-{}", std::process::id()));
+        let backends: Vec<Box<dyn scrobble::Backend + Send>> =
+            vec![Box::new(Recorder(submitted.clone()))];
+        m.scrobbler = Scrobbler::spawn(backends, cache.to_str().map(ToString::to_string));
+        let skipped = m.insert_library(track("Digital Love", 301));
+        let played = m.insert_library(track("Aerodynamic", 212));
+        listen(&mut m, skipped, 20);
+        m.stop();
+        play_through(&mut m, played);
+        for _ in 0..500 {
+            if !submitted.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let submitted = submitted.lock().unwrap();
+        let titles: Vec<&str> = submitted.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(titles, ["Aerodynamic"]);
+        assert_eq!(submitted[0].timestamp, m.history.last().unwrap().started);
+        std::fs::remove_file(cache).ok();
+    }
+}
@@ -0,0 +1,692 @@
+// chapters.rs - reading chapter markers from id3 tags, mp4 files and cue sheets
+use crate::cue;
+use crate::track::Chapter;
+use id3::Version;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Most chapters read from a file, so that a damaged one can't run away with memory
+const MAX_CHAPTERS: usize = 4096;
+// Largest mp4 movie header read into memory when looking for chapters
+const MAX_HEADER: u64 = 64 * 1024 * 1024;
+// Largest chapter title sample read from an mp4 chapter track
+const MAX_SAMPLE: usize = 64 * 1024;
+// Deepest that id3 tables of contents are followed into each other
+const MAX_DEPTH: usize = 8;
+
+pub fn read(path: &str) -> Vec<Chapter> {
+    // Find the chapters of a file, preferring markers inside it to a cue sheet beside it
+    let extension = Path::new(path)
+        .extension()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or_default()
+        .to_lowercase();
+    let chapters = if ["m4a", "m4b", "mp4"].contains(&extension.as_str()) {
+        read_mp4(path)
+    } else {
+        read_id3(path)
+    };
+    let mut chapters = chapters
+        .filter(|c| !c.is_empty())
+        .or_else(|| read_cue(path))
+        .unwrap_or_default();
+    chapters.truncate(MAX_CHAPTERS);
+    // Chapters without a title are numbered instead
+    for (i, chapter) in chapters.iter_mut().enumerate() {
+        if chapter.title.trim().is_empty() {
+            chapter.title = format!("Chapter {}", i + 1);
+        }
+    }
+    chapters
+}
+
+fn read_cue(path: &str) -> Option<Vec<Chapter>> {
+    // Use the tracks a cue sheet marks out in the file as its chapters
+    let (_, file) = cue::find(path)?;
+    let chapters = file.tracks.into_iter().map(|t| Chapter {
+        title: t.title.unwrap_or_default(),
+        start: t.start,
+    });
+    Some(chapters.collect())
+}
+
+fn read_id3(path: &str) -> Option<Vec<Chapter>> {
+    // Read the CHAP frames, in the order the top level CTOC frame lists them if there is one
+    let tag = id3::Tag::read_from_path(path).ok()?;
+    let syncsafe = tag.version() == Version::Id3v24;
+    let mut chapters = HashMap::new();
+    let mut tables = HashMap::new();
+    let mut top = None;
+    for frame in tag.frames() {
+        // The id3 crate leaves chapter frames undecoded
+        if let Some(data) = frame.content().unknown() {
+            if frame.id() == "CHAP" {
+                if let Some((id, chapter)) = parse_chap(data, syncsafe) {
+                    chapters.insert(id, chapter);
+                }
+            } else if frame.id() == "CTOC" {
+                if let Some((id, top_level, entries)) = parse_ctoc(data) {
+                    if top_level && top.is_none() {
+                        top = Some(id.clone());
+                    }
+                    tables.insert(id, entries);
+                }
+            }
+        }
+    }
+    let mut result = vec![];
+    if let Some(top) = top {
+        contents(&top, &tables, &mut chapters, &mut result, 0);
+    }
+    // Without a table of contents, chapters are put in the order they start
+    if result.is_empty() {
+        result = chapters.into_values().collect();
+        result.sort_by_key(|c| c.start);
+    }
+    Some(result)
+}
+
+fn contents(
+    id: &str,
+    tables: &HashMap<String, Vec<String>>,
+    chapters: &mut HashMap<String, Chapter>,
+    result: &mut Vec<Chapter>,
+    depth: usize,
+) {
+    // Gather the chapters a table of contents lists, following any tables nested within it
+    if depth > MAX_DEPTH {
+        return;
+    }
+    for entry in tables.get(id).into_iter().flatten() {
+        if let Some(chapter) = chapters.remove(entry) {
+            result.push(chapter);
+        } else {
+            contents(entry, tables, chapters, result, depth + 1);
+        }
+    }
+}
+
+fn parse_chap(data: &[u8], syncsafe: bool) -> Option<(String, Chapter)> {
+    // An element ID, start and end times in milliseconds, byte offsets, then frames of its own
+    let (id, rest) = terminated(data)?;
+    let start = be32(rest, 0)?;
+    let title = frame_text(rest.get(16..)?, *b"TIT2", syncsafe).unwrap_or_default();
+    let chapter = Chapter {
+        title,
+        start: u64::from(start),
+    };
+    Some((id, chapter))
+}
+
+fn parse_ctoc(data: &[u8]) -> Option<(String, bool, Vec<String>)> {
+    // An element ID, flags saying whether it is the top level table, then the IDs it lists
+    let (id, rest) = terminated(data)?;
+    let top_level = rest.first()? & 2 != 0;
+    let count = *rest.get(1)?;
+    let mut rest = rest.get(2..)?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let (entry, next) = terminated(rest)?;
+        entries.push(entry);
+        rest = next;
+    }
+    Some((id, top_level, entries))
+}
+
+fn frame_text(mut frames: &[u8], name: [u8; 4], syncsafe: bool) -> Option<String> {
+    // Find a text frame among the frames embedded in a chapter
+    while frames.len() >= 10 {
+        let size = if syncsafe {
+            frames[4..8]
+                .iter()
+                .fold(0, |size, b| (size << 7) | u32::from(b & 0x7f))
+        } else {
+            be32(frames, 4)?
+        };
+        let end = usize::try_from(size).ok()?.checked_add(10)?;
+        let body = frames.get(10..end)?;
+        if frames[..4] == name {
+            return text(body);
+        }
+        frames = &frames[end..];
+    }
+    None
+}
+
+fn text(data: &[u8]) -> Option<String> {
+    // Text frames start with a byte giving their encoding, and may hold several strings
+    let (encoding, data) = data.split_first()?;
+    let text = match (encoding, data) {
+        (0, _) => data.iter().map(|b| char::from(*b)).collect(),
+        (1, [0xff, 0xfe, rest @ ..]) => utf16(rest, true),
+        (1, [0xfe, 0xff, rest @ ..]) | (2, rest) => utf16(rest, false),
+        _ => String::from_utf8_lossy(data).into_owned(),
+    };
+    let text = text.split('\0').next().unwrap_or_default().trim();
+    Some(text.to_string()).filter(|t| !t.is_empty())
+}
+
+fn utf16(data: &[u8], little_endian: bool) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| {
+            if little_endian {
+                u16::from_le_bytes([c[0], c[1]])
+            } else {
+                u16::from_be_bytes([c[0], c[1]])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn terminated(data: &[u8]) -> Option<(String, &[u8])> {
+    // Split off a string ended by a null byte
+    let end = data.iter().position(|b| *b == 0)?;
+    let value = String::from_utf8_lossy(&data[..end]).into_owned();
+    Some((value, &data[end + 1..]))
+}
+
+fn read_mp4(path: &str) -> Option<Vec<Chapter>> {
+    // Read the Nero chapter list, falling back on a QuickTime chapter track
+    let mut file = File::open(path).ok()?;
+    let length = file.metadata().ok()?.len();
+    let (start, end) = find_atom(&mut file, length, *b"moov")?;
+    if end - start > MAX_HEADER {
+        return None;
+    }
+    let mut moov = vec![0; usize::try_from(end - start).ok()?];
+    file.seek(SeekFrom::Start(start)).ok()?;
+    file.read_exact(&mut moov).ok()?;
+    let chapters = atom(&moov, &[b"udta", b"chpl"]).and_then(parse_chpl);
+    chapters
+        .filter(|c| !c.is_empty())
+        .or_else(|| chapter_track(&mut file, &moov))
+}
+
+fn find_atom(file: &mut File, length: u64, name: [u8; 4]) -> Option<(u64, u64)> {
+    // Find a top level atom in a file without reading the ones before it, such as the audio
+    let mut position = 0;
+    while position + 8 <= length {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(position)).ok()?;
+        file.read_exact(&mut header[..8]).ok()?;
+        let (size, skip) = match be32(&header, 0)? {
+            // The size is either 64 bits long, or left as 0 to run to the end of the file
+            1 => {
+                file.read_exact(&mut header[8..]).ok()?;
+                (be64(&header, 8)?, 16)
+            }
+            0 => (length - position, 8),
+            size => (u64::from(size), 8),
+        };
+        if size < skip {
+            return None;
+        }
+        if header[4..8] == name {
+            return Some((position + skip, position + size));
+        }
+        position += size;
+    }
+    None
+}
+
+fn children(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    // Split the contents of an atom into the atoms within it, as their names and contents
+    let mut result = vec![];
+    let mut rest = data;
+    while rest.len() >= 8 {
+        let (size, skip) = match be32(rest, 0).and_then(|s| usize::try_from(s).ok()) {
+            Some(1) => (be64(rest, 8).and_then(|s| usize::try_from(s).ok()), 16),
+            Some(0) => (Some(rest.len()), 8),
+            size => (size, 8),
+        };
+        match size {
+            Some(size) if size >= skip && size <= rest.len() => {
+                result.push((&rest[4..8], &rest[skip..size]));
+                rest = &rest[size..];
+            }
+            _ => break,
+        }
+    }
+    result
+}
+
+fn atom<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    // Follow a path of atom names down into some contents
+    path.iter().try_fold(data, |data, name| {
+        let found = children(data).into_iter().find(|(n, _)| n == name);
+        found.map(|(_, contents)| contents)
+    })
+}
+
+fn parse_chpl(data: &[u8]) -> Option<Vec<Chapter>> {
+    // A version, flags and reserved space, a count, then start times in 100ns units and titles
+    let mut at = if data.first() == Some(&1) { 9 } else { 5 };
+    let count = *data.get(at - 1)?;
+    let mut result = vec![];
+    for _ in 0..count {
+        let start = be64(data, at)?;
+        let length = usize::from(*data.get(at + 8)?);
+        let title = data.get(at + 9..at + 9 + length)?;
+        result.push(Chapter {
+            title: String::from_utf8_lossy(title).into_owned(),
+            start: start / 10_000,
+        });
+        at += 9 + length;
+    }
+    Some(result)
+}
+
+fn chapter_track(file: &mut File, moov: &[u8]) -> Option<Vec<Chapter>> {
+    // Chapter titles are the samples of a text track, which the audio track points to
+    let tracks: Vec<&[u8]> = children(moov)
+        .into_iter()
+        .filter(|(name, _)| *name == b"trak")
+        .map(|(_, contents)| contents)
+        .collect();
+    let id = tracks
+        .iter()
+        .find_map(|t| be32(atom(t, &[b"tref", b"chap"])?, 0))?;
+    let track = tracks.iter().find(|t| {
+        let header = atom(t, &[b"tkhd"]);
+        header.and_then(|h| be32(h, versioned(h, 12, 20))) == Some(id)
+    })?;
+    let media = atom(track, &[b"mdia"])?;
+    let header = atom(media, &[b"mdhd"])?;
+    let timescale = u64::from(be32(header, versioned(header, 12, 20))?).max(1);
+    let table = atom(media, &[b"minf", b"stbl"])?;
+    let times = sample_times(atom(table, &[b"stts"])?);
+    let mut result = vec![];
+    for (time, (offset, size)) in times.into_iter().zip(sample_places(table)?) {
+        // Titles are short, so a larger sample is damaged and left out
+        if size > MAX_SAMPLE {
+            continue;
+        }
+        let mut sample = vec![0; size];
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        file.read_exact(&mut sample).ok()?;
+        result.push(Chapter {
+            title: sample_text(&sample),
+            start: time * 1000 / timescale,
+        });
+    }
+    Some(result)
+}
+
+fn versioned(data: &[u8], first: usize, later: usize) -> usize {
+    // Later versions of a header widen its times to 64 bits, moving what comes after them
+    if data.first() == Some(&1) {
+        later
+    } else {
+        first
+    }
+}
+
+fn sample_times(stts: &[u8]) -> Vec<u64> {
+    // Work out when each sample starts, from runs of samples lasting the same time
+    let mut result = vec![];
+    let mut time = 0;
+    let runs = be32(stts, 4)
+        .and_then(|r| usize::try_from(r).ok())
+        .unwrap_or(0);
+    for i in 0..runs {
+        if let (Some(count), Some(duration)) = (be32(stts, 8 + i * 8), be32(stts, 12 + i * 8)) {
+            for _ in 0..count {
+                if result.len() == MAX_CHAPTERS {
+                    return result;
+                }
+                result.push(time);
+                time += u64::from(duration);
+            }
+        }
+    }
+    result
+}
+
+fn sample_places(table: &[u8]) -> Option<Vec<(u64, usize)>> {
+    // Find where each sample is in the file, from where its chunk starts and the samples before it
+    let stsz = atom(table, &[b"stsz"])?;
+    let fixed = be32(stsz, 4)?;
+    let count = usize::try_from(be32(stsz, 8)?).ok()?.min(MAX_CHAPTERS);
+    let sizes = (0..count)
+        .map(|i| {
+            if fixed > 0 {
+                Some(fixed)
+            } else {
+                be32(stsz, 12 + i * 4)
+            }
+        })
+        .collect::<Option<Vec<u32>>>()?;
+    // Chunk offsets are 32 bits long, unless the file needs them to be 64
+    let chunks = if let Some(stco) = atom(table, &[b"stco"]) {
+        let count = usize::try_from(be32(stco, 4)?).ok()?.min(MAX_CHAPTERS);
+        (0..count)
+            .map(|i| be32(stco, 8 + i * 4).map(u64::from))
+            .collect::<Option<Vec<u64>>>()?
+    } else {
+        let co64 = atom(table, &[b"co64"])?;
+        let count = usize::try_from(be32(co64, 4)?).ok()?.min(MAX_CHAPTERS);
+        (0..count)
+            .map(|i| be64(co64, 8 + i * 8))
+            .collect::<Option<Vec<u64>>>()?
+    };
+    // Runs of chunks holding the same number of samples, starting from chunk 1
+    let per_chunk = atom(table, &[b"stsc"])?;
+    let runs = usize::try_from(be32(per_chunk, 4)?).ok()?.min(MAX_CHAPTERS);
+    let runs = (0..runs)
+        .map(|i| Some((be32(per_chunk, 8 + i * 12)?, be32(per_chunk, 12 + i * 12)?)))
+        .collect::<Option<Vec<(u32, u32)>>>()?;
+    let mut result = vec![];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let number = u32::try_from(i + 1).ok()?;
+        let samples = runs.iter().rev().find(|r| r.0 <= number).map_or(0, |r| r.1);
+        let mut offset = *chunk;
+        for _ in 0..samples {
+            if let Some(size) = sizes.get(result.len()) {
+                result.push((offset, usize::try_from(*size).ok()?));
+                offset += u64::from(*size);
+            }
+        }
+    }
+    Some(result)
+}
+
+fn sample_text(sample: &[u8]) -> String {
+    // Text samples are a length, then UTF-8 text or UTF-16 text starting with a byte order mark
+    let length = sample
+        .get(..2)
+        .map_or(0, |l| usize::from(u16::from_be_bytes([l[0], l[1]])));
+    let text = sample.get(2..2 + length).unwrap_or_default();
+    match text {
+        [0xfe, 0xff, rest @ ..] => utf16(rest, false),
+        [0xff, 0xfe, rest @ ..] => utf16(rest, true),
+        _ => String::from_utf8_lossy(text).into_owned(),
+    }
+}
+
+fn be32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: [u8; 4], body: &[u8], syncsafe: bool) -> Vec<u8> {
+        // Build an id3 frame header, with its size written either way
+        let size = u32::try_from(body.len()).unwrap();
+        let size = if syncsafe {
+            (0..4)
+                .rev()
+                .fold(0, |s, i| (s << 8) | ((size >> (7 * i)) & 0x7f))
+        } else {
+            size
+        };
+        let mut result = name.to_vec();
+        result.extend(size.to_be_bytes());
+        result.extend([0, 0]);
+        result.extend(body);
+        result
+    }
+
+    fn chap(id: &str, start: u32, frames: &[u8]) -> Vec<u8> {
+        let mut result = format!("{}\0", id).into_bytes();
+        result.extend(start.to_be_bytes());
+        result.extend((start + 1000).to_be_bytes());
+        result.extend([0xff; 8]);
+        result.extend(frames);
+        result
+    }
+
+    fn ctoc(id: &str, flags: u8, entries: &[&str]) -> Vec<u8> {
+        let mut result = format!("{}\0", id).into_bytes();
+        result.extend([flags, u8::try_from(entries.len()).unwrap()]);
+        for entry in entries {
+            result.extend(format!("{}\0", entry).into_bytes());
+        }
+        result
+    }
+
+    #[test]
+    fn chap_frames() {
+        // Sizes over 127 are written differently in id3v2.3 and id3v2.4
+        let long = "x".repeat(200);
+        for syncsafe in [false, true] {
+            let mut frames = frame(*b"TPE1", b"\0Someone", syncsafe);
+            frames.extend(frame(*b"TIT2", format!("\0{}", long).as_bytes(), syncsafe));
+            let (id, chapter) = parse_chap(&chap("ch1", 61_500, &frames), syncsafe).unwrap();
+            assert_eq!((id.as_str(), chapter.start), ("ch1", 61_500));
+            assert_eq!(chapter.title, long, "{}", syncsafe);
+        }
+        // Chapters don't need a title, but do need their times
+        let (_, chapter) = parse_chap(&chap("ch2", 5, &[]), false).unwrap();
+        assert_eq!((chapter.title.as_str(), chapter.start), ("", 5));
+        assert!(parse_chap(b"ch3\0\0\0", false).is_none());
+        assert!(parse_chap(b"no terminator", false).is_none());
+    }
+
+    #[test]
+    fn ctoc_frames() {
+        assert_eq!(
+            parse_ctoc(&ctoc("toc", 3, &["ch1", "ch2"])),
+            Some((
+                "toc".to_string(),
+                true,
+                vec!["ch1".to_string(), "ch2".to_string()]
+            ))
+        );
+        assert_eq!(
+            parse_ctoc(&ctoc("sub", 1, &[])),
+            Some(("sub".to_string(), false, vec![]))
+        );
+        // The count can't run past the entries there are
+        let mut short = ctoc("toc", 2, &["ch1"]);
+        short[5] = 2;
+        assert_eq!(parse_ctoc(&short), None);
+    }
+
+    #[test]
+    fn text_frames() {
+        let tests: [(&[u8], Option<&str>); 8] = [
+            (b"\0Caf\xe9", Some("Caf\u{e9}")),
+            (b"\x01\xff\xfeC\0a\0f\0\xe9\0", Some("Caf\u{e9}")),
+            (b"\x01\xfe\xff\0C\0a\0f\0\xe9", Some("Caf\u{e9}")),
+            (b"\x02\0C\0a\0f\0\xe9", Some("Caf\u{e9}")),
+            (b"\x03Caf\xc3\xa9", Some("Caf\u{e9}")),
+            (b"\x03 First \0Second", Some("First")),
+            (b"\x03  ", None),
+            (b"", None),
+        ];
+        for (body, expected) in tests {
+            let frames = frame(*b"TIT2", body, false);
+            assert_eq!(
+                frame_text(&frames, *b"TIT2", false).as_deref(),
+                expected,
+                "{:?}",
+                body
+            );
+        }
+        // Frames that run past the end are ignored
+        let mut frames = frame(*b"TIT2", b"\0Title", false);
+        frames.truncate(frames.len() - 1);
+        assert_eq!(frame_text(&frames, *b"TIT2", false), None);
+        assert_eq!(
+            frame_text(&frame(*b"TALB", b"\0Album", false), *b"TIT2", false),
+            None
+        );
+    }
+
+    #[test]
+    fn tables_of_contents() {
+        // Tables are followed in order, into nested ones, and loops between them end
+        let mut chapters: HashMap<String, Chapter> = ["a", "b", "c", "d"]
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let chapter = Chapter {
+                    title: id.to_string(),
+                    start: 1000 * u64::try_from(i).unwrap(),
+                };
+                (id.to_string(), chapter)
+            })
+            .collect();
+        let mut tables = HashMap::new();
+        tables.insert(
+            "top".to_string(),
+            vec!["c".to_string(), "part".to_string(), "a".to_string()],
+        );
+        tables.insert(
+            "part".to_string(),
+            vec!["b".to_string(), "loop".to_string()],
+        );
+        tables.insert("loop".to_string(), vec!["part".to_string()]);
+        let mut result = vec![];
+        contents("top", &tables, &mut chapters, &mut result, 0);
+        let titles: Vec<&str> = result.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(titles, ["c", "b", "a"]);
+        assert!(chapters.contains_key("d"));
+    }
+
+    fn chpl(version: u8, chapters: &[(u64, &str)]) -> Vec<u8> {
+        let mut result = vec![version, 0, 0, 0];
+        if version == 1 {
+            result.extend([0; 4]);
+        }
+        result.push(u8::try_from(chapters.len()).unwrap());
+        for (start, title) in chapters {
+            result.extend(start.to_be_bytes());
+            result.push(u8::try_from(title.len()).unwrap());
+            result.extend(title.as_bytes());
+        }
+        result
+    }
+
+    #[test]
+    fn chpl_atoms() {
+        // Start times are in 100 nanosecond units
+        let chapters = [
+            (0, "Intro"),
+            (615_000_000, "Caf\u{e9}"),
+            (1_200_000_000, ""),
+        ];
+        for version in [0, 1] {
+            let result = parse_chpl(&chpl(version, &chapters)).unwrap();
+            let result: Vec<(u64, &str)> =
+                result.iter().map(|c| (c.start, c.title.as_str())).collect();
+            assert_eq!(result, [(0, "Intro"), (61_500, "Caf\u{e9}"), (120_000, "")]);
+        }
+        let mut short = chpl(0, &chapters);
+        short.truncate(short.len() - 20);
+        assert!(parse_chpl(&short).is_none());
+        assert!(parse_chpl(&[]).is_none());
+    }
+
+    fn wrap(name: [u8; 4], contents: &[u8]) -> Vec<u8> {
+        // Put contents into an atom
+        let mut result = u32::try_from(contents.len() + 8)
+            .unwrap()
+            .to_be_bytes()
+            .to_vec();
+        result.extend(name);
+        result.extend(contents);
+        result
+    }
+
+    fn table(entries: &[&[u32]]) -> Vec<u8> {
+        // A version and flags, then a count of entries and the entries themselves
+        let mut result = vec![0; 4];
+        result.extend(u32::try_from(entries.len()).unwrap().to_be_bytes());
+        for value in entries.iter().flat_map(|e| e.iter()) {
+            result.extend(value.to_be_bytes());
+        }
+        result
+    }
+
+    fn read_m4b(name: &str, file: &[u8]) -> Vec<(u64, String)> {
+        let path = std::env::temp_dir().join(format!(
+            "synchron-chapters-{}-{}.m4b",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, file).unwrap();
+        let chapters = read(path.to_str().unwrap());
+        std::fs::remove_file(&path).ok();
+        chapters.into_iter().map(|c| (c.start, c.title)).collect()
+    }
+
+    #[test]
+    fn mp4_files() {
+        // Read a Nero chapter list from the movie header of a file
+        let udta = wrap(
+            *b"udta",
+            &wrap(*b"chpl", &chpl(1, &[(0, "One"), (10_000_000, "Two")])),
+        );
+        let mut file = wrap(*b"ftyp", b"M4B \0\0\0\0");
+        file.extend(wrap(*b"mdat", &[0; 64]));
+        file.extend(wrap(*b"moov", &udta));
+        assert_eq!(
+            read_m4b("nero", &file),
+            [(0, "One".to_string()), (1000, "Two".to_string())]
+        );
+    }
+
+    #[test]
+    fn chapter_tracks() {
+        // Read chapter titles from the samples of the text track the audio track points to
+        let samples: [&[u8]; 3] = [b"\0\x05Intro", b"\0\x04Huge", b"\0\x03End"];
+        let mut offsets = vec![];
+        let mut mdat = vec![];
+        for sample in samples {
+            // The file type atom and the media data header come first
+            offsets.push(u32::try_from(24 + mdat.len()).unwrap());
+            mdat.extend(sample);
+        }
+        // A damaged size for the second title, which would need a gigabyte to read
+        let sizes: Vec<u32> = samples
+            .iter()
+            .map(|s| u32::try_from(s.len()).unwrap())
+            .collect();
+        let sizes = [sizes[0], 1 << 30, sizes[2]];
+        let mut stsz = vec![0; 8];
+        stsz.extend(3_u32.to_be_bytes());
+        for size in sizes {
+            stsz.extend(size.to_be_bytes());
+        }
+        let stbl = [
+            wrap(*b"stts", &table(&[&[3, 600]])),
+            wrap(*b"stsz", &stsz),
+            wrap(
+                *b"stco",
+                &table(&[&[offsets[0]], &[offsets[1]], &[offsets[2]]]),
+            ),
+            wrap(*b"stsc", &table(&[&[1, 1, 1]])),
+        ]
+        .concat();
+        let mut tkhd = vec![0; 12];
+        tkhd.extend(2_u32.to_be_bytes());
+        let mut mdhd = vec![0; 12];
+        mdhd.extend(600_u32.to_be_bytes());
+        let mdia = [
+            wrap(*b"mdhd", &mdhd),
+            wrap(*b"minf", &wrap(*b"stbl", &stbl)),
+        ]
+        .concat();
+        let text = [wrap(*b"tkhd", &tkhd), wrap(*b"mdia", &mdia)].concat();
+        let audio = wrap(*b"tref", &wrap(*b"chap", &2_u32.to_be_bytes()));
+        let moov = [wrap(*b"trak", &audio), wrap(*b"trak", &text)].concat();
+        let mut file = wrap(*b"ftyp", b"M4B \0\0\0\0");
+        file.extend(wrap(*b"mdat", &mdat));
+        file.extend(wrap(*b"moov", &moov));
+        assert_eq!(
+            read_m4b("track", &file),
+            [(0, "Intro".to_string()), (2000, "End".to_string())]
+        );
+    }
+}
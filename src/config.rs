@@ -1,4 +1,5 @@
 // config.rs - manage config file and databases
+use crate::podcast::Feed;
 use crate::track::Track;
 use crate::util::{attempt_open, expand_path};
 use serde::{Deserialize, Serialize};
@@ -12,6 +13,8 @@ const DEFAULT_DATABASE: &str = include_str!("../database.ron");
 // Higher = Slower reaction times, better performance
 pub const PULSE: u64 = 200;
 pub const DBUS_PULSE: u64 = 500;
+// Version of the database format, bumped when tracks need their tags reading again
+pub const DATABASE_VERSION: u32 = 4;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Pane {
@@ -19,6 +22,11 @@ pub enum Pane {
     SortedLibrary,
     Playlists,
     Files,
+    Search,
+    History,
+    Radio,
+    Podcasts,
+    Bookmarks,
     Empty,
 }
 
@@ -29,6 +37,111 @@ pub struct Config {
     pub open_on_pane: u8,
     pub indicators: HashMap<String, String>,
     pub show_hidden_files: bool,
+    #[serde(default = "default_columns")]
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub library_roots: Vec<String>,
+    #[serde(default)]
+    pub scrobbling: Scrobbling,
+    // Folder that podcast episodes are downloaded into
+    #[serde(default = "default_podcast_dir")]
+    pub podcast_dir: String,
+    // Whether to start playing straight away when carrying on from the last run
+    #[serde(default)]
+    pub auto_play: bool,
+    // How to even out the loudness of tracks using their ReplayGain tags
+    #[serde(default)]
+    pub replay_gain: ReplayGainConfig,
+}
+
+// Which ReplayGain values to use, with auto picking album gain while an album plays in order
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum GainMode {
+    Off,
+    Track,
+    Album,
+    Auto,
+}
+
+impl Default for GainMode {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReplayGainConfig {
+    #[serde(default)]
+    pub mode: GainMode,
+    // Gain added on top of every track, in dB
+    #[serde(default)]
+    pub pre_amp: f64,
+    // Whether to turn tracks down so their peaks don't clip, rather than softly limiting them
+    #[serde(default = "default_prevent_clipping")]
+    pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainConfig {
+    fn default() -> Self {
+        Self {
+            mode: GainMode::default(),
+            pre_amp: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+// Services to scrobble listens to, each one left out until it is set up
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Scrobbling {
+    #[serde(default)]
+    pub lastfm: Option<LastFmConfig>,
+    #[serde(default)]
+    pub listenbrainz: Option<ListenBrainzConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LastFmConfig {
+    pub api_key: String,
+    pub secret: String,
+    // Session key of the account to scrobble to, from Last.fm's authentication flow
+    pub session_key: String,
+    #[serde(default = "default_lastfm_endpoint")]
+    pub endpoint: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ListenBrainzConfig {
+    // User token, found on the ListenBrainz settings page
+    pub token: String,
+    #[serde(default = "default_listenbrainz_endpoint")]
+    pub endpoint: String,
+}
+
+fn default_lastfm_endpoint() -> String {
+    "https://ws.audioscrobbler.com/2.0/".to_string()
+}
+
+fn default_listenbrainz_endpoint() -> String {
+    "https://api.listenbrainz.org".to_string()
+}
+
+fn default_podcast_dir() -> String {
+    "~/Podcasts".to_string()
+}
+
+fn default_prevent_clipping() -> bool {
+    true
+}
+
+fn default_columns() -> Vec<String> {
+    // Columns shown in tables of tracks, when not set in the config
+    vec![
+        "title".to_string(),
+        "album".to_string(),
+        "artist".to_string(),
+        "year".to_string(),
+    ]
 }
 
 impl Config {
@@ -53,16 +166,69 @@ pub struct Display {
     pub playlists: Vec<String>,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Station {
+    pub name: String,
+    pub url: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Database {
     pub tracks: HashMap<usize, Track>,
     pub playlists: HashMap<String, Vec<usize>>,
+    // Filter expressions of smart playlists, whose tracks are kept in playlists
+    #[serde(default)]
+    pub smart_playlists: HashMap<String, String>,
+    // Internet radio stations, in the order they are shown
+    #[serde(default)]
+    pub stations: Vec<Station>,
+    // Podcast feeds that are followed, in the order they are shown
+    #[serde(default)]
+    pub podcasts: Vec<Feed>,
     pub display: Display,
+    #[serde(default)]
+    pub version: u32,
+    // ID to give the next track added, so IDs of removed tracks are never reused
+    #[serde(default)]
+    pub next_id: usize,
+    // IDs of playlists, which stay the same when a playlist is renamed
+    #[serde(default)]
+    pub playlist_ids: HashMap<String, usize>,
+    #[serde(default)]
+    pub next_playlist_id: usize,
 }
 
 impl Database {
     pub fn open() -> Self {
-        // Attempt to open the database
+        // Attempt to open the database, bringing it up to date if it is from an older version
+        let mut database = Self::read();
+        if database.version < DATABASE_VERSION {
+            for track in database.tracks.values_mut() {
+                track.update();
+            }
+            database.version = DATABASE_VERSION;
+        }
+        // Databases from before the counter was kept start after the highest ID
+        let first_free = database.tracks.keys().max().map_or(0, |id| id + 1);
+        database.next_id = database.next_id.max(first_free);
+        let first_free = database.playlist_ids.values().max().map_or(0, |id| id + 1);
+        database.next_playlist_id = database.next_playlist_id.max(first_free);
+        database
+    }
+
+    pub fn playlist_id(&mut self, name: &str) -> usize {
+        // Find the ID of a playlist, giving it the next one if it doesn't have one yet
+        if let Some(id) = self.playlist_ids.get(name) {
+            return *id;
+        }
+        let id = self.next_playlist_id;
+        self.next_playlist_id += 1;
+        self.playlist_ids.insert(name.to_string(), id);
+        id
+    }
+
+    fn read() -> Self {
+        // Read the database from disk
         let path_base =
             expand_path("~/.local/share").unwrap_or_else(|| "~/.local/share".to_string());
         std::fs::create_dir_all(format!("{}/synchron/", path_base)).ok();
@@ -105,3 +271,32 @@ impl Database {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        // Configs that leave an option out get what the default config file sets it to
+        let config: Config = ron::from_str(DEFAULT_CONFIG).unwrap();
+        let default = ReplayGainConfig::default();
+        assert_eq!(config.replay_gain.mode, default.mode);
+        assert!((config.replay_gain.pre_amp - default.pre_amp).abs() < f64::EPSILON);
+        assert_eq!(
+            config.replay_gain.prevent_clipping,
+            default.prevent_clipping
+        );
+    }
+
+    #[test]
+    fn playlist_ids() {
+        // Playlists keep their ID, and IDs of deleted playlists aren't handed out again
+        let mut database: Database = ron::from_str(DEFAULT_DATABASE).unwrap();
+        assert_eq!(database.playlist_id("Rock"), 0);
+        assert_eq!(database.playlist_id("Jazz"), 1);
+        assert_eq!(database.playlist_id("Rock"), 0);
+        database.playlist_ids.remove("Jazz");
+        assert_eq!(database.playlist_id("Blues"), 2);
+    }
+}
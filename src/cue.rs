@@ -0,0 +1,289 @@
+// cue.rs - reading cue sheets, which mark out the tracks within a file
+use crate::playlist_file;
+use std::path::Path;
+
+// Cue sheet times count frames, of which there are 75 in a second
+const FRAMES: u64 = 75;
+
+#[derive(Debug, Clone, Default)]
+pub struct Sheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    // Year and genre, from the REM comments that rippers write
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub files: Vec<File>,
+}
+
+// A file named by a cue sheet, along with the tracks marked out within it
+#[derive(Debug, Clone, Default)]
+pub struct File {
+    // Where the file is, as written in the sheet
+    pub path: String,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    // Where the track starts within its file, in milliseconds
+    pub start: u64,
+}
+
+pub fn read(path: &str) -> Result<Sheet, String> {
+    // Read a cue sheet, which isn't always UTF-8
+    let bytes = std::fs::read(path).map_err(|_| format!("Failed to read cue sheet: {}", path))?;
+    let contents = String::from_utf8_lossy(&bytes);
+    Ok(parse(contents.trim_start_matches('\u{feff}')))
+}
+
+pub fn resolve(sheet: &str, file: &File) -> Option<String> {
+    // Find a file named by a cue sheet, taking relative paths from the sheet's folder
+    playlist_file::resolve(sheet, &file.path)
+}
+
+pub fn find(path: &str) -> Option<(Sheet, File)> {
+    // Find the cue sheet that goes with an audio file, and the part of it about that file
+    let audio = Path::new(path);
+    let folder = audio.parent()?;
+    let stem = audio.file_stem()?.to_str()?;
+    let mut sheets: Vec<String> = std::fs::read_dir(folder)
+        .ok()?
+        .filter_map(|e| e.ok()?.path().to_str().map(ToString::to_string))
+        .filter(|p| extension(p) == "cue")
+        .collect();
+    sheets.sort();
+    for sheet in sheets {
+        if let Ok(read) = read(&sheet) {
+            let named = Path::new(&sheet).file_stem().and_then(|s| s.to_str()) == Some(stem);
+            // Sheets named after the file may still name the file it was ripped to, so trust them
+            if named && read.files.len() == 1 {
+                let file = read.files[0].clone();
+                return Some((read, file));
+            }
+            let found = read
+                .files
+                .iter()
+                .find(|f| resolve(&sheet, f).as_deref() == Some(path))
+                .cloned();
+            if let Some(file) = found {
+                return Some((read, file));
+            }
+        }
+    }
+    None
+}
+
+fn parse(contents: &str) -> Sheet {
+    // Titles and performers belong to the track they follow, or the whole sheet before any track
+    let mut sheet = Sheet::default();
+    let mut in_track = false;
+    for line in contents.lines().map(str::trim) {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        let track = sheet
+            .files
+            .last_mut()
+            .and_then(|f| f.tracks.last_mut())
+            .filter(|_| in_track);
+        match (command.to_uppercase().as_str(), track) {
+            ("FILE", _) => {
+                sheet.files.push(File {
+                    path: file_name(rest),
+                    tracks: vec![],
+                });
+                in_track = false;
+            }
+            ("TRACK", _) => {
+                if let Some(file) = sheet.files.last_mut() {
+                    // Tracks are numbered, followed by the type of track, such as AUDIO
+                    let number = rest.split_whitespace().next().and_then(|n| n.parse().ok());
+                    file.tracks.push(Track {
+                        number: number.unwrap_or(0),
+                        ..Track::default()
+                    });
+                    in_track = true;
+                }
+            }
+            ("TITLE", Some(track)) => track.title = Some(unquote(rest)),
+            ("PERFORMER", Some(track)) => track.performer = Some(unquote(rest)),
+            ("TITLE", None) => sheet.title = Some(unquote(rest)),
+            ("PERFORMER", None) => sheet.performer = Some(unquote(rest)),
+            ("REM", _) => match rest.split_once(' ') {
+                Some(("DATE", date)) => sheet.date = Some(unquote(date.trim())),
+                Some(("GENRE", genre)) => sheet.genre = Some(unquote(genre.trim())),
+                _ => (),
+            },
+            // Index 1 is where a track starts, with index 0 marking the gap before it
+            ("INDEX", Some(track)) => {
+                if let Some(("01" | "1", time)) = rest.split_once(' ') {
+                    track.start = parse_time(time.trim()).unwrap_or(0);
+                }
+            }
+            _ => (),
+        }
+    }
+    sheet
+}
+
+fn file_name(rest: &str) -> String {
+    // File names are followed by the type of file, such as WAVE or MP3
+    if rest.starts_with('"') {
+        unquote(rest)
+    } else {
+        rest.rsplit_once(' ')
+            .map_or(rest, |(name, _)| name)
+            .to_string()
+    }
+}
+
+fn unquote(value: &str) -> String {
+    // Take the text up to the last quote, as cue sheets don't escape quotes inside the text,
+    // or the whole value if it isn't quoted
+    match value.strip_prefix('"') {
+        Some(rest) => rest
+            .rsplit_once('"')
+            .map_or(rest, |(text, _)| text)
+            .to_string(),
+        None => value.to_string(),
+    }
+}
+
+fn parse_time(time: &str) -> Option<u64> {
+    // Read a time written as minutes:seconds:frames into milliseconds
+    let mut parts = time.split(':').map(|p| p.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    Some((minutes * 60 + seconds) * 1000 + frames * 1000 / FRAMES)
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE "Electronic"
+REM DATE 2001
+REM COMMENT "ExactAudioCopy v1.0"
+PERFORMER "Daft Punk"
+TITLE "Discovery"
+FILE "Daft Punk - Discovery.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "One More Time"
+    PERFORMER "Daft Punk"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Aerodynamic"
+    INDEX 00 05:18:50
+    INDEX 01 05:20:37
+  track 3 audio
+    title Digital Love
+    index 1 08:52:74
+FILE bonus track.wav WAVE
+  TRACK 04 AUDIO
+    PERFORMER "Someone "Else""
+    INDEX 01 00:00:00
+"#;
+
+    #[test]
+    fn sheets() {
+        let sheet = parse(SHEET);
+        assert_eq!(sheet.title.as_deref(), Some("Discovery"));
+        assert_eq!(sheet.performer.as_deref(), Some("Daft Punk"));
+        assert_eq!(sheet.date.as_deref(), Some("2001"));
+        assert_eq!(sheet.genre.as_deref(), Some("Electronic"));
+        let files: Vec<&str> = sheet.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(files, ["Daft Punk - Discovery.flac", "bonus track.wav"]);
+        let tracks: Vec<(u32, Option<&str>, Option<&str>, u64)> = sheet
+            .files
+            .iter()
+            .flat_map(|f| &f.tracks)
+            .map(|t| {
+                (
+                    t.number,
+                    t.title.as_deref(),
+                    t.performer.as_deref(),
+                    t.start,
+                )
+            })
+            .collect();
+        assert_eq!(
+            tracks,
+            [
+                (1, Some("One More Time"), Some("Daft Punk"), 0),
+                // The gap before a track, at index 0, is left with the track before
+                (2, Some("Aerodynamic"), None, 320_493),
+                (3, Some("Digital Love"), None, 532_986),
+                (4, None, Some("Someone \"Else\""), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn oddities() {
+        // Tracks before any file are ignored, and broken times start at the beginning
+        let sheet = parse(
+            "TRACK 01 AUDIO\nTITLE \"Album\"\nFILE a.mp3 MP3\nTRACK xx AUDIO\nINDEX 01 1:2\n\n",
+        );
+        assert_eq!(sheet.title.as_deref(), Some("Album"));
+        assert_eq!(sheet.files.len(), 1);
+        assert_eq!(sheet.files[0].path, "a.mp3");
+        let track = &sheet.files[0].tracks[0];
+        assert_eq!((track.number, track.start), (0, 0));
+        let sheet = parse("");
+        assert!(sheet.files.is_empty() && sheet.title.is_none());
+    }
+
+    #[test]
+    fn times() {
+        let tests = [
+            ("00:00:00", Some(0)),
+            ("00:01:00", Some(1000)),
+            ("01:00:00", Some(60_000)),
+            ("00:00:74", Some(986)),
+            ("05:20:37", Some(320_493)),
+            ("99:59:74", Some(5_999_986)),
+            ("1:2", None),
+            ("00:00:xx", None),
+            ("", None),
+        ];
+        for (time, expected) in tests {
+            assert_eq!(parse_time(time), expected, "{}", time);
+        }
+    }
+
+    #[test]
+    fn finding() {
+        // Sheets are matched to the audio they name, or to the file they are named after
+        let base = std::env::temp_dir().join(format!("synchron-cue-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        let base = std::fs::canonicalize(&base).unwrap();
+        let path = |name: &str| base.join(name).to_str().unwrap().to_string();
+        for name in ["album.flac", "renamed.flac", "other.flac"] {
+            std::fs::write(path(name), "").unwrap();
+        }
+        std::fs::write(
+            path("album.cue"),
+            "FILE \"album.flac\" WAVE\nTRACK 01 AUDIO\n",
+        )
+        .unwrap();
+        std::fs::write(
+            path("renamed.cue"),
+            "FILE \"rip.wav\" WAVE\nTRACK 01 AUDIO\n",
+        )
+        .unwrap();
+        let found = |name: &str| find(&path(name)).map(|(_, file)| file.path);
+        assert_eq!(found("album.flac").as_deref(), Some("album.flac"));
+        assert_eq!(found("renamed.flac").as_deref(), Some("rip.wav"));
+        assert_eq!(found("other.flac"), None);
+        std::fs::remove_dir_all(base).ok();
+    }
+}
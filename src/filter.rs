@@ -0,0 +1,432 @@
+// filter.rs - a small expression language for filtering tracks by their tags
+use crate::track::{Track, COLUMNS};
+use crate::util::now;
+use std::collections::HashMap;
+use std::fmt;
+
+// Fields searched by terms that don't name a field
+const DEFAULT_FIELDS: [&str; 4] = ["title", "album", "artist", "path"];
+// Fields that can be filtered on besides the table columns
+const EXTRA_FIELDS: [&str; 1] = ["added"];
+// Options that shape the result rather than filtering it
+const OPTIONS: [&str; 2] = ["sort", "limit"];
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    // Position of the character where parsing failed, starting from 0
+    pub position: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(position: usize, message: &str) -> Self {
+        Self {
+            position,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position + 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Contains,
+    Equal,
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+}
+
+#[derive(Debug, Clone)]
+struct Term {
+    negated: bool,
+    field: Option<String>,
+    op: Op,
+    value: String,
+    // Position of the value, for reporting problems with it
+    position: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    // Every term has to match for a track to pass the filter
+    terms: Vec<Term>,
+    // Field to order the tracks by, and whether to order them backwards
+    sort: Option<(String, bool)>,
+    // Most tracks to keep, after ordering them
+    limit: Option<usize>,
+}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        // Parse an expression such as: artist:"Daft Punk" year:>=2000 -album:live
+        let chars: Vec<char> = input.chars().collect();
+        let mut result = Self::default();
+        let mut i = 0;
+        loop {
+            // Skip the spaces between terms
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+            if i == chars.len() {
+                break;
+            }
+            let (term, next) = Term::parse(&chars, i)?;
+            match term.field.as_deref() {
+                Some("sort") => result.sort = Some(term.sort()?),
+                Some("limit") => result.limit = Some(term.limit()?),
+                _ => result.terms.push(term),
+            }
+            i = next;
+        }
+        Ok(result)
+    }
+
+    pub fn matches(&self, track: &Track) -> bool {
+        self.terms.iter().all(|t| t.matches(track))
+    }
+
+    pub fn apply(&self, tracks: &HashMap<usize, Track>) -> Vec<usize> {
+        // Find the IDs of the tracks that pass the filter, in ID order unless sorted otherwise
+        let mut result: Vec<usize> = tracks
+            .iter()
+            .filter(|(_, track)| self.matches(track))
+            .map(|(id, _)| *id)
+            .collect();
+        result.sort_unstable();
+        if let Some((field, backwards)) = &self.sort {
+            let mut keyed: Vec<(Option<i64>, String, usize)> = result
+                .iter()
+                .map(|id| {
+                    let track = &tracks[id];
+                    (number(track, field), text(track, field).to_lowercase(), *id)
+                })
+                .collect();
+            keyed.sort_by(|a, b| {
+                let order = (&a.0, &a.1).cmp(&(&b.0, &b.1));
+                if *backwards {
+                    order.reverse()
+                } else {
+                    order
+                }
+            });
+            result = keyed.into_iter().map(|(_, _, id)| id).collect();
+        }
+        if let Some(limit) = self.limit {
+            result.truncate(limit);
+        }
+        result
+    }
+}
+
+impl Term {
+    fn parse(chars: &[char], mut i: usize) -> Result<(Self, usize), ParseError> {
+        // A leading dash excludes the tracks that match the term
+        let negated = chars[i] == '-';
+        if negated {
+            i += 1;
+        }
+        // Look for a field name followed by a colon
+        let start = i;
+        let mut end = i;
+        while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+            end += 1;
+        }
+        let mut field = None;
+        if end > start && chars.get(end) == Some(&':') {
+            let name = chars[start..end].iter().collect::<String>().to_lowercase();
+            if !is_field(&name) && !OPTIONS.contains(&name.as_str()) {
+                return Err(ParseError::new(start, &format!("Unknown field '{}'", name)));
+            }
+            field = Some(name);
+            i = end + 1;
+        }
+        // Comparisons can only be made against a field
+        let mut op = Op::Contains;
+        if field.is_some() {
+            let symbols = [
+                (">=", Op::AtLeast),
+                ("<=", Op::AtMost),
+                (">", Op::Above),
+                ("<", Op::Below),
+                ("=", Op::Equal),
+            ];
+            for (symbol, kind) in symbols {
+                let length = symbol.len();
+                if chars.len() >= i + length
+                    && chars[i..i + length].iter().copied().eq(symbol.chars())
+                {
+                    op = kind;
+                    i += length;
+                    break;
+                }
+            }
+        }
+        // Read the value, which can be quoted to include spaces
+        let value_start = i;
+        let value: String = if chars.get(i) == Some(&'"') {
+            let length = chars[i + 1..]
+                .iter()
+                .position(|c| *c == '"')
+                .ok_or_else(|| ParseError::new(i, "Unterminated quote"))?;
+            let value = chars[i + 1..i + 1 + length].iter().collect();
+            i += length + 2;
+            value
+        } else {
+            let length = chars[i..]
+                .iter()
+                .position(|c| c.is_whitespace())
+                .unwrap_or(chars.len() - i);
+            let value = chars[i..i + length].iter().collect();
+            i += length;
+            value
+        };
+        if value.is_empty() {
+            return Err(ParseError::new(value_start, "Expected a value"));
+        }
+        if let Some(c) = chars.get(i) {
+            if !c.is_whitespace() {
+                return Err(ParseError::new(i, "Expected a space after the quote"));
+            }
+        }
+        // Ordering needs a number to compare against
+        let ordered = matches!(op, Op::Above | Op::AtLeast | Op::Below | Op::AtMost);
+        if ordered && parse_number(&value).is_none() {
+            return Err(ParseError::new(
+                value_start,
+                &format!("Expected a number, found '{}'", value),
+            ));
+        }
+        let term = Self {
+            negated,
+            field,
+            op,
+            value,
+            position: value_start,
+        };
+        Ok((term, i))
+    }
+
+    fn sort(&self) -> Result<(String, bool), ParseError> {
+        // Read a sort option such as sort:year, where sort:-year orders backwards
+        let (field, backwards) = if let Some(field) = self.value.strip_prefix('-') {
+            (field, true)
+        } else {
+            (self.value.as_str(), false)
+        };
+        if self.negated || self.op != Op::Contains || !is_field(field) {
+            return Err(ParseError::new(
+                self.position,
+                &format!("Can't sort by '{}'", self.value),
+            ));
+        }
+        Ok((field.to_lowercase(), backwards))
+    }
+
+    fn limit(&self) -> Result<usize, ParseError> {
+        // Read a limit option such as limit:50
+        match self.value.parse() {
+            Ok(limit) if !self.negated && self.op == Op::Contains => Ok(limit),
+            _ => Err(ParseError::new(
+                self.position,
+                &format!("Expected a number of tracks, found '{}'", self.value),
+            )),
+        }
+    }
+
+    fn matches(&self, track: &Track) -> bool {
+        // Check if a track matches this term, ignoring negation
+        let found = if let Some(field) = &self.field {
+            let number = number(track, field);
+            let value = parse_number(&self.value);
+            match self.op {
+                Op::Contains => contains(&text(track, field), &self.value),
+                Op::Equal => {
+                    if let (Some(number), Some(value)) = (number, value) {
+                        number == value
+                    } else {
+                        text(track, field).to_lowercase() == self.value.to_lowercase()
+                    }
+                }
+                // Tracks without a number in the field never pass a comparison
+                ordered => {
+                    if let (Some(number), Some(value)) = (number, value) {
+                        match ordered {
+                            Op::Above => number > value,
+                            Op::AtLeast => number >= value,
+                            Op::Below => number < value,
+                            _ => number <= value,
+                        }
+                    } else {
+                        false
+                    }
+                }
+            }
+        } else {
+            DEFAULT_FIELDS
+                .iter()
+                .any(|f| contains(&text(track, f), &self.value))
+        };
+        found != self.negated
+    }
+}
+
+fn is_field(name: &str) -> bool {
+    COLUMNS.contains(&name) || EXTRA_FIELDS.contains(&name)
+}
+
+fn text(track: &Track, field: &str) -> String {
+    // Get the text of a field, matching against the full path rather than the file name
+    match field {
+        "path" => track.path.clone(),
+        "added" => number(track, field)
+            .map(|n| n.to_string())
+            .unwrap_or_default(),
+        _ => track.column(field),
+    }
+}
+
+fn number(track: &Track, field: &str) -> Option<i64> {
+    // Get the numeric value of a field, if it has one
+    let tag = &track.tag;
+    match field {
+        "track" => tag.track_number.map(i64::from),
+        "disc" => tag.disc_number.map(i64::from),
+        "duration" => tag.duration.and_then(|d| i64::try_from(d).ok()),
+        "plays" => Some(i64::from(track.stats.plays)),
+        "skips" => Some(i64::from(track.stats.skips)),
+        "rating" => Some(i64::from(track.stats.rating)),
+        // Times are compared in days ago, so added:<7 finds this week's tracks
+        "added" => track.stats.added.and_then(days_ago),
+        "last_played" => track.stats.last_played.and_then(days_ago),
+        // Dates such as 2001-05-01 are compared by their year
+        _ => {
+            let digits: String = text(track, field)
+                .trim()
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect();
+            digits.parse().ok()
+        }
+    }
+}
+
+fn days_ago(time: u64) -> Option<i64> {
+    i64::try_from(now().saturating_sub(time) / 86400).ok()
+}
+
+fn parse_number(value: &str) -> Option<i64> {
+    // Read a number to compare against, allowing durations written as minutes:seconds
+    if let Some((minutes, seconds)) = value.split_once(':') {
+        let minutes: i64 = minutes.parse().ok()?;
+        let seconds: i64 = seconds.parse().ok()?;
+        Some(minutes * 60 + seconds)
+    } else {
+        value.parse().ok()
+    }
+}
+
+fn contains(text: &str, value: &str) -> bool {
+    text.to_lowercase().contains(&value.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::Tag;
+
+    fn track(title: &str, artist: &str, year: &str, duration: u64) -> Track {
+        Track {
+            path: format!("/music/{}.flac", title),
+            tag: Tag {
+                title: title.to_string(),
+                artist: artist.to_string(),
+                year: year.to_string(),
+                duration: Some(duration),
+                ..Tag::default()
+            },
+            ..Track::default()
+        }
+    }
+
+    fn library() -> HashMap<usize, Track> {
+        let mut tracks = HashMap::new();
+        tracks.insert(0, track("One More Time", "Daft Punk", "2000", 320));
+        tracks.insert(1, track("Around the World", "Daft Punk", "1997", 429));
+        tracks.insert(2, track("Windowlicker", "Aphex Twin", "1999", 366));
+        tracks.insert(3, track("Xtal", "Aphex Twin", "1992-01-01", 291));
+        tracks
+    }
+
+    #[test]
+    fn matching() {
+        let tests = [
+            ("", vec![0, 1, 2, 3]),
+            ("daft", vec![0, 1]),
+            ("artist:\"daft punk\"", vec![0, 1]),
+            ("\"one more\"", vec![0]),
+            ("-artist:daft", vec![2, 3]),
+            ("-\"aphex twin\" -world", vec![0]),
+            ("title:=xtal", vec![3]),
+            ("title:=xta", vec![]),
+            ("year:2000", vec![0]),
+            ("year:=1992", vec![3]),
+            ("year:>1997", vec![0, 2]),
+            ("year:>=1997", vec![0, 1, 2]),
+            ("year:<1997", vec![3]),
+            ("year:<=1997", vec![1, 3]),
+            ("duration:>5:00 duration:<6:30", vec![0, 2]),
+            ("-year:<1997", vec![0, 1, 2]),
+            ("path:/music/xtal", vec![3]),
+            ("twin sort:-year", vec![2, 3]),
+            ("sort:year", vec![3, 1, 2, 0]),
+            ("sort:title limit:2", vec![1, 0]),
+        ];
+        let tracks = library();
+        for (input, expected) in tests {
+            let filter = Filter::parse(input).unwrap();
+            assert_eq!(filter.apply(&tracks), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let tests = [
+            ("colour:red", 0, "Unknown field 'colour'"),
+            ("daft -mood:happy", 6, "Unknown field 'mood'"),
+            ("artist:\"daft punk", 7, "Unterminated quote"),
+            ("artist:", 7, "Expected a value"),
+            ("year:>", 6, "Expected a value"),
+            ("\"daft\"punk", 6, "Expected a space after the quote"),
+            ("year:>=later", 7, "Expected a number, found 'later'"),
+            ("duration:<3:x", 10, "Expected a number, found '3:x'"),
+            ("sort:colour", 5, "Can't sort by 'colour'"),
+            ("-sort:year", 6, "Can't sort by 'year'"),
+            ("limit:some", 6, "Expected a number of tracks, found 'some'"),
+        ];
+        for (input, position, message) in tests {
+            let error = Filter::parse(input).unwrap_err();
+            assert_eq!(
+                (error.position, error.message.as_str()),
+                (position, message),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn error_display() {
+        let error = Filter::parse("year:>x").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected a number, found 'x' at position 7"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+// history.rs - an append-only log of everything that has been listened to
+use crate::util::data_path;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+// Name of the file the history is kept in
+const HISTORY: &str = "history.ron";
+// Leeway for the position moving on further than the time between looking at it
+const SLACK: Duration = Duration::from_secs(1);
+
+// A single listen of a track
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    // Library ID of the track, which is never given to another track
+    pub id: usize,
+    // When the track started playing, in seconds since the unix epoch
+    pub started: u64,
+    // How long the track was listened to for, in seconds, not counting pauses or seeks
+    pub listened: u64,
+    // Whether the track played through to the end
+    pub completed: bool,
+}
+
+// A listen that is still going on
+#[derive(Debug)]
+pub struct Listen {
+    pub id: usize,
+    pub started: u64,
+    // Time the position has moved on by while playing
+    listened: Duration,
+    // Position last seen, and when it was seen
+    last: Option<(Duration, Instant)>,
+}
+
+impl Listen {
+    pub fn new(id: usize, started: u64) -> Self {
+        Self {
+            id,
+            started,
+            listened: Duration::ZERO,
+            last: None,
+        }
+    }
+
+    pub fn follow(&mut self, position: Duration) {
+        // Count how far the position has moved on, so pauses and seeks ahead aren't listened time
+        if let Some((last, seen)) = self.last {
+            if let Some(step) = position.checked_sub(last) {
+                if step <= seen.elapsed() + SLACK {
+                    self.listened += step;
+                }
+            }
+        }
+        self.last = Some((position, Instant::now()));
+    }
+
+    pub fn finish(self, completed: bool) -> Entry {
+        // Turn into an entry for the history
+        Entry {
+            id: self.id,
+            started: self.started,
+            listened: self.listened.as_secs(),
+            completed,
+        }
+    }
+}
+
+pub fn read() -> Vec<Entry> {
+    // Read the history from disk, oldest first, skipping any damaged lines
+    if let Some(path) = data_path(HISTORY) {
+        if let Ok(history) = std::fs::read_to_string(path) {
+            return history
+                .lines()
+                .filter_map(|line| ron::from_str(line).ok())
+                .collect();
+        }
+    }
+    vec![]
+}
+
+pub fn append(entry: &Entry) {
+    // Add an entry onto the end of the history, one per line
+    if let (Some(path), Ok(line)) = (data_path(HISTORY), ron::ser::to_string(entry)) {
+        let file = OpenOptions::new().create(true).append(true).open(path);
+        if file.and_then(|mut f| writeln!(f, "{}", line)).is_err() {
+            println!("ERROR: Failed to write history to disk");
+        }
+    }
+}
@@ -1,7 +1,7 @@
 /*
     Synchron - A terminal music player
     - Allows control through dbus, integrating into your bar and playerctl
-    - Reads ID3 tags from music
+    - Reads ID3, Vorbis, MP4 and APE tags from music
     - Can be controlled through prompt
     - Can play most mainstream formats
 */
@@ -15,22 +15,34 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[macro_use]
 mod util;
 mod audio;
+mod chapters;
 mod config;
+mod cue;
+mod filter;
+mod history;
 mod mpris;
 mod playlist;
+mod playlist_file;
+mod podcast;
+mod scrobble;
+mod session;
+mod tags;
 mod track;
 mod ui;
+mod watch;
 
-use audio::{LoopStatus, Manager, PlaybackStatus};
+use audio::{LoopStatus, Manager, PlaybackStatus, PlayerEvent};
 use config::PULSE;
 use jargon_args::Jargon;
 use mpris::Event;
 use scanln::scanln;
+use session::Session;
+use std::io::Write;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use track::Track;
 use ui::Ui;
-use util::HELP;
+use util::{uri_to_path, HELP};
 
 fn main() {
     // Parse command line arguments
@@ -43,15 +55,15 @@ fn main() {
         println!("v{}", VERSION);
         std::process::exit(0);
     }
-    // Start into the correct mode
+    // Start into the correct mode, playing any files given
     if args.contains(["-c", "--cli"]) {
-        start_cli();
+        start_cli(&args.finish());
     } else {
-        start_tui();
+        start_tui(&args.finish());
     }
 }
 
-fn start_tui() {
+fn start_tui(files: &[String]) {
     // Handle any panics that may occur
     std::panic::set_hook(Box::new(|e| {
         crossterm::terminal::disable_raw_mode().unwrap();
@@ -66,10 +78,16 @@ fn start_tui() {
     // Build and initialise a manager
     let mut m = Manager::new();
     m.init();
+    // Errors are shown once the interface is up
+    if let Err(e) = open_files(&mut m, files) {
+        m.report_error(e);
+    }
     // Allow for it to be accessed from threads
     let m = Arc::new(Mutex::new(m));
     // Start mpris event loop
     spawn_mpris(&m);
+    // Keep the library in sync with the watched folders
+    watch::spawn(&m);
     // Initiate a text user interface
     if let Ok(mut ui) = Ui::new(m) {
         // Initiate UI lifecycle
@@ -79,47 +97,239 @@ fn start_tui() {
     }
 }
 
-fn start_cli() {
+fn open_files(m: &mut Manager, files: &[String]) -> Result<(), String> {
+    // Play files, folders and playlist files given from outside the player
+    if files.is_empty() {
+        return Ok(());
+    }
+    let failed = m.open_locations(files);
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Couldn't open: {}", failed.join(", ")))
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+fn start_cli(files: &[String]) {
     // Build and initialise a manager
     let mut m = Manager::new();
     m.init();
+    // Errors are shown before the first prompt
+    if let Err(e) = open_files(&mut m, files) {
+        m.report_error(e);
+    }
     // Allow for it to be accessed from threads
     let m = Arc::new(Mutex::new(m));
     // Start mpris event loop
     spawn_mpris(&m);
+    // Keep the library in sync with the watched folders
+    watch::spawn(&m);
     // Initiate a control prompt for the player
     loop {
+        // Show any error from playback since the last command
+        if let Some(error) = m.lock().unwrap().take_error() {
+            println!("ERROR: {}", error);
+        }
         let cmd = scanln!("{}", m.lock().unwrap().config.prompt);
+        // Importing locks the manager by itself, so that it isn't held while reading files
+        if let Some(path) = cmd.strip_prefix("library add ") {
+            let progress = Manager::import(&m, path, |p| {
+                print!("\rImporting: {}/{}", p.done, p.total);
+                std::io::stdout().flush().ok();
+            });
+            println!("\nAdded {} new tracks to the library", progress.added);
+            for file in progress.failed {
+                println!("ERROR: Failed to import: {}", file);
+            }
+            continue;
+        }
+        // Podcasts are fetched and downloaded without holding the manager either
+        if let Some(url) = cmd.strip_prefix("podcast add ") {
+            match Manager::add_feed(&m, url) {
+                Ok(title) => println!("Added podcast: {}", title),
+                Err(e) => println!("ERROR: {}", e),
+            }
+            continue;
+        } else if cmd == "podcast refresh" {
+            let (new, failed) = Manager::refresh_feeds(&m);
+            println!("Found {} new episodes", new);
+            for e in failed {
+                println!("ERROR: {}", e);
+            }
+            continue;
+        } else if let Some(args) = cmd.strip_prefix("podcast download ") {
+            match args.split_once(' ').map(|(f, e)| (f.parse(), e.parse())) {
+                Some((Ok(f), Ok(e))) => match Manager::download_episode(&m, f, e) {
+                    Ok(path) => println!("Downloaded to {}", path),
+                    Err(e) => println!("ERROR: {}", e),
+                },
+                _ => println!("ERROR: Expected a podcast and episode number: {}", args),
+            }
+            continue;
+        }
         let mut m = m.lock().unwrap();
         match cmd.as_str().split(' ').collect::<Vec<&str>>().as_slice() {
             // Opening media
             ["open", "playlist", p] => m.load_playlist(p),
-            ["open", t] => m.load(t.parse().unwrap_or(0)),
+            ["open", t] => {
+                if let Some(t) = parse_id(t) {
+                    m.load(t);
+                }
+            }
             // File tagging
-            ["tag", "title", i, t @ ..] => m.set_title(i.parse().unwrap_or(0), &t.join(" ")),
-            ["tag", "album", i, a @ ..] => m.set_album(i.parse().unwrap_or(0), &a.join(" ")),
-            ["tag", "artist", i, a @ ..] => m.set_artist(i.parse().unwrap_or(0), &a.join(" ")),
-            ["tag", "year", i, y] => m.set_year(i.parse().unwrap_or(0), y),
-            ["tag", "update", i] => m.update_tag(i.parse().unwrap_or(0)),
-            ["tag", i] => m.view_track(i.parse().unwrap_or(0)),
+            ["tag", "title", i, t @ ..] => {
+                if let Some(i) = parse_id(i) {
+                    m.set_title(i, &t.join(" "));
+                }
+            }
+            ["tag", "album", i, a @ ..] => {
+                if let Some(i) = parse_id(i) {
+                    m.set_album(i, &a.join(" "));
+                }
+            }
+            ["tag", "artist", i, a @ ..] => {
+                if let Some(i) = parse_id(i) {
+                    m.set_artist(i, &a.join(" "));
+                }
+            }
+            ["tag", "year", i, y] => {
+                if let Some(i) = parse_id(i) {
+                    m.set_year(i, y);
+                }
+            }
+            ["tag", "update", i] => {
+                if let Some(i) = parse_id(i) {
+                    m.update_tag(i);
+                }
+            }
+            ["tag", i] => {
+                if let Some(i) = parse_id(i) {
+                    m.view_track(i);
+                }
+            }
+            ["rate", i, r] => match (i.parse(), r.parse()) {
+                (Ok(i), Ok(r)) => m.set_rating(i, r),
+                _ => println!("ERROR: Expected a track ID and a rating from 0 to 5"),
+            },
             // Library commands
             ["library"] => println!("{}", m.list_library()),
-            ["library", "add", o @ ..] => {
-                let _ = m.add_library(Track::load(&o.join(" ")));
+            ["library", "remove", i] => {
+                if let Some(i) = parse_id(i) {
+                    m.remove_library(i);
+                }
             }
-            ["library", "remove", i] => m.remove_library(i.parse().unwrap_or(0)),
+            ["library", "filter", f @ ..] => match m.filter(&f.join(" ")) {
+                Ok(ids) => println!("{}", m.list_tracks(&ids)),
+                Err(e) => println!("ERROR: Invalid filter: {}", e),
+            },
+            ["search", q @ ..] => println!("{}", m.list_tracks(&m.search(&q.join(" ")))),
             // Queue and playlist handling
-            ["playlist", "add", p, i] => m.add_to_playlist(p, i.parse().unwrap_or(0)),
-            ["playlist", "remove", p, i] => m.remove_from_playlist(p, i.parse().unwrap_or(0)),
+            ["playlist", "add", p, i] => {
+                if let Some(i) = parse_id(i) {
+                    m.add_to_playlist(p, i);
+                }
+            }
+            ["playlist", "remove", p, i] => match i.parse() {
+                Ok(i) => m.remove_from_playlist(p, i),
+                Err(_) => println!("ERROR: Invalid position: {}", i),
+            },
             ["playlist", "new", p] => m.new_playlist(p),
+            ["playlist", "smart", p, r @ ..] => {
+                if let Err(e) = m.new_smart_playlist(p, &r.join(" ")) {
+                    println!("ERROR: Invalid rule: {}", e);
+                }
+            }
             ["playlist"] => println!("{}", m.list_playlists()),
             ["playlist", p] => println!("{}", m.list_playlist(p)),
             ["playlist", "delete", p] => m.delete_playlist(p),
             ["playlist", "rename", o, n] => m.rename_playlist(o, n),
-            ["queue", t] => m.queue(t.parse().unwrap_or(0)),
+            ["playlist", "export", p, f @ ..] => {
+                if let Err(e) = m.export_playlist(p, &f.join(" ")) {
+                    println!("ERROR: {}", e);
+                }
+            }
+            ["playlist", "import", f @ ..] => match m.import_playlist(&f.join(" ")) {
+                Ok((name, unresolved)) => {
+                    println!("Imported playlist: {}", name);
+                    for entry in unresolved {
+                        println!("ERROR: Couldn't find track: {}", entry);
+                    }
+                }
+                Err(e) => println!("ERROR: {}", e),
+            },
+            ["queue", "next", t] => {
+                if let Some(t) = parse_id(t) {
+                    m.queue_next(t);
+                }
+            }
+            ["queue", t] => {
+                if let Some(t) = parse_id(t) {
+                    m.queue(t);
+                }
+            }
             ["clear"] => m.clear_queue(),
             ["next"] => m.next().unwrap_or(()),
             ["prev"] => m.previous().unwrap_or(()),
+            // Audiobook chapters and bookmarks
+            ["chapters"] => print!("{}", m.list_chapters()),
+            ["next", "chapter"] => m.next_chapter(),
+            ["previous" | "prev", "chapter"] => m.previous_chapter(),
+            ["bookmark"] => print!("{}", m.list_bookmarks()),
+            ["bookmark", "add", n @ ..] => m.add_bookmark(&n.join(" ")),
+            ["bookmark", "remove", n] => match n.parse() {
+                Ok(n) => m.remove_bookmark(n),
+                Err(_) => println!("ERROR: Expected a bookmark number: {}", n),
+            },
+            ["bookmark", "play", n] => match n.parse() {
+                Ok(n) => m.play_bookmark(n),
+                Err(_) => println!("ERROR: Expected a bookmark number: {}", n),
+            },
+            // Listening history
+            ["history"] => print!("{}", m.list_history(20)),
+            ["history", n] => match n.parse() {
+                Ok(n) => print!("{}", m.list_history(n)),
+                Err(_) => println!("ERROR: Expected a number of entries: {}", n),
+            },
+            // Internet radio
+            ["radio"] => print!("{}", m.list_stations()),
+            ["radio", "add", u, n @ ..] => {
+                // Stations are named after their URL unless given a name
+                let name = if n.is_empty() {
+                    u.to_string()
+                } else {
+                    n.join(" ")
+                };
+                m.add_station(&name, u);
+            }
+            ["radio", "remove", i] => match i.parse() {
+                Ok(i) => m.remove_station(i),
+                Err(_) => println!("ERROR: Expected a station number: {}", i),
+            },
+            ["radio", "play", i] => match i.parse() {
+                Ok(i) => m.play_station(i),
+                Err(_) => println!("ERROR: Expected a station number: {}", i),
+            },
+            // Podcasts
+            ["podcast"] => print!("{}", m.list_feeds()),
+            ["podcast", "episodes", f] => match f.parse() {
+                Ok(f) => print!("{}", m.list_episodes(f)),
+                Err(_) => println!("ERROR: Expected a podcast number: {}", f),
+            },
+            ["podcast", "remove", f] => match f.parse() {
+                Ok(f) => m.remove_feed(f),
+                Err(_) => println!("ERROR: Expected a podcast number: {}", f),
+            },
+            ["podcast", "play", f, e] => match (f.parse(), e.parse()) {
+                (Ok(f), Ok(e)) => m.play_episode(f, e),
+                _ => println!("ERROR: Expected a podcast and episode number: {} {}", f, e),
+            },
+            ["podcast", kind @ ("played" | "unplayed"), f, e] => match (f.parse(), e.parse()) {
+                (Ok(f), Ok(e)) => m.set_played(f, e, *kind == "played"),
+                _ => println!("ERROR: Expected a podcast and episode number: {} {}", f, e),
+            },
+            // Scrobbling
+            ["scrobbles"] => print!("{}", m.scrobbler.status()),
             // Metadata
             ["status"] => {
                 let (p, d, pr) = m.get_position().unwrap_or((0, 0, 0.0));
@@ -156,11 +366,17 @@ fn start_cli() {
                 let volume = m.metadata.lock().unwrap().volume;
                 m.set_volume(volume - 0.3);
             }
-            ["volume", "set", v] => m.set_volume(v.parse().unwrap_or(1.0)),
+            ["volume", "set", v] => match v.parse::<f64>() {
+                Ok(v) if v >= 0.0 => m.set_volume(v),
+                _ => println!("ERROR: Invalid volume: {}", v),
+            },
             ["volume", "get"] => println!("{}", m.metadata.lock().unwrap().volume),
             ["volume", "reset"] => m.set_volume(1.0),
             // Position controls
-            ["position", "set", p] => m.set_position(p.parse().unwrap_or(-1)),
+            ["position", "set", p] => match p.parse::<f64>() {
+                Ok(p) if p >= 0.0 => m.set_position(Duration::from_secs_f64(p)),
+                _ => println!("ERROR: Invalid position: {}", p),
+            },
             ["position", "get"] => {
                 let (p, d, pr) = m.get_position().unwrap_or((0, 0, 0.0));
                 println!("{}s / {}s ({:.2}%)", p, d, pr * 100.);
@@ -169,7 +385,12 @@ fn start_cli() {
             ["seek", "forward"] => m.seek(true, Duration::from_secs(5)),
             // Exit player
             ["exit"] => {
+                m.record_listen(false);
                 m.database.write();
+                // Panes are only kept by the TUI, so leave them as they were
+                let mut session = Session::read();
+                session.playback = Some(m.playback());
+                session.write();
                 std::process::exit(0)
             }
             // Unknown command
@@ -179,6 +400,15 @@ fn start_cli() {
     }
 }
 
+fn parse_id(text: &str) -> Option<usize> {
+    // Read a track ID from a command, reporting it if it isn't one
+    let id = text.parse().ok();
+    if id.is_none() {
+        println!("ERROR: Expected a track ID: {}", text);
+    }
+    id
+}
+
 fn spawn_mpris(m: &Arc<Mutex<Manager>>) {
     // Spawn a manager event loop, which handles mpris requests
     std::thread::spawn({
@@ -190,7 +420,11 @@ fn spawn_mpris(m: &Arc<Mutex<Manager>>) {
                 let mut m = m.lock().unwrap();
                 if let Ok(e) = m.mpris.try_recv() {
                     match e {
-                        Event::OpenUri(uri) => m.open(Track::load(&uri)),
+                        Event::OpenUri(uri) => {
+                            if let Err(e) = open_files(&mut m, &[uri]) {
+                                m.report_error(e);
+                            }
+                        }
                         Event::Pause => m.pause(),
                         Event::Play => m.play(),
                         Event::PlayPause => m.play_pause(),
@@ -202,20 +436,42 @@ fn spawn_mpris(m: &Arc<Mutex<Manager>>) {
                         Event::Stop => m.stop(),
                         Event::Next => m.next().unwrap_or(()),
                         Event::Previous => m.previous().unwrap_or(()),
+                        Event::AddTrack(uri, after, current) => {
+                            if let Some(track) = uri_to_path(&uri).and_then(|p| Track::load(&p)) {
+                                m.add_track(track, after, current);
+                            }
+                        }
+                        Event::RemoveTrack(id) => m.remove_track(id),
+                        Event::GoTo(id) => m.go_to(id).unwrap_or(()),
+                        Event::ActivatePlaylist(name) => {
+                            m.load_playlist(&name);
+                            m.play();
+                        }
                         Event::Raise | Event::Quit => (),
                     }
                 }
 
-                // Stop status after track has finished
-                let status = m.metadata.lock().unwrap().playback_status;
-                #[allow(clippy::float_cmp)]
-                if m.get_position().unwrap_or((0, 0, 0.0)).2 == 1.
-                    && status != PlaybackStatus::Stopped
-                {
-                    m.metadata.lock().unwrap().playback_status = PlaybackStatus::Stopped;
-                    m.next();
-                    m.update();
+                // Handle player event
+                if let Ok(e) = m.events.try_recv() {
+                    match e {
+                        PlayerEvent::EndOfStream => {
+                            // Stop status after track has finished, then move on
+                            m.metadata.lock().unwrap().playback_status = PlaybackStatus::Stopped;
+                            m.advance();
+                            m.update();
+                        }
+                        PlayerEvent::TrackChanged => m.advance_gapless(),
+                        PlayerEvent::Error(e) => {
+                            // Skip over tracks that can't be played
+                            m.report_error(format!("Failed to play track: {}", e));
+                            m.metadata.lock().unwrap().playback_status = PlaybackStatus::Stopped;
+                            m.skip_failed();
+                            m.update();
+                        }
+                    }
                 }
+                // Remember how far into the playing episode or audiobook playback has got
+                m.keep_position();
                 std::mem::drop(m);
                 // Wait before next loop
                 std::thread::sleep(Duration::from_millis(PULSE));
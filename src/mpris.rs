@@ -1,14 +1,14 @@
 // mpris.rs - handling mpris interactions
 use crate::audio::{LoopStatus, Metadata};
 use crate::config::DBUS_PULSE;
-use crate::track::Tag;
+use crate::track::Track;
 use dbus::arg::{RefArg, Variant};
 use dbus::blocking::Connection;
 use dbus::channel::MatchingReceiver;
 use dbus::ffidisp::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged as Ppc;
 use dbus::message::SignalArgs;
 use dbus::strings::Path as DbusPath;
-use dbus::MethodErr;
+use dbus::{Message, MethodErr};
 use dbus_crossroads::{Crossroads, IfaceBuilder};
 use std::collections::HashMap;
 use std::sync::{mpsc, Arc, Mutex};
@@ -16,6 +16,40 @@ use std::time::Duration;
 
 // Types
 type EventHandler = Arc<Mutex<dyn Fn(Event) + Send + 'static>>;
+type MprisMetadata = HashMap<String, Variant<Box<dyn RefArg>>>;
+type MprisPlaylist = (DbusPath<'static>, String, String);
+
+// Object paths for tracks in the track list, outside the /org/mpris namespace the spec reserves
+const TRACK_PATH: &str = "/com/github/curlpipe/synchron/track/";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+// Object paths for stored playlists, named by their ID
+const PLAYLIST_PATH: &str = "/com/github/curlpipe/synchron/playlist/";
+const NO_PLAYLIST: &str = "/";
+// What can be opened through OpenUri, including folders and playlist files
+const URI_SCHEMES: [&str; 1] = ["file"];
+const MIME_TYPES: [&str; 21] = [
+    "audio/mpeg",
+    "audio/flac",
+    "audio/x-flac",
+    "audio/ogg",
+    "audio/vorbis",
+    "audio/opus",
+    "audio/mp4",
+    "audio/x-m4a",
+    "audio/aac",
+    "audio/wav",
+    "audio/x-wav",
+    "audio/aiff",
+    "audio/x-aiff",
+    "audio/x-ape",
+    "audio/x-wavpack",
+    "inode/directory",
+    "audio/x-mpegurl",
+    "audio/mpegurl",
+    "application/vnd.apple.mpegurl",
+    "audio/x-scpls",
+    "application/xspf+xml",
+];
 
 // Representation of control events
 #[derive(Clone, Debug)]
@@ -23,7 +57,7 @@ pub enum Event {
     OpenUri(String),
     SetLoopStatus(LoopStatus),
     SetShuffleStatus(bool),
-    SetPosition(i64),
+    SetPosition(Duration),
     SetVolume(f64),
     Seek(bool, Duration),
     Play,
@@ -34,6 +68,10 @@ pub enum Event {
     Stop,
     Raise,
     Quit,
+    AddTrack(String, Option<usize>, bool),
+    RemoveTrack(usize),
+    GoTo(usize),
+    ActivatePlaylist(String),
 }
 
 #[allow(clippy::too_many_lines)]
@@ -54,15 +92,17 @@ pub fn connect(ev: EventHandler, md: &Arc<Mutex<Metadata>>, update: &mpsc::Recei
             b.property("Identity").get(move |_, _| Ok(name.clone()));
             b.property("CanQuit").get(move |_, _| Ok(true));
             b.property("CanRaise").get(move |_, _| Ok(true));
-            b.property("HasTrackList").get(move |_, _| Ok(false));
+            b.property("HasTrackList").get(move |_, _| Ok(true));
             b.property("SupportedUriSchemes")
-                .get(move |_, _| Ok(&[] as &[String]));
+                .get(move |_, _| Ok(URI_SCHEMES.map(String::from).to_vec()));
             b.property("SupportedMimeTypes")
-                .get(move |_, _| Ok(&[] as &[String]));
+                .get(move |_, _| Ok(MIME_TYPES.map(String::from).to_vec()));
         }
     });
     // Register Player
     let player_md = md.clone();
+    let tracklist_handler = ev.clone();
+    let playlists_handler = ev.clone();
     let mp2p = cr.register("org.mpris.MediaPlayer2.Player", move |b| {
         // Register play, pause, next, preivous and stop events
         register(b, &ev, "Play", Event::Play);
@@ -78,6 +118,8 @@ pub fn connect(ev: EventHandler, md: &Arc<Mutex<Metadata>>, update: &mpsc::Recei
         b.property("CanGoNext").get(|_, _| Ok(true));
         b.property("CanGoPrevious").get(|_, _| Ok(true));
         b.property("CanSeek").get(|_, _| Ok(true));
+        // Signal sent whenever the position jumps
+        b.signal::<(i64,), _>("Seeked", ("Position",));
         // Get the playback status from the metadata
         b.property("PlaybackStatus").get({
             let md = player_md.clone();
@@ -119,7 +161,12 @@ pub fn connect(ev: EventHandler, md: &Arc<Mutex<Metadata>>, update: &mpsc::Recei
         b.property("Position").get({
             let md = player_md.clone();
             move |_, _| -> Result<i64, MethodErr> {
-                Ok(md.lock().unwrap().position.0.try_into().unwrap())
+                Ok(md
+                    .lock()
+                    .unwrap()
+                    .position_micros
+                    .try_into()
+                    .unwrap_or(i64::MAX))
             }
         });
         b.property("Volume")
@@ -138,26 +185,21 @@ pub fn connect(ev: EventHandler, md: &Arc<Mutex<Metadata>>, update: &mpsc::Recei
         // Get and format the track information from the metadata
         b.property("Metadata").get({
             let md = player_md.clone();
-            move |_, _| {
-                let mut export = mpris_metadata(&md.lock().unwrap().tag);
-                export.insert(
-                    "mpris:trackid".to_string(),
-                    Variant(Box::new(DbusPath::new("/").unwrap())),
-                );
-                Ok(export)
-            }
+            move |_, _| Ok(current_metadata(&md.lock().unwrap()))
         });
         // Method to set the position as requested through dbus
         b.method("SetPosition", ("TrackID", "Position"), (), {
             let ev = ev.clone();
-            move |_, _, (_, position): (DbusPath, i64)| {
-                // Send to event handler, in the correct format (seconds)
-                (ev.lock().unwrap())(Event::SetPosition(
-                    Duration::from_micros(position.try_into().unwrap_or(0))
-                        .as_secs()
-                        .try_into()
-                        .unwrap_or(0),
-                ));
+            let md = player_md.clone();
+            move |_, _, (id, position): (DbusPath, i64)| {
+                // Ignore requests for stale tracks or negative positions, as the spec requires
+                if track_id(&id) != md.lock().unwrap().track_id {
+                    return Ok(());
+                }
+                if let Ok(position) = position.try_into() {
+                    // Send to event handler
+                    (ev.lock().unwrap())(Event::SetPosition(Duration::from_micros(position)));
+                }
                 Ok(())
             }
         });
@@ -166,7 +208,7 @@ pub fn connect(ev: EventHandler, md: &Arc<Mutex<Metadata>>, update: &mpsc::Recei
             let ev = ev.clone();
             move |_, _, (offset,): (i64,)| {
                 // Work out direction and magnitude of seek
-                let magnitude = offset.abs() as u64;
+                let magnitude = offset.unsigned_abs();
                 let forwards = offset > 0;
                 // Send to event handler
                 (ev.lock().unwrap())(Event::Seek(forwards, Duration::from_micros(magnitude)));
@@ -176,14 +218,161 @@ pub fn connect(ev: EventHandler, md: &Arc<Mutex<Metadata>>, update: &mpsc::Recei
         // Method to open a new media file
         b.method("OpenUri", ("Uri",), (), {
             move |_, _, (uri,): (String,)| {
-                // Send to event handler
+                // Turn away schemes that aren't supported, then send to event handler
+                if uri.contains("://")
+                    && !URI_SCHEMES
+                        .iter()
+                        .any(|s| uri.starts_with(&format!("{}://", s)))
+                {
+                    return Err(MethodErr::invalid_arg(&uri));
+                }
                 (ev.lock().unwrap())(Event::OpenUri(uri));
                 Ok(())
             }
         });
     });
+    // Register TrackList
+    let tracklist_md = md.clone();
+    let tracklist = cr.register("org.mpris.MediaPlayer2.TrackList", move |b| {
+        // Signals sent when the play queue changes
+        b.signal::<(Vec<DbusPath<'static>>, DbusPath<'static>), _>(
+            "TrackListReplaced",
+            ("Tracks", "CurrentTrack"),
+        );
+        b.signal::<(MprisMetadata, DbusPath<'static>), _>("TrackAdded", ("Metadata", "AfterTrack"));
+        b.signal::<(DbusPath<'static>,), _>("TrackRemoved", ("TrackId",));
+        b.property("CanEditTracks").get(|_, _| Ok(true));
+        // Get the tracks in the play queue from the metadata
+        b.property("Tracks").get({
+            let md = tracklist_md.clone();
+            move |_, _| {
+                let md = md.lock().unwrap();
+                Ok(md
+                    .track_list
+                    .iter()
+                    .map(|(id, _)| track_path(Some(*id)))
+                    .collect::<Vec<_>>())
+            }
+        });
+        // Method to get the metadata of tracks in the play queue
+        b.method("GetTracksMetadata", ("TrackIds",), ("Metadata",), {
+            let md = tracklist_md.clone();
+            move |_, _, (paths,): (Vec<DbusPath>,)| {
+                let md = md.lock().unwrap();
+                let mut result = vec![];
+                for id in paths.iter().filter_map(track_id) {
+                    if md.track_id == Some(id) {
+                        result.push(current_metadata(&md));
+                    } else if let Some((_, track)) = md.track_list.iter().find(|(i, _)| *i == id) {
+                        result.push(mpris_metadata(Some(id), track));
+                    }
+                }
+                Ok((result,))
+            }
+        });
+        // Method to add a track to the play queue
+        b.method("AddTrack", ("Uri", "AfterTrack", "SetAsCurrent"), (), {
+            let ev = tracklist_handler.clone();
+            move |_, _, (uri, after, current): (String, DbusPath, bool)| {
+                // Send to event handler
+                (ev.lock().unwrap())(Event::AddTrack(uri, track_id(&after), current));
+                Ok(())
+            }
+        });
+        // Method to remove a track from the play queue
+        b.method("RemoveTrack", ("TrackId",), (), {
+            let ev = tracklist_handler.clone();
+            move |_, _, (path,): (DbusPath,)| {
+                if let Some(id) = track_id(&path) {
+                    // Send to event handler
+                    (ev.lock().unwrap())(Event::RemoveTrack(id));
+                }
+                Ok(())
+            }
+        });
+        // Method to skip to a track in the play queue
+        b.method("GoTo", ("TrackId",), (), {
+            let ev = tracklist_handler.clone();
+            move |_, _, (path,): (DbusPath,)| {
+                if let Some(id) = track_id(&path) {
+                    // Send to event handler
+                    (ev.lock().unwrap())(Event::GoTo(id));
+                }
+                Ok(())
+            }
+        });
+    });
+    // Register Playlists
+    let playlists_md = md.clone();
+    let playlists = cr.register("org.mpris.MediaPlayer2.Playlists", move |b| {
+        b.signal::<(MprisPlaylist,), _>("PlaylistChanged", ("Playlist",));
+        b.property("Orderings")
+            .get(|_, _| Ok(vec!["UserDefined".to_string(), "Alphabetical".to_string()]));
+        // Get the number of playlists from the metadata
+        b.property("PlaylistCount").get({
+            let md = playlists_md.clone();
+            move |_, _| -> Result<u32, MethodErr> {
+                let count = md.lock().unwrap().playlists.len();
+                Ok(count.try_into().unwrap_or(u32::MAX))
+            }
+        });
+        // Get the playlist being played from the metadata
+        b.property("ActivePlaylist").get({
+            let md = playlists_md.clone();
+            move |_, _| Ok(active_playlist(md.lock().unwrap().playlist.as_ref()))
+        });
+        // Method to list the stored playlists
+        b.method(
+            "GetPlaylists",
+            ("Index", "MaxCount", "Order", "ReverseOrder"),
+            ("Playlists",),
+            {
+                let md = playlists_md.clone();
+                move |_, _, (index, max, order, reverse): (u32, u32, String, bool)| {
+                    let mut list = md.lock().unwrap().playlists.clone();
+                    if order == "Alphabetical" {
+                        list.sort_by(|a, b| a.1.cmp(&b.1));
+                    }
+                    if reverse {
+                        list.reverse();
+                    }
+                    let result: Vec<MprisPlaylist> = list
+                        .iter()
+                        .skip(index as usize)
+                        .take(max as usize)
+                        .map(|(id, name)| mpris_playlist(*id, name))
+                        .collect();
+                    Ok((result,))
+                }
+            },
+        );
+        // Method to start playing a stored playlist
+        b.method("ActivatePlaylist", ("PlaylistId",), (), {
+            let ev = playlists_handler.clone();
+            let md = playlists_md.clone();
+            move |_, _, (path,): (DbusPath,)| {
+                let id = playlist_id(&path);
+                let name = md
+                    .lock()
+                    .unwrap()
+                    .playlists
+                    .iter()
+                    .find(|(i, _)| Some(*i) == id)
+                    .map(|(_, name)| name.clone());
+                if let Some(name) = name {
+                    // Send to event handler
+                    (ev.lock().unwrap())(Event::ActivatePlaylist(name));
+                }
+                Ok(())
+            }
+        });
+    });
     // Insert into mpris
-    cr.insert("/org/mpris/MediaPlayer2", &[mp2, mp2p], ());
+    cr.insert(
+        "/org/mpris/MediaPlayer2",
+        &[mp2, mp2p, tracklist, playlists],
+        (),
+    );
     // Start recieving events
     c.start_receive(
         dbus::message::MatchRule::new_method_call(),
@@ -193,10 +382,31 @@ pub fn connect(ev: EventHandler, md: &Arc<Mutex<Metadata>>, update: &mpsc::Recei
         }),
     );
     // Start server loop
+    let mut tracks: Vec<usize> = vec![];
+    let mut active: Option<(usize, String)> = None;
     loop {
         if update.try_recv().is_ok() {
             // When an update event is received, update information in the player
-            let m = md.lock().unwrap();
+            let mut m = md.lock().unwrap();
+            // Notify of jumps in the position
+            if let Some(position) = m.seeked.take() {
+                let position: i64 = position.try_into().unwrap_or(i64::MAX);
+                c.channel()
+                    .send(
+                        Message::signal(
+                            &DbusPath::new("/org/mpris/MediaPlayer2").unwrap(),
+                            &"org.mpris.MediaPlayer2.Player".into(),
+                            &"Seeked".into(),
+                        )
+                        .append1(position),
+                    )
+                    .unwrap();
+            }
+            // Notify of changes to the play queue
+            for signal in track_list_signals(&tracks, &m) {
+                c.channel().send(signal).unwrap();
+            }
+            tracks = m.track_list.iter().map(|(id, _)| *id).collect();
             let mut changed = Ppc {
                 interface_name: "org.mpris.MediaPlayer2.Player".to_string(),
                 ..Ppc::default()
@@ -214,17 +424,46 @@ pub fn connect(ev: EventHandler, md: &Arc<Mutex<Metadata>>, update: &mpsc::Recei
             );
             add_prop!(changed.changed_properties, "Shuffle", m.shuffle_status);
             add_prop!(changed.changed_properties, "Volume", m.volume);
-            add_prop!(
-                changed.changed_properties,
-                "Metadata",
-                mpris_metadata(&m.tag)
-            );
+            add_prop!(changed.changed_properties, "Metadata", current_metadata(&m));
             // Send the message
             c.channel()
                 .send(changed.to_emit_message(
                     &DbusPath::new("/org/mpris/MediaPlayer2".to_string()).unwrap(),
                 ))
                 .unwrap();
+            // Update information about playlists
+            let mut changed = Ppc {
+                interface_name: "org.mpris.MediaPlayer2.Playlists".to_string(),
+                ..Ppc::default()
+            };
+            let count: u32 = m.playlists.len().try_into().unwrap_or(u32::MAX);
+            add_prop!(changed.changed_properties, "PlaylistCount", count);
+            if m.playlist != active {
+                add_prop!(
+                    changed.changed_properties,
+                    "ActivePlaylist",
+                    active_playlist(m.playlist.as_ref())
+                );
+                active.clone_from(&m.playlist);
+            }
+            c.channel()
+                .send(changed.to_emit_message(
+                    &DbusPath::new("/org/mpris/MediaPlayer2".to_string()).unwrap(),
+                ))
+                .unwrap();
+            // Announce playlists that have been renamed
+            for (id, name) in m.changed_playlists.drain(..) {
+                c.channel()
+                    .send(
+                        Message::signal(
+                            &DbusPath::new("/org/mpris/MediaPlayer2").unwrap(),
+                            &"org.mpris.MediaPlayer2.Playlists".into(),
+                            &"PlaylistChanged".into(),
+                        )
+                        .append1(mpris_playlist(id, &name)),
+                    )
+                    .unwrap();
+            }
         }
         // Wait before checking again
         c.process(std::time::Duration::from_millis(DBUS_PULSE))
@@ -241,12 +480,160 @@ pub fn register(b: &mut IfaceBuilder<()>, ev: &EventHandler, name: &'static str,
     });
 }
 
-fn mpris_metadata(tag: &Tag) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+fn track_list_signals(old: &[usize], m: &Metadata) -> Vec<Message> {
+    // Work out which signals describe the changes made to the play queue
+    let new: Vec<usize> = m.track_list.iter().map(|(id, _)| *id).collect();
+    let path = DbusPath::new("/org/mpris/MediaPlayer2").unwrap();
+    let iface = "org.mpris.MediaPlayer2.TrackList".into();
+    let mut result = vec![];
+    if old == new.as_slice() {
+        // Nothing has changed
+    } else if new.len() > old.len() && is_subsequence(old, &new) {
+        // Tracks have only been added
+        let mut remaining = old.iter().peekable();
+        for (c, (id, track)) in m.track_list.iter().enumerate() {
+            if remaining.peek() == Some(&id) {
+                remaining.next();
+                continue;
+            }
+            let after = c.checked_sub(1).map(|p| new[p]);
+            result.push(
+                Message::signal(&path, &iface, &"TrackAdded".into())
+                    .append2(mpris_metadata(Some(*id), track), track_path(after)),
+            );
+        }
+    } else if new.len() < old.len() && is_subsequence(&new, old) {
+        // Tracks have only been removed
+        let mut remaining = new.iter().peekable();
+        for id in old {
+            if remaining.peek() == Some(&id) {
+                remaining.next();
+                continue;
+            }
+            result.push(
+                Message::signal(&path, &iface, &"TrackRemoved".into())
+                    .append1(track_path(Some(*id))),
+            );
+        }
+    } else {
+        // Play queue has been rearranged or swapped out
+        let paths: Vec<DbusPath> = new.iter().map(|id| track_path(Some(*id))).collect();
+        result.push(
+            Message::signal(&path, &iface, &"TrackListReplaced".into())
+                .append2(paths, track_path(m.track_id)),
+        );
+    }
+    result
+}
+
+fn is_subsequence(short: &[usize], long: &[usize]) -> bool {
+    // Determine if every item in short appears in long, in the same order
+    let mut long = long.iter();
+    short.iter().all(|s| long.any(|l| l == s))
+}
+
+fn track_path(id: Option<usize>) -> DbusPath<'static> {
+    // Form the object path of a track in the track list
+    if let Some(id) = id {
+        DbusPath::new(format!("{}{}", TRACK_PATH, id)).unwrap()
+    } else {
+        DbusPath::new(NO_TRACK).unwrap()
+    }
+}
+
+fn track_id(path: &DbusPath) -> Option<usize> {
+    // Get the track ID back out of a track list object path
+    path.strip_prefix(TRACK_PATH)?.parse().ok()
+}
+
+fn mpris_playlist(id: usize, name: &str) -> MprisPlaylist {
+    // Form a playlist for mpris, with an object path from its ID so it survives a rename
+    let path = DbusPath::new(format!("{}{}", PLAYLIST_PATH, id)).unwrap();
+    (path, name.to_string(), String::new())
+}
+
+fn playlist_id(path: &DbusPath) -> Option<usize> {
+    // Get the playlist ID back out of a playlist object path
+    path.strip_prefix(PLAYLIST_PATH)?.parse().ok()
+}
+
+fn active_playlist(playlist: Option<&(usize, String)>) -> (bool, MprisPlaylist) {
+    // Form the active playlist structure for mpris
+    if let Some((id, name)) = playlist {
+        (true, mpris_playlist(*id, name))
+    } else {
+        let path = DbusPath::new(NO_PLAYLIST).unwrap();
+        (false, (path, String::new(), String::new()))
+    }
+}
+
+fn mpris_metadata(id: Option<usize>, track: &Track) -> MprisMetadata {
     // Create a hashmap of id3 tags for mpris
-    let mut md: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+    let tag = &track.tag;
+    let mut md: MprisMetadata = HashMap::new();
+    add_prop!(md, "mpris:trackid", track_path(id));
+    add_prop!(md, "xesam:url", track.path.clone());
     add_prop!(md, "xesam:title", tag.title.clone());
     add_prop!(md, "xesam:album", tag.album.clone());
-    add_prop!(md, "xesam:artist", tag.artist.clone());
-    add_prop!(md, "xesam:year", tag.year.clone());
+    add_prop!(md, "xesam:artist", vec![tag.artist.clone()]);
+    // Only the year is known of when a track was made, so it is given as the start of that year
+    if let Some(year) = tag.year.parse::<u32>().ok().filter(|y| *y > 0) {
+        add_prop!(
+            md,
+            "xesam:contentCreated",
+            format!("{:04}-01-01T00:00:00Z", year)
+        );
+    }
+    if let Some(number) = tag.track_number.and_then(|n| i32::try_from(n).ok()) {
+        add_prop!(md, "xesam:trackNumber", number);
+    }
+    if let Some(genre) = &tag.genre {
+        add_prop!(md, "xesam:genre", vec![genre.clone()]);
+    }
+    // Durations in tags are in seconds, where mpris wants microseconds
+    if let Some(duration) = tag.duration {
+        let length = i64::try_from(duration.saturating_mul(1_000_000)).unwrap_or(i64::MAX);
+        add_prop!(md, "mpris:length", length);
+    }
+    let plays = i32::try_from(track.stats.plays).unwrap_or(i32::MAX);
+    add_prop!(md, "xesam:useCount", plays);
+    add_prop!(md, "xesam:userRating", f64::from(track.stats.rating) / 5.0);
+    md
+}
+
+fn current_metadata(m: &Metadata) -> MprisMetadata {
+    // Add the details only known about the track that is currently loaded
+    let track = Track {
+        path: m.path.clone().unwrap_or_default(),
+        tag: m.tag.clone(),
+        stats: m.stats.clone(),
+        ..Track::default()
+    };
+    let mut md = mpris_metadata(m.track_id, &track);
+    if m.path.is_none() {
+        md.remove("xesam:url");
+    }
+    if let Some(length) = m.length {
+        add_prop!(
+            md,
+            "mpris:length",
+            i64::try_from(length).unwrap_or(i64::MAX)
+        );
+    }
+    if let Some(art) = &m.art {
+        add_prop!(md, "mpris:artUrl", art.clone());
+    }
+    if let Some(station) = &m.station {
+        // Stream titles are usually written as "Artist - Title"
+        let title = m.stream_title.clone().unwrap_or_else(|| station.clone());
+        if let Some((artist, title)) = title.split_once(" - ") {
+            add_prop!(md, "xesam:artist", vec![artist.to_string()]);
+            add_prop!(md, "xesam:title", title.to_string());
+        } else {
+            add_prop!(md, "xesam:artist", Vec::<String>::new());
+            add_prop!(md, "xesam:title", title);
+        }
+        add_prop!(md, "xesam:album", station.clone());
+    }
     md
 }
@@ -1,134 +1,600 @@
 // playlist.rs - tools for mananging playlists and queuing for the next and previous operations
+use crate::audio::LoopStatus;
 use crate::Track;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 #[derive(Default)]
 pub struct PlayList {
+    // Playlist context, the playlist or library view that playback was started from
     tracks: Vec<Track>,
     pub ids: Vec<usize>,
     pub ptr: Option<usize>,
+    // Whether the pointer is at a track yet to play, as the one it was at has been removed
+    upcoming: bool,
     pub name: Option<String>,
+    // Order to play the playlist context in, as indices into the tracks
+    order: Vec<usize>,
+    pub loop_status: LoopStatus,
+    shuffle: bool,
+    // Immediate queue, consumed before the playlist context
+    queue: VecDeque<(Track, usize)>,
+    // Track taken from the immediate queue that is currently playing
+    playing: Option<(Track, usize)>,
+}
+
+// A play queue written down by track ID, so it can be put back together later
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub ids: Vec<usize>,
+    pub ptr: Option<usize>,
+    #[serde(default)]
+    pub upcoming: bool,
+    pub name: Option<String>,
+    pub order: Vec<usize>,
+    pub loop_status: LoopStatus,
+    pub shuffle: bool,
+    pub queue: Vec<usize>,
+    pub playing: Option<usize>,
 }
 
 impl PlayList {
     pub fn queue(&mut self, track: Track, id: usize) {
-        // Add song onto the end of the playlist
-        self.tracks.push(track);
-        self.ids.push(id);
+        // Add song onto the end of the immediate queue
+        self.queue.push_back((track, id));
     }
 
     pub fn queue_next(&mut self, track: Track, id: usize) {
         // Add song to play immediately after the current one
-        self.tracks.insert(self.get_ptr() + 1, track);
-        self.ids.insert(self.get_ptr() + 1, id);
+        self.queue.push_front((track, id));
     }
 
     pub fn play(&mut self, track: Track, id: usize) -> Option<Track> {
-        // Immediately add song and start playing it
-        if !self.is_ready() {
-            self.ptr = Some(0);
-        }
-        if self.tracks.is_empty() {
+        // Immediately start playing a song, without disturbing the playlist context
+        self.playing = Some((track, id));
+        self.current()
+    }
+
+    pub fn insert(&mut self, track: Track, id: usize, after: Option<usize>) {
+        // Add a track to the play queue after another one, or at the very start without one
+        let after = match after {
+            Some(after) => after,
+            None if self.tracks.is_empty() => return self.queue_next(track, id),
+            None => return self.insert_context(0, track, id),
+        };
+        // The context track interrupted by a queued track comes before it
+        let interrupted = self.ptr.and_then(|p| self.ids.get(p)) == Some(&after) && !self.upcoming;
+        if Some(after) == self.current_id() || interrupted {
+            // Nothing can come between the current track and the immediate queue
+            self.queue_next(track, id);
+        } else if let Some(idx) = self.queue.iter().position(|(_, i)| *i == after) {
+            self.queue.insert(idx + 1, (track, id));
+        } else if let Some(idx) = self.ids.iter().position(|i| *i == after) {
+            let position = self.order.iter().position(|i| *i == idx).unwrap_or(0);
+            self.insert_context(position + 1, track, id);
+        } else {
             self.queue(track, id);
-            self.current()
+        }
+    }
+
+    fn insert_context(&mut self, position: usize, track: Track, id: usize) {
+        // Put a track into the playlist context at a position in the play order
+        // Without shuffle the play order follows the tracks, so the track goes in at the same place
+        let idx = if self.shuffle {
+            self.tracks.len()
         } else {
-            self.queue_next(track, id);
-            self.next()
+            position
+        };
+        // A track put where the pointer waits at a track yet to play becomes the one to play next
+        let waiting = self.upcoming && self.position() == Some(position);
+        self.tracks.insert(idx, track);
+        self.ids.insert(idx, id);
+        for i in &mut self.order {
+            if *i >= idx {
+                *i += 1;
+            }
+        }
+        match self.ptr {
+            _ if waiting => self.ptr = Some(idx),
+            Some(ptr) if ptr >= idx => self.ptr = Some(ptr + 1),
+            _ => (),
         }
+        self.order.insert(position.min(self.order.len()), idx);
     }
 
     pub fn set(&mut self, ptr: usize, tracks: Vec<Track>, ids: Vec<usize>) {
         // Insert a custom playlist to use, as well as an index to start from
         self.ptr = Some(ptr);
+        self.upcoming = false;
         self.tracks = tracks;
         self.ids = ids;
+        self.playing = None;
+        self.reorder();
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        // Turn shuffle on or off, turning it off restores the original order
+        self.shuffle = shuffle;
+        self.reorder();
+    }
+
+    fn reorder(&mut self) {
+        // Work out the order to play the playlist context in
+        self.order = (0..self.tracks.len()).collect();
+        if self.shuffle {
+            self.order.shuffle(&mut rand::thread_rng());
+            // Keep the current track first, so every other track is played once after it
+            if let Some(idx) = self.position() {
+                self.order.swap(0, idx);
+            }
+        }
+    }
+
+    fn position(&self) -> Option<usize> {
+        // Find where the current track in the playlist context is within the play order
+        let ptr = self.ptr?;
+        self.order.iter().position(|i| *i == ptr)
     }
 
     pub fn clear(&mut self) {
-        // Clear the playlist
+        // Clear the playlist and the immediate queue
         self.tracks.clear();
         self.ids.clear();
-        self.ptr = Some(0);
+        self.order.clear();
+        self.queue.clear();
+        self.playing = None;
+        self.ptr = None;
+        self.upcoming = false;
     }
 
     pub fn next(&mut self) -> Option<Track> {
-        // Switch to the next track in the queue
-        if self.ptr? + 1 >= self.tracks.len() {
-            None
-        } else {
-            self.ptr = Some(self.ptr? + 1);
-            self.current()
+        // Switch to the next track, taking from the immediate queue first
+        if let Some(next) = self.queue.pop_front() {
+            // Queued tracks are removed as soon as they are played
+            self.playing = Some(next);
+            return self.current();
         }
+        let next = self.next_position()?;
+        self.playing = None;
+        self.ptr = Some(self.order[next]);
+        self.upcoming = false;
+        self.current()
     }
 
-    pub fn previous(&mut self) -> Option<Track> {
-        // Switch to the previously played track
-        if self.ptr? > 0 {
-            self.ptr = Some(self.ptr? - 1);
+    pub fn advance(&mut self) -> Option<Track> {
+        // Move on once the current track has finished, replaying it if looping the track
+        if matches!(self.loop_status, LoopStatus::Track) && self.is_ready() {
             self.current()
+        } else {
+            self.next()
+        }
+    }
+
+    pub fn peek_advance(&self) -> Option<Track> {
+        // Find the track that advance will move onto, without moving onto it
+        if matches!(self.loop_status, LoopStatus::Track) && self.is_ready() {
+            return self.current();
+        }
+        if let Some((track, _)) = self.queue.front() {
+            return Some(track.clone());
+        }
+        let next = self.next_position()?;
+        Some(self.tracks[self.order[next]].clone())
+    }
+
+    fn next_position(&self) -> Option<usize> {
+        // Work out which position in the play order comes after the current one
+        let position = self.position()?;
+        if self.upcoming {
+            // Carry on from the track that took the place of the removed one
+            Some(position)
+        } else if position + 1 < self.order.len() {
+            Some(position + 1)
+        } else if matches!(self.loop_status, LoopStatus::Playlist) {
+            // Wrap around to the start of the playlist
+            Some(0)
         } else {
             None
         }
     }
 
+    pub fn previous(&mut self) -> Option<Track> {
+        // Switch to the previously played track (immediate queue history is never kept)
+        if self.playing.is_some() && self.ptr.is_some() && !self.upcoming {
+            // Return to the track in the playlist context that was interrupted
+            self.playing = None;
+            return self.current();
+        }
+        let position = self.position()?;
+        let previous = if position > 0 {
+            position - 1
+        } else if matches!(self.loop_status, LoopStatus::Playlist) {
+            // Wrap around to the end of the playlist
+            self.order.len() - 1
+        } else {
+            return None;
+        };
+        self.playing = None;
+        self.ptr = Some(self.order[previous]);
+        self.upcoming = false;
+        self.current()
+    }
+
+    pub fn go_to(&mut self, id: usize) -> Option<Track> {
+        // Jump to a track in the playlist context, or pull it forward from the immediate queue
+        if let Some(idx) = self.ids.iter().position(|i| *i == id) {
+            self.playing = None;
+            self.ptr = Some(idx);
+            self.upcoming = false;
+            return self.current();
+        }
+        let idx = self.queue.iter().position(|(_, i)| *i == id)?;
+        self.playing = self.queue.remove(idx);
+        self.current()
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        // Remove a track from the immediate queue and playlist context, unless it is playing
+        self.queue.retain(|(_, i)| *i != id);
+        let current = self.context_ptr();
+        for idx in (0..self.ids.len()).rev() {
+            if self.ids[idx] != id || Some(idx) == current {
+                continue;
+            }
+            self.tracks.remove(idx);
+            self.ids.remove(idx);
+            // Keep the play order and pointer in line with the shifted tracks
+            let position = self.order.iter().position(|i| *i == idx);
+            self.order.retain(|i| *i != idx);
+            for i in &mut self.order {
+                if *i > idx {
+                    *i -= 1;
+                }
+            }
+            match self.ptr {
+                Some(ptr) if ptr > idx => self.ptr = Some(ptr - 1),
+                Some(ptr) if ptr == idx => {
+                    // Carry on from the track that now sits where the removed one was in the order
+                    let position = position.unwrap_or(0);
+                    self.upcoming = position < self.order.len();
+                    self.ptr = self
+                        .order
+                        .get(position)
+                        .or_else(|| self.order.last())
+                        .copied();
+                }
+                _ => (),
+            }
+        }
+    }
+
+    pub fn track_list(&self) -> Vec<(Track, usize)> {
+        // List tracks in the order they play in, with the immediate queue after the current track
+        let context: Vec<(Track, usize)> = self
+            .order
+            .iter()
+            .map(|i| (self.tracks[*i].clone(), self.ids[*i]))
+            .collect();
+        // A track yet to play hasn't been reached, so it comes after the current one
+        let split = self
+            .position()
+            .map_or(0, |p| if self.upcoming { p } else { p + 1 });
+        let mut result = context[..split].to_vec();
+        result.extend(self.playing.clone());
+        result.extend(self.queue.iter().cloned());
+        result.extend_from_slice(&context[split..]);
+        // Only keep the first appearance of each track, so that every entry is unique
+        let mut seen = HashSet::new();
+        result.retain(|(_, id)| seen.insert(*id));
+        result
+    }
+
     pub fn current_id(&self) -> Option<usize> {
         // Get the currently playing track ID
+        if let Some((_, id)) = &self.playing {
+            return Some(*id);
+        }
         Some(*self.ids.get(self.ptr?)?)
     }
 
     pub fn current(&self) -> Option<Track> {
         // Get the currently playing track
-        if !self.is_ready() {
-            return None;
+        if let Some((track, _)) = &self.playing {
+            return Some(track.clone());
         }
         Some(self.tracks.get(self.ptr?)?.clone())
     }
 
+    pub fn context_ptr(&self) -> Option<usize> {
+        // Get the position in the playlist context, if a track from it is playing
+        if self.playing.is_some() {
+            None
+        } else {
+            self.ptr
+        }
+    }
+
     pub fn is_ready(&self) -> bool {
-        self.ptr.is_some()
+        self.ptr.is_some() || self.playing.is_some()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        // Write down the play queue by track ID
+        Snapshot {
+            ids: self.ids.clone(),
+            ptr: self.ptr,
+            upcoming: self.upcoming,
+            name: self.name.clone(),
+            order: self.order.clone(),
+            loop_status: self.loop_status,
+            shuffle: self.shuffle,
+            queue: self.queue.iter().map(|(_, id)| *id).collect(),
+            playing: self.playing.as_ref().map(|(_, id)| *id),
+        }
     }
 
-    pub fn get_ptr(&self) -> usize {
-        self.ptr.unwrap()
+    pub fn restore(&mut self, snapshot: Snapshot, library: &HashMap<usize, Track>) {
+        // Put a play queue back together, leaving out tracks that have left the library
+        let kept: Vec<usize> = (0..snapshot.ids.len())
+            .filter(|i| library.contains_key(&snapshot.ids[*i]))
+            .collect();
+        let moved = |idx: usize| kept.iter().position(|i| *i == idx);
+        self.ids = kept.iter().map(|i| snapshot.ids[*i]).collect();
+        self.tracks = self.ids.iter().map(|id| library[id].clone()).collect();
+        self.ptr = snapshot.ptr.and_then(moved);
+        self.upcoming = snapshot.upcoming && self.ptr.is_some();
+        self.name = snapshot.name;
+        self.loop_status = snapshot.loop_status;
+        self.shuffle = snapshot.shuffle;
+        self.order = snapshot.order.into_iter().filter_map(moved).collect();
+        // Shuffle again if the play order no longer covers every track exactly once
+        let unique: HashSet<&usize> = self.order.iter().collect();
+        if self.order.len() != self.tracks.len() || unique.len() != self.order.len() {
+            self.reorder();
+        }
+        let find = |id: usize| Some((library.get(&id)?.clone(), id));
+        self.queue = snapshot.queue.into_iter().filter_map(find).collect();
+        self.playing = snapshot.playing.and_then(find);
     }
 
     pub fn move_down(&mut self, ptr: usize) {
         // Move a particular track downwards
-        self.tracks.swap(ptr, ptr + 1);
-        self.ids.swap(ptr, ptr + 1);
-        if ptr == self.get_ptr() {
-            self.ptr = Some(self.get_ptr() + 1);
+        if ptr + 1 < self.tracks.len() {
+            self.swap(ptr, ptr + 1);
         }
     }
 
     pub fn move_up(&mut self, ptr: usize) {
         // Move a particular track upwards
-        self.tracks.swap(ptr, ptr.saturating_sub(1));
-        self.ids.swap(ptr, ptr.saturating_sub(1));
-        if ptr == self.get_ptr() {
-            self.ptr = Some(self.get_ptr().saturating_sub(1));
+        if ptr > 0 && ptr < self.tracks.len() {
+            self.swap(ptr, ptr - 1);
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        // Swap two tracks in the playlist context, keeping the pointer and play order intact
+        self.tracks.swap(a, b);
+        self.ids.swap(a, b);
+        for i in &mut self.order {
+            if *i == a {
+                *i = b;
+            } else if *i == b {
+                *i = a;
+            }
+        }
+        if self.ptr == Some(a) {
+            self.ptr = Some(b);
+        } else if self.ptr == Some(b) {
+            self.ptr = Some(a);
         }
     }
 
     pub fn move_next(&mut self, ptr: usize) {
-        // Move a particular song in this queue to play next
-        let track = self.tracks.remove(ptr);
-        let id = self.ids.remove(ptr);
-        self.queue_next(track, id);
+        // Queue a particular song from the playlist context to play next
+        if let (Some(track), Some(id)) = (self.tracks.get(ptr), self.ids.get(ptr)) {
+            self.queue_next(track.clone(), *id);
+        }
     }
 
-    pub fn view(&mut self) -> String {
+    pub fn view(&self) -> String {
         let mut result = String::new();
-        for (c, track) in self.tracks.iter().enumerate() {
+        // Show the queued track that is currently playing
+        if let Some((track, _)) = &self.playing {
+            result.push_str(&format!("-> {}\n", track.format()));
+        }
+        // Show the immediate queue
+        if !self.queue.is_empty() {
+            result.push_str("Queue:\n");
+            for (track, _) in &self.queue {
+                result.push_str(&format!("   {}\n", track.format()));
+            }
+        }
+        // Show the playlist context
+        if !self.tracks.is_empty() {
             result.push_str(&format!(
-                "{}{}\n",
-                if c == self.get_ptr() { "-> " } else { "   " },
-                track.format()
+                "{}:\n",
+                self.name.as_deref().unwrap_or("Playlist")
             ));
+            for (c, track) in self.tracks.iter().enumerate() {
+                result.push_str(&format!(
+                    "{}{}\n",
+                    if Some(c) == self.context_ptr() {
+                        "-> "
+                    } else {
+                        "   "
+                    },
+                    track.format()
+                ));
+            }
         }
         result
     }
 
     pub fn is_empty(&self) -> bool {
-        self.tracks.is_empty()
+        self.tracks.is_empty() && self.queue.is_empty() && self.playing.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: usize) -> Track {
+        Track {
+            path: id.to_string(),
+            ..Track::default()
+        }
+    }
+
+    fn playlist(ids: &[usize], ptr: usize) -> PlayList {
+        let mut list = PlayList::default();
+        list.set(ptr, ids.iter().map(|i| track(*i)).collect(), ids.to_vec());
+        list
+    }
+
+    fn id(track: Option<Track>) -> Option<usize> {
+        track.map(|t| t.path.parse().unwrap())
+    }
+
+    fn ids(list: &PlayList) -> Vec<usize> {
+        list.track_list().iter().map(|(_, id)| *id).collect()
+    }
+
+    #[test]
+    fn queue_before_context() {
+        // Queued tracks play before the rest of the context, and are gone once played
+        let mut list = playlist(&[1, 2, 3], 0);
+        list.queue(track(10), 10);
+        list.queue(track(11), 11);
+        assert_eq!(ids(&list), [1, 10, 11, 2, 3]);
+        assert_eq!(id(list.next()), Some(10));
+        assert_eq!(ids(&list), [1, 10, 11, 2, 3]);
+        assert_eq!(list.context_ptr(), None);
+        assert_eq!(id(list.next()), Some(11));
+        assert_eq!(ids(&list), [1, 11, 2, 3]);
+        assert_eq!(id(list.next()), Some(2));
+        assert_eq!(ids(&list), [1, 2, 3]);
+        assert_eq!(list.context_ptr(), Some(1));
+        assert_eq!(id(list.next()), Some(3));
+        assert_eq!(id(list.next()), None);
+        assert_eq!(list.current_id(), Some(3));
+    }
+
+    #[test]
+    fn previous_through_context() {
+        // Going back returns to the interrupted track, then walks the context only
+        let mut list = playlist(&[1, 2, 3], 1);
+        list.queue(track(10), 10);
+        assert_eq!(id(list.next()), Some(10));
+        assert_eq!(id(list.previous()), Some(2));
+        assert_eq!(id(list.previous()), Some(1));
+        assert_eq!(id(list.previous()), None);
+        assert_eq!(list.current_id(), Some(1));
+        assert_eq!(ids(&list), [1, 2, 3]);
+        assert_eq!(id(list.next()), Some(2));
+    }
+
+    #[test]
+    fn queue_next_and_insert() {
+        let mut list = playlist(&[1, 2, 3], 0);
+        list.queue(track(10), 10);
+        list.queue_next(track(11), 11);
+        assert_eq!(ids(&list), [1, 11, 10, 2, 3]);
+        // Nothing comes between the current track and the queue
+        list.insert(track(12), 12, Some(1));
+        assert_eq!(ids(&list), [1, 12, 11, 10, 2, 3]);
+        list.insert(track(13), 13, Some(10));
+        assert_eq!(ids(&list), [1, 12, 11, 10, 13, 2, 3]);
+        list.insert(track(20), 20, Some(2));
+        assert_eq!(ids(&list), [1, 12, 11, 10, 13, 2, 20, 3]);
+        list.insert(track(21), 21, None);
+        assert_eq!(ids(&list), [21, 1, 12, 11, 10, 13, 2, 20, 3]);
+        assert_eq!(list.current_id(), Some(1));
+        let played: Vec<Option<usize>> = (0..8).map(|_| id(list.next())).collect();
+        let expected = [12, 11, 10, 13, 2, 20, 3].map(Some);
+        assert_eq!(played[..7], expected);
+        assert_eq!(played[7], None);
+    }
+
+    #[test]
+    fn removing() {
+        let mut list = playlist(&[1, 2, 3, 4], 1);
+        list.queue(track(10), 10);
+        list.queue(track(11), 11);
+        list.remove(10);
+        // The playing track stays put
+        list.remove(2);
+        assert_eq!(ids(&list), [1, 2, 11, 3, 4]);
+        assert_eq!(id(list.next()), Some(11));
+        // Once interrupted, it can go, and the track after it is next
+        list.remove(2);
+        assert_eq!(ids(&list), [1, 11, 3, 4]);
+        assert_eq!(id(list.peek_advance()), Some(3));
+        // Tracks put in before the one waiting to play come first
+        list.insert(track(30), 30, Some(1));
+        list.queue_next(track(12), 12);
+        assert_eq!(ids(&list), [1, 11, 12, 30, 3, 4]);
+        let played: Vec<Option<usize>> = (0..5).map(|_| id(list.next())).collect();
+        assert_eq!(played, [Some(12), Some(30), Some(3), Some(4), None]);
+    }
+
+    #[test]
+    fn looping() {
+        // Looping a track replays it when it finishes, though skipping still moves on
+        let mut list = playlist(&[1, 2], 0);
+        list.loop_status = LoopStatus::Track;
+        assert_eq!(id(list.peek_advance()), Some(1));
+        assert_eq!(id(list.advance()), Some(1));
+        assert_eq!(id(list.advance()), Some(1));
+        assert_eq!(id(list.next()), Some(2));
+        // Looping the playlist wraps around both ways
+        let mut list = playlist(&[1, 2, 3], 2);
+        list.loop_status = LoopStatus::Playlist;
+        assert_eq!(id(list.peek_advance()), Some(1));
+        assert_eq!(id(list.advance()), Some(1));
+        assert_eq!(id(list.previous()), Some(3));
+        // Without looping, playback stops at either end
+        list.loop_status = LoopStatus::None;
+        assert_eq!(id(list.advance()), None);
+        list.set(0, vec![track(1), track(2)], vec![1, 2]);
+        assert_eq!(id(list.previous()), None);
+    }
+
+    #[test]
+    fn shuffling() {
+        let all: Vec<usize> = (1..=20).collect();
+        let mut list = playlist(&all, 4);
+        list.set_shuffle(true);
+        // Every track plays once, starting from the current one
+        let order = ids(&list);
+        assert_eq!(order[0], 5);
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, all);
+        let played: Vec<usize> = (1..20).filter_map(|_| id(list.next())).collect();
+        assert_eq!(played, order[1..]);
+        assert_eq!(id(list.next()), None);
+        // Going back retraces the shuffled order
+        let back: Vec<usize> = (1..20).filter_map(|_| id(list.previous())).collect();
+        let mut expected = order[..19].to_vec();
+        expected.reverse();
+        assert_eq!(back, expected);
+        assert_eq!(id(list.previous()), None);
+    }
+
+    #[test]
+    fn unshuffling() {
+        // Turning shuffle off puts the original order back, staying on the current track
+        let all: Vec<usize> = (1..=20).collect();
+        let mut list = playlist(&all, 0);
+        list.set_shuffle(true);
+        let played: Vec<usize> = (0..5).filter_map(|_| id(list.next())).collect();
+        let current = *played.last().unwrap();
+        list.set_shuffle(false);
+        assert_eq!(ids(&list), all);
+        assert_eq!(list.current_id(), Some(current));
+        assert_eq!(list.context_ptr(), Some(current - 1));
+        assert_eq!(id(list.next()), all.get(current).copied());
     }
 }
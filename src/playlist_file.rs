@@ -0,0 +1,478 @@
+// playlist_file.rs - reading and writing playlists as M3U, PLS and XSPF files
+use crate::util::{
+    expand_path, percent_decode, percent_encode, uri_to_path, xml_elements, xml_escape, xml_text,
+};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Option<Self> {
+        // Work out the format of a playlist file from its extension
+        let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "m3u" | "m3u8" => Some(Self::M3u),
+            "pls" => Some(Self::Pls),
+            "xspf" => Some(Self::Xspf),
+            _ => None,
+        }
+    }
+}
+
+// A track listed in a playlist file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entry {
+    // Where the track is, as written in the file
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    // Duration in seconds
+    pub duration: Option<u64>,
+}
+
+pub fn read(path: &str) -> Result<Vec<Entry>, String> {
+    // Read the entries of a playlist file, in order
+    let format =
+        Format::from_path(path).ok_or_else(|| format!("Unknown playlist format: {}", path))?;
+    let bytes = std::fs::read(path).map_err(|_| format!("Failed to read playlist: {}", path))?;
+    // Older M3U files aren't always UTF-8, so read them as well as possible
+    let contents = String::from_utf8_lossy(&bytes);
+    let contents = contents.trim_start_matches('\u{feff}');
+    Ok(match format {
+        Format::M3u => read_m3u(contents),
+        Format::Pls => read_pls(contents),
+        Format::Xspf => read_xspf(contents),
+    })
+}
+
+pub fn write(path: &str, name: &str, entries: &[Entry]) -> Result<(), String> {
+    // Write entries out to a playlist file, in the format its extension asks for
+    let format =
+        Format::from_path(path).ok_or_else(|| format!("Unknown playlist format: {}", path))?;
+    let contents = match format {
+        Format::M3u => write_m3u(name, entries),
+        Format::Pls => write_pls(entries),
+        Format::Xspf => write_xspf(name, entries),
+    };
+    std::fs::write(path, contents).map_err(|_| format!("Failed to write playlist: {}", path))
+}
+
+pub fn resolve(playlist: &str, location: &str) -> Option<String> {
+    // Find the file a location points to, taking relative paths from the playlist's folder
+    let location = uri_to_path(location)?;
+    if location.starts_with('~') || Path::new(&location).is_absolute() {
+        return expand_path(&location);
+    }
+    let path = folder(playlist).join(location);
+    expand_path(path.to_str()?)
+}
+
+pub fn relative(playlist: &str, path: &str) -> String {
+    // Write paths relative to the playlist's folder when they are inside it
+    if let Some(base) = expand_path(folder(playlist).to_str().unwrap_or(".")) {
+        if let Some(rest) = path.strip_prefix(&format!("{}/", base.trim_end_matches('/'))) {
+            return rest.to_string();
+        }
+    }
+    path.to_string()
+}
+
+fn folder(playlist: &str) -> &Path {
+    // Find the folder a playlist file is in
+    match Path::new(playlist).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn split_info(info: &str) -> (Option<String>, Option<String>) {
+    // Split display text such as "Artist - Title" into the artist and title
+    match info.split_once(" - ") {
+        Some((artist, title)) => (
+            Some(artist.trim().to_string()),
+            Some(title.trim().to_string()),
+        ),
+        None if info.trim().is_empty() => (None, None),
+        None => (None, Some(info.trim().to_string())),
+    }
+}
+
+fn join_info(entry: &Entry) -> String {
+    // Put the artist and title together as display text
+    match (&entry.artist, &entry.title) {
+        (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+        (None, Some(title)) => title.clone(),
+        _ => String::new(),
+    }
+}
+
+fn read_m3u(contents: &str) -> Vec<Entry> {
+    // Read every path, along with the #EXTINF line before it
+    let mut result = vec![];
+    let mut info = None;
+    for line in contents.lines().map(str::trim) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            info = Some(extinf.to_string());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let mut entry = Entry {
+                location: line.to_string(),
+                ..Entry::default()
+            };
+            if let Some(info) = info.take() {
+                let (duration, text) = info.split_once(',').unwrap_or((&info, ""));
+                entry.duration = duration.trim().parse().ok();
+                let (artist, title) = split_info(text);
+                entry.artist = artist;
+                entry.title = title;
+            }
+            result.push(entry);
+        }
+    }
+    result
+}
+
+fn write_m3u(name: &str, entries: &[Entry]) -> String {
+    let mut result = format!("#EXTM3U\n#PLAYLIST:{}\n", name);
+    for entry in entries {
+        // Unknown durations are written as -1
+        let duration = entry
+            .duration
+            .map_or(-1, |d| i64::try_from(d).unwrap_or(-1));
+        result.push_str(&format!("#EXTINF:{},{}\n", duration, join_info(entry)));
+        result.push_str(&format!("{}\n", entry.location));
+    }
+    result
+}
+
+fn read_pls(contents: &str) -> Vec<Entry> {
+    // Gather up the numbered File, Title and Length keys
+    let mut entries: BTreeMap<usize, Entry> = BTreeMap::new();
+    for line in contents.lines().map(str::trim) {
+        if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim().to_lowercase();
+            let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+            let (name, number) = key.split_at(split);
+            if let Ok(number) = number.parse() {
+                let entry = entries.entry(number).or_default();
+                match name {
+                    "file" => entry.location = value.trim().to_string(),
+                    "title" => {
+                        let (artist, title) = split_info(value);
+                        entry.artist = artist;
+                        entry.title = title;
+                    }
+                    // Streams have a length of -1, which doesn't parse
+                    "length" => entry.duration = value.trim().parse().ok(),
+                    _ => (),
+                }
+            }
+        }
+    }
+    entries
+        .into_values()
+        .filter(|e| !e.location.is_empty())
+        .collect()
+}
+
+fn write_pls(entries: &[Entry]) -> String {
+    let mut result = "[playlist]\n".to_string();
+    for (i, entry) in entries.iter().enumerate() {
+        // Entries are numbered from 1
+        let n = i + 1;
+        result.push_str(&format!("File{}={}\n", n, entry.location));
+        let info = join_info(entry);
+        if !info.is_empty() {
+            result.push_str(&format!("Title{}={}\n", n, info));
+        }
+        if let Some(duration) = entry.duration {
+            result.push_str(&format!("Length{}={}\n", n, duration));
+        }
+    }
+    result.push_str(&format!("NumberOfEntries={}\nVersion=2\n", entries.len()));
+    result
+}
+
+fn read_xspf(contents: &str) -> Vec<Entry> {
+    // Read the location, title, creator and duration of every track element
+    let mut result = vec![];
+    for (_, track) in xml_elements(contents, "track") {
+        if let Some(location) = xml_text(track, "location") {
+            // Locations are URIs, so relative ones still need decoding
+            let location = if location.contains("://") {
+                location
+            } else {
+                percent_decode(&location)
+            };
+            result.push(Entry {
+                location,
+                title: xml_text(track, "title"),
+                artist: xml_text(track, "creator"),
+                // Durations are in milliseconds
+                duration: xml_text(track, "duration")
+                    .and_then(|d| d.parse::<u64>().ok())
+                    .map(|d| d / 1000),
+            });
+        }
+    }
+    result
+}
+
+fn write_xspf(name: &str, entries: &[Entry]) -> String {
+    let mut result = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string();
+    result.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    result.push_str(&format!(
+        "  <title>{}</title>\n  <trackList>\n",
+        xml_escape(name)
+    ));
+    for entry in entries {
+        // Absolute paths need to be turned into file URIs
+        let location = if entry.location.starts_with('/') {
+            format!("file://{}", percent_encode(&entry.location))
+        } else {
+            percent_encode(&entry.location)
+        };
+        result.push_str("    <track>\n");
+        result.push_str(&format!(
+            "      <location>{}</location>\n",
+            xml_escape(&location)
+        ));
+        if let Some(title) = &entry.title {
+            result.push_str(&format!("      <title>{}</title>\n", xml_escape(title)));
+        }
+        if let Some(artist) = &entry.artist {
+            result.push_str(&format!(
+                "      <creator>{}</creator>\n",
+                xml_escape(artist)
+            ));
+        }
+        if let Some(duration) = entry.duration {
+            result.push_str(&format!("      <duration>{}</duration>\n", duration * 1000));
+        }
+        result.push_str("    </track>\n");
+    }
+    result.push_str("  </trackList>\n</playlist>\n");
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(
+        location: &str,
+        artist: Option<&str>,
+        title: Option<&str>,
+        duration: Option<u64>,
+    ) -> Entry {
+        Entry {
+            location: location.to_string(),
+            title: title.map(str::to_string),
+            artist: artist.map(str::to_string),
+            duration,
+        }
+    }
+
+    #[test]
+    fn formats() {
+        let tests = [
+            ("mix.m3u", Some(Format::M3u)),
+            ("/music/Mix.M3U8", Some(Format::M3u)),
+            ("radio.pls", Some(Format::Pls)),
+            ("mix.xspf", Some(Format::Xspf)),
+            ("mix.txt", None),
+            ("mix", None),
+        ];
+        for (path, format) in tests {
+            assert_eq!(Format::from_path(path), format, "{}", path);
+        }
+    }
+
+    #[test]
+    fn m3u() {
+        let contents = "#EXTM3U\n\
+            #PLAYLIST:Mix\n\
+            #EXTINF:320,Daft Punk - One More Time\n\
+            Discovery/01 One More Time.flac\n\
+            \n\
+            # A comment\n\
+            #EXTINF:-1,Radio\n\
+            http://radio.example/stream\n\
+            /music/untagged.mp3\n\
+            #EXTINF:abc,\n\
+            \t other.ogg \n";
+        assert_eq!(
+            read_m3u(contents),
+            [
+                entry(
+                    "Discovery/01 One More Time.flac",
+                    Some("Daft Punk"),
+                    Some("One More Time"),
+                    Some(320)
+                ),
+                entry("http://radio.example/stream", None, Some("Radio"), None),
+                entry("/music/untagged.mp3", None, None, None),
+                entry("other.ogg", None, None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn pls() {
+        let contents = "[playlist]\n\
+            File2=http://radio.example/stream\n\
+            Title2=Radio\n\
+            Length2=-1\n\
+            file1 = /music/one.flac\n\
+            title1 = Daft Punk - One More Time\n\
+            LENGTH1=320\n\
+            Title3=Missing a file\n\
+            NumberOfEntries=3\n\
+            Version=2\n";
+        assert_eq!(
+            read_pls(contents),
+            [
+                entry(
+                    "/music/one.flac",
+                    Some("Daft Punk"),
+                    Some("One More Time"),
+                    Some(320)
+                ),
+                entry("http://radio.example/stream", None, Some("Radio"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn xspf() {
+        let contents = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <playlist version="1" xmlns="http://xspf.org/ns/0/">
+              <title>Mix</title>
+              <trackList>
+                <track>
+                  <location>file:///music/Daft%20Punk/One%20More%20Time.flac</location>
+                  <title>One More Time</title>
+                  <creator>Daft Punk</creator>
+                  <duration>320500</duration>
+                </track>
+                <track>
+                  <location>Rock%20%26%20Roll.mp3</location>
+                  <title><![CDATA[Rock & Roll]]></title>
+                  <creator>Led Zeppelin &amp; friends</creator>
+                </track>
+                <track><title>No location</title></track>
+                <trackList/>
+              </trackList>
+            </playlist>"#;
+        assert_eq!(
+            read_xspf(contents),
+            [
+                entry(
+                    "file:///music/Daft%20Punk/One%20More%20Time.flac",
+                    Some("Daft Punk"),
+                    Some("One More Time"),
+                    Some(320)
+                ),
+                entry(
+                    "Rock & Roll.mp3",
+                    Some("Led Zeppelin & friends"),
+                    Some("Rock & Roll"),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let entries = [
+            entry(
+                "/music/AC/DC & <friends>.flac",
+                Some("AC/DC"),
+                Some("Back in Black"),
+                Some(255),
+            ),
+            entry("relative/track.mp3", None, Some("Untitled"), None),
+            entry("http://radio.example/stream", None, None, None),
+        ];
+        for (name, read, write) in [
+            (
+                "m3u",
+                read_m3u as fn(&str) -> Vec<Entry>,
+                write_m3u("Mix", &entries),
+            ),
+            ("pls", read_pls, write_pls(&entries)),
+            ("xspf", read_xspf, write_xspf("Mix & more", &entries)),
+        ] {
+            let mut read = read(&write);
+            // XSPF holds absolute paths as file URIs
+            for entry in &mut read {
+                if let Some(path) = entry.location.strip_prefix("file://") {
+                    entry.location = percent_decode(path);
+                }
+            }
+            assert_eq!(read, entries, "{}", name);
+        }
+    }
+
+    #[test]
+    fn written() {
+        let entries = [
+            entry(
+                "/music/one.flac",
+                Some("Daft Punk"),
+                Some("One More Time"),
+                Some(320),
+            ),
+            entry("two.ogg", None, None, None),
+        ];
+        assert_eq!(
+            write_m3u("Mix", &entries),
+            "#EXTM3U\n#PLAYLIST:Mix\n#EXTINF:320,Daft Punk - One More Time\n/music/one.flac\n#EXTINF:-1,\ntwo.ogg\n"
+        );
+        assert_eq!(
+            write_pls(&entries),
+            "[playlist]\nFile1=/music/one.flac\nTitle1=Daft Punk - One More Time\nLength1=320\nFile2=two.ogg\nNumberOfEntries=2\nVersion=2\n"
+        );
+        assert!(write_xspf("A & B", &entries).contains("<title>A &amp; B</title>"));
+    }
+
+    #[test]
+    fn locations() {
+        // Locations are only resolved to files that exist
+        let base = std::env::temp_dir().join(format!("synchron-lists-{}", std::process::id()));
+        std::fs::create_dir_all(base.join("lists/a")).unwrap();
+        std::fs::write(base.join("lists/a/b c.flac"), "").unwrap();
+        std::fs::write(base.join("other.flac"), "").unwrap();
+        let base = std::fs::canonicalize(&base).unwrap();
+        let base = base.to_str().unwrap();
+        let playlist = format!("{}/lists/mix.m3u", base);
+        let track = format!("{}/lists/a/b c.flac", base);
+        let other = format!("{}/other.flac", base);
+        let tests = [
+            ("a/b c.flac", Some(&track)),
+            ("a/../a/b c.flac", Some(&track)),
+            ("../other.flac", Some(&other)),
+            (other.as_str(), Some(&other)),
+            (&format!("file://{}", percent_encode(&track)), Some(&track)),
+            ("a/missing.flac", None),
+            ("http://radio.example/stream", None),
+        ];
+        for (location, expected) in tests {
+            assert_eq!(
+                resolve(&playlist, location).as_ref(),
+                expected,
+                "{}",
+                location
+            );
+        }
+        assert_eq!(relative(&playlist, &track), "a/b c.flac");
+        assert_eq!(relative(&playlist, &other), other);
+        std::fs::remove_dir_all(base).ok();
+    }
+}
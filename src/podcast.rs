@@ -0,0 +1,564 @@
+// podcast.rs - following podcast feeds and keeping track of their episodes
+use crate::playlist_file;
+use crate::util::{is_file, xml_attribute, xml_elements, xml_text};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Seconds to wait for a server to connect, or to send more data, before giving up
+const TIMEOUT: u64 = 30;
+// Month names as they start in RSS dates
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Feed {
+    // Where the feed is read from, either an http(s) URL or a local file
+    pub url: String,
+    pub title: String,
+    // Episodes in the order the feed lists them, which is usually newest first
+    #[serde(default)]
+    pub episodes: Vec<Episode>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Episode {
+    // Tells episodes apart when the feed is fetched again
+    pub guid: String,
+    pub title: String,
+    // Location of the audio, as given in the feed
+    pub url: String,
+    // When the episode came out, in seconds since the unix epoch
+    pub published: Option<u64>,
+    // Duration in seconds
+    pub duration: Option<u64>,
+    pub played: bool,
+    // Where to pick up from, in seconds into the episode
+    pub position: u64,
+    // Path of the audio once it has been downloaded
+    pub download: Option<String>,
+}
+
+impl Feed {
+    pub fn fetch(url: &str) -> Result<Self, String> {
+        // Read a feed in from its URL or file
+        let mut contents = vec![];
+        open(url, url)?
+            .read_to_end(&mut contents)
+            .map_err(|e| format!("Failed to read feed: {}", e))?;
+        let contents = String::from_utf8_lossy(&contents);
+        let (title, episodes) =
+            parse(&contents).ok_or_else(|| format!("Not an RSS or Atom feed: {}", url))?;
+        Ok(Self {
+            url: url.to_string(),
+            title: title.unwrap_or_else(|| url.to_string()),
+            episodes,
+        })
+    }
+
+    pub fn merge(&mut self, fresh: Self) -> usize {
+        // Take on a newly fetched copy of the feed, keeping what is known about each episode
+        // Returns the number of episodes that weren't in the feed before
+        let mut new = 0;
+        let mut episodes = vec![];
+        for mut episode in fresh.episodes {
+            if let Some(old) = self.episodes.iter().find(|e| e.guid == episode.guid) {
+                episode.played = old.played;
+                episode.position = old.position;
+                episode.download.clone_from(&old.download);
+            } else {
+                new += 1;
+            }
+            episodes.push(episode);
+        }
+        // Downloaded episodes are kept after they drop out of the feed
+        for old in self.episodes.drain(..) {
+            if old.download.is_some() && !episodes.iter().any(|e| e.guid == old.guid) {
+                episodes.push(old);
+            }
+        }
+        self.title = fresh.title;
+        self.episodes = episodes;
+        new
+    }
+
+    pub fn unplayed(&self) -> usize {
+        self.episodes.iter().filter(|e| !e.played).count()
+    }
+}
+
+impl Episode {
+    pub fn location(&self, feed: &str) -> Option<String> {
+        // Find what to play, preferring the downloaded copy
+        if let Some(download) = self.download.as_ref().filter(|d| is_file(d)) {
+            return Some(format!("file://{}", download));
+        }
+        if is_remote(&self.url) {
+            Some(self.url.clone())
+        } else if is_remote(feed) {
+            // Feeds from the internet can't point at files on this computer
+            None
+        } else {
+            playlist_file::resolve(feed, &self.url).map(|path| format!("file://{}", path))
+        }
+    }
+}
+
+pub fn download(feed: &Feed, episode: &Episode, folder: &str) -> Result<String, String> {
+    // Download an episode into a folder named after its feed, returning the path of the file
+    let base = expanduser::expanduser(folder)
+        .map_err(|_| format!("Invalid download folder: {}", folder))?;
+    let folder = base.join(file_name(&feed.title));
+    std::fs::create_dir_all(&folder)
+        .map_err(|_| format!("Failed to create folder: {}", folder.display()))?;
+    // Name the file after the episode, keeping the extension the feed gives it
+    let extension = extension(&episode.url).unwrap_or_else(|| "mp3".to_string());
+    let path = folder.join(format!("{}.{}", file_name(&episode.title), extension));
+    // Download to the side first, so that unfinished downloads are never played
+    let partial = PathBuf::from(format!("{}.part", path.display()));
+    let mut reader = open(&feed.url, &episode.url)?;
+    let mut file =
+        File::create(&partial).map_err(|_| format!("Failed to write: {}", partial.display()))?;
+    if let Err(e) = std::io::copy(&mut reader, &mut file) {
+        std::fs::remove_file(&partial).ok();
+        return Err(format!("Failed to download {}: {}", episode.url, e));
+    }
+    std::fs::rename(&partial, &path).map_err(|_| format!("Failed to write: {}", path.display()))?;
+    Ok(path.display().to_string())
+}
+
+fn open(feed: &str, location: &str) -> Result<Box<dyn Read + Send>, String> {
+    // Open a location for reading, over http(s) or from a file relative to the feed
+    if is_remote(location) {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(TIMEOUT))
+            .timeout_read(Duration::from_secs(TIMEOUT))
+            .build();
+        let response = agent
+            .get(location)
+            .call()
+            .map_err(|e| format!("Failed to fetch {}: {}", location, e))?;
+        Ok(Box::new(response.into_reader()))
+    } else if is_remote(feed) {
+        // Feeds from the internet can't point at files on this computer
+        Err(format!("Not an http(s) location: {}", location))
+    } else {
+        let path = playlist_file::resolve(feed, location)
+            .ok_or_else(|| format!("Couldn't find: {}", location))?;
+        let file = File::open(&path).map_err(|_| format!("Failed to read: {}", path))?;
+        Ok(Box::new(file))
+    }
+}
+
+pub fn is_remote(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+pub fn resolve(location: &str) -> Option<String> {
+    // Turn a feed location into one that doesn't depend on the current folder
+    if is_remote(location) {
+        Some(location.to_string())
+    } else {
+        playlist_file::resolve(".", location)
+    }
+}
+
+fn file_name(text: &str) -> String {
+    // Make text safe to use as a file name
+    let name: String = text
+        .chars()
+        .map(|c| if "/\\:*?\"<>|".contains(c) { '_' } else { c })
+        .collect();
+    let name = name.trim().trim_start_matches('.');
+    if name.is_empty() {
+        "Untitled".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+fn extension(url: &str) -> Option<String> {
+    // Find the file extension at the end of a URL, ignoring any query
+    let path = url.split(['?', '#']).next()?;
+    let name = path.rsplit('/').next()?;
+    let (_, extension) = name.rsplit_once('.')?;
+    let valid = !extension.is_empty()
+        && extension.len() <= 4
+        && extension.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then(|| extension.to_lowercase())
+}
+
+fn parse(xml: &str) -> Option<(Option<String>, Vec<Episode>)> {
+    // Read the title and episodes of an RSS or Atom feed
+    let rss = xml.contains("<rss") || xml.contains("<channel");
+    if !rss && !xml.contains("<feed") {
+        return None;
+    }
+    let kind = if rss { "item" } else { "entry" };
+    // The feed's own title comes before any of its episodes
+    let head = xml.find(&format!("<{}", kind)).unwrap_or(xml.len());
+    let title = xml_text(&xml[..head], "title").filter(|t| !t.is_empty());
+    let mut episodes = vec![];
+    for (_, item) in xml_elements(xml, kind) {
+        let episode = if rss {
+            parse_item(item)
+        } else {
+            parse_entry(item)
+        };
+        // Anything without audio attached isn't an episode
+        if let Some(episode) = episode {
+            episodes.push(episode);
+        }
+    }
+    Some((title, episodes))
+}
+
+fn parse_item(item: &str) -> Option<Episode> {
+    // Read an episode from an RSS item
+    let url = xml_elements(item, "enclosure")
+        .into_iter()
+        .find_map(|(attributes, _)| xml_attribute(attributes, "url"))?;
+    Some(Episode {
+        guid: xml_text(item, "guid")
+            .filter(|g| !g.is_empty())
+            .unwrap_or_else(|| url.clone()),
+        title: xml_text(item, "title").unwrap_or_else(|| url.clone()),
+        published: xml_text(item, "pubDate").and_then(|d| parse_rfc2822(&d)),
+        duration: xml_text(item, "itunes:duration").and_then(|d| parse_duration(&d)),
+        url,
+        ..Episode::default()
+    })
+}
+
+fn parse_entry(entry: &str) -> Option<Episode> {
+    // Read an episode from an Atom entry, whose audio is linked as an enclosure
+    let url = xml_elements(entry, "link")
+        .into_iter()
+        .filter(|(attributes, _)| xml_attribute(attributes, "rel").as_deref() == Some("enclosure"))
+        .find_map(|(attributes, _)| xml_attribute(attributes, "href"))?;
+    let published = xml_text(entry, "published").or_else(|| xml_text(entry, "updated"));
+    Some(Episode {
+        guid: xml_text(entry, "id")
+            .filter(|g| !g.is_empty())
+            .unwrap_or_else(|| url.clone()),
+        title: xml_text(entry, "title").unwrap_or_else(|| url.clone()),
+        published: published.and_then(|d| parse_rfc3339(&d)),
+        duration: xml_text(entry, "itunes:duration").and_then(|d| parse_duration(&d)),
+        url,
+        ..Episode::default()
+    })
+}
+
+fn parse_duration(text: &str) -> Option<u64> {
+    // Read a duration written as seconds, minutes:seconds or hours:minutes:seconds
+    let mut result = 0;
+    for part in text.trim().split(':') {
+        let part = part.split('.').next()?;
+        result = result * 60 + part.trim().parse::<u64>().ok()?;
+    }
+    Some(result)
+}
+
+fn parse_rfc2822(text: &str) -> Option<u64> {
+    // Read a date such as "Tue, 10 Jun 2003 04:00:00 GMT", as used by RSS
+    let text = text.split_once(',').map_or(text, |(_, rest)| rest);
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let day = parts.first()?.parse().ok()?;
+    let month = parts.get(1)?.to_lowercase();
+    let month = MONTHS.iter().position(|m| month.starts_with(m))? + 1;
+    let year = parts.get(2)?.parse().ok()?;
+    let time = parse_time(parts.get(3).unwrap_or(&"00:00"))?;
+    // Named zones other than UTC are rare enough to be read as UTC
+    let offset = parts.get(4).and_then(|z| parse_offset(z)).unwrap_or(0);
+    timestamp(year, i64::try_from(month).ok()?, day, time, offset)
+}
+
+fn parse_rfc3339(text: &str) -> Option<u64> {
+    // Read a date such as "2003-12-13T18:30:02Z", as used by Atom
+    let text = text.trim();
+    let (date, rest) = text.split_once(['T', 't', ' ']).unwrap_or((text, ""));
+    let mut date = date.splitn(3, '-');
+    let year = date.next()?.parse().ok()?;
+    let month = date.next()?.parse().ok()?;
+    let day = date.next()?.parse().ok()?;
+    let split = rest.find(['Z', 'z', '+', '-']).unwrap_or(rest.len());
+    let (time, zone) = rest.split_at(split);
+    let time = if time.is_empty() {
+        0
+    } else {
+        parse_time(time)?
+    };
+    let offset = parse_offset(zone).unwrap_or(0);
+    timestamp(year, month, day, time, offset)
+}
+
+fn parse_time(text: &str) -> Option<i64> {
+    // Read a time of day as seconds since midnight, ignoring fractions of a second
+    let mut parts = text.split(':');
+    let hours: i64 = parts.next()?.parse().ok()?;
+    let minutes: i64 = parts.next()?.parse().ok()?;
+    let seconds: i64 = match parts.next() {
+        Some(seconds) => seconds.split('.').next()?.parse().ok()?,
+        None => 0,
+    };
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+fn parse_offset(zone: &str) -> Option<i64> {
+    // Read a time zone offset such as +0100 or -05:00 as seconds ahead of UTC
+    let sign = match zone.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits: String = zone[1..].chars().filter(char::is_ascii_digit).collect();
+    if digits.len() != 4 {
+        return None;
+    }
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+fn timestamp(year: i64, month: i64, day: i64, time: i64, offset: i64) -> Option<u64> {
+    // Work out the seconds since the unix epoch of a date and time in some time zone
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    u64::try_from(days * 86400 + time - offset).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+  <channel>
+    <title><![CDATA[Tom & Jerry's <Show>]]></title>
+    <itunes:title>Not the title</itunes:title>
+    <item>
+      <title>Episode 2: Q&amp;A &#8211; &#x201C;live&#x201D;</title>
+      <guid isPermaLink="false">ep-2</guid>
+      <pubDate>Tue, 10 Jun 2003 04:00:00 GMT</pubDate>
+      <itunes:duration>1:02:03</itunes:duration>
+      <enclosure length="123" type="audio/mpeg" url="https://example.com/2.mp3?a=1&amp;b=2"/>
+    </item>
+    <item>
+      <title><![CDATA[Episode 1 <unplugged>]]></title>
+      <pubDate>Tue, 10 Jun 2003 04:00:00 +0100</pubDate>
+      <itunes:duration>95</itunes:duration>
+      <enclosure url='https://example.com/1.mp3' type='audio/mpeg'></enclosure>
+    </item>
+    <item>
+      <title>Trailer</title>
+      <enclosure type="audio/mpeg" url="trailer.mp3" />
+    </item>
+    <item>
+      <title>Show notes without audio</title>
+    </item>
+  </channel>
+</rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title type="text">Atom &lt;Cast&gt;</title>
+  <entry>
+    <title>First</title>
+    <id>urn:uuid:1</id>
+    <published>2003-12-13T18:30:02.25-05:00</published>
+    <updated>2004-01-01T00:00:00Z</updated>
+    <link rel="alternate" href="https://example.com/first.html"/>
+    <link rel="enclosure" type="audio/ogg" href="https://example.com/first.ogg"/>
+  </entry>
+  <entry>
+    <title>Second</title>
+    <updated>2003-12-13T18:30:02Z</updated>
+    <link href="https://example.com/second.html"/>
+    <link href="https://example.com/second.ogg" rel="enclosure"/>
+  </entry>
+  <entry>
+    <title>Without audio</title>
+    <link rel="alternate" href="https://example.com/third.html"/>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn rss() {
+        let (title, episodes) = parse(RSS).unwrap();
+        assert_eq!(title.as_deref(), Some("Tom & Jerry's <Show>"));
+        let summary: Vec<_> = episodes
+            .iter()
+            .map(|e| {
+                (
+                    e.guid.as_str(),
+                    e.title.as_str(),
+                    e.url.as_str(),
+                    e.published,
+                    e.duration,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "ep-2",
+                    "Episode 2: Q&A \u{2013} \u{201c}live\u{201d}",
+                    "https://example.com/2.mp3?a=1&b=2",
+                    Some(1_055_217_600),
+                    Some(3723)
+                ),
+                // Episodes without a guid are told apart by their audio
+                (
+                    "https://example.com/1.mp3",
+                    "Episode 1 <unplugged>",
+                    "https://example.com/1.mp3",
+                    Some(1_055_214_000),
+                    Some(95)
+                ),
+                ("trailer.mp3", "Trailer", "trailer.mp3", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn atom() {
+        let (title, episodes) = parse(ATOM).unwrap();
+        assert_eq!(title.as_deref(), Some("Atom <Cast>"));
+        let summary: Vec<_> = episodes
+            .iter()
+            .map(|e| {
+                (
+                    e.guid.as_str(),
+                    e.title.as_str(),
+                    e.url.as_str(),
+                    e.published,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "urn:uuid:1",
+                    "First",
+                    "https://example.com/first.ogg",
+                    Some(1_071_358_202)
+                ),
+                (
+                    "https://example.com/second.ogg",
+                    "Second",
+                    "https://example.com/second.ogg",
+                    Some(1_071_340_202)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn not_feeds() {
+        assert!(parse("<html><body>Not a feed</body></html>").is_none());
+        assert!(parse("").is_none());
+        let (title, episodes) = parse("<rss><channel></channel></rss>").unwrap();
+        assert_eq!((title, episodes.len()), (None, 0));
+    }
+
+    #[test]
+    fn dates() {
+        let tests = [
+            ("Tue, 10 Jun 2003 04:00:00 GMT", Some(1_055_217_600)),
+            ("10 Jun 2003 04:00:00 GMT", Some(1_055_217_600)),
+            ("Tue, 10 June 2003 04:00 +0100", Some(1_055_214_000)),
+            ("Tue, 10 Jun 2003 04:00:00 EST", Some(1_055_217_600)),
+            ("Thu, 29 Feb 2024 12:00:00 -0000", Some(1_709_208_000)),
+            ("Tue, 10 Jun 2003", Some(1_055_203_200)),
+            ("Tue, 10 Foo 2003 04:00:00 GMT", None),
+            ("Tue, 10 Jun 2003 4am", None),
+            ("", None),
+        ];
+        for (text, expected) in tests {
+            assert_eq!(parse_rfc2822(text), expected, "{}", text);
+        }
+        let tests = [
+            ("2003-12-13T18:30:02Z", Some(1_071_340_202)),
+            ("2003-12-13t18:30:02z", Some(1_071_340_202)),
+            ("2003-12-13 18:30:02", Some(1_071_340_202)),
+            ("2003-12-13T18:30:02.25-05:00", Some(1_071_358_202)),
+            ("2003-12-13T18:30:02+0000", Some(1_071_340_202)),
+            ("2003-12-13", Some(1_071_273_600)),
+            ("1970-01-01T00:00:00Z", Some(0)),
+            ("1969-12-31T23:59:59Z", None),
+            ("13/12/2003", None),
+            ("", None),
+        ];
+        for (text, expected) in tests {
+            assert_eq!(parse_rfc3339(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        let tests = [
+            ("95", Some(95)),
+            ("1:35", Some(95)),
+            ("1:02:03", Some(3723)),
+            (" 12:00.5 ", Some(720)),
+            ("an hour", None),
+            ("", None),
+        ];
+        for (text, expected) in tests {
+            assert_eq!(parse_duration(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn locations() {
+        // Only feeds on disk can point at files, relative to the feed
+        let base = std::env::temp_dir().join(format!("synchron-feed-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        let base = std::fs::canonicalize(&base).unwrap();
+        let feed = base.join("feed.xml").to_str().unwrap().to_string();
+        let audio = base.join("1.mp3").to_str().unwrap().to_string();
+        std::fs::write(&feed, RSS).unwrap();
+        std::fs::write(&audio, "").unwrap();
+        let episode = |url: &str| Episode {
+            url: url.to_string(),
+            ..Episode::default()
+        };
+        let remote = "https://example.com/feed.xml";
+        let tests = [
+            (feed.as_str(), "1.mp3", Some(format!("file://{}", audio))),
+            (
+                feed.as_str(),
+                audio.as_str(),
+                Some(format!("file://{}", audio)),
+            ),
+            (feed.as_str(), "missing.mp3", None),
+            (
+                remote,
+                "https://example.com/1.mp3",
+                Some("https://example.com/1.mp3".to_string()),
+            ),
+            (remote, "1.mp3", None),
+            (remote, audio.as_str(), None),
+            (remote, "file:///etc/passwd", None),
+        ];
+        for (feed, url, expected) in tests {
+            assert_eq!(episode(url).location(feed), expected, "{} {}", feed, url);
+        }
+        assert!(open(&feed, "1.mp3").is_ok());
+        for location in [audio.as_str(), "/etc/passwd", "file:///etc/passwd"] {
+            let error = open(remote, location).err().unwrap();
+            assert_eq!(error, format!("Not an http(s) location: {}", location));
+        }
+        std::fs::remove_dir_all(base).ok();
+    }
+}
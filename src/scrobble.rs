@@ -0,0 +1,742 @@
+// scrobble.rs - submitting listens to Last.fm and ListenBrainz, keeping them on disk until accepted
+use crate::config::{LastFmConfig, ListenBrainzConfig, Scrobbling};
+use crate::util::{data_path, write_atomic};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::sync::{
+    mpsc::{self, RecvTimeoutError, Sender},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+// Tracks this short, in seconds, are never scrobbled
+const MIN_DURATION: u64 = 30;
+// Listening for this long, in seconds, scrobbles a track even if it's less than half of it
+const MAX_THRESHOLD: u64 = 240;
+// Seconds to wait before trying again when a service can't be reached
+const RETRY: u64 = 300;
+// Seconds to wait for a service to respond
+const TIMEOUT: u64 = 30;
+// Name of the file scrobbles are kept in until they are accepted
+const CACHE: &str = "scrobbles.ron";
+// Most scrobbles to send in one request
+const BATCH: usize = 50;
+// Times to try taking the lock on the cache, 10 milliseconds apart, before taking it over
+const LOCK_TRIES: usize = 200;
+
+// A listen that is waiting to be submitted
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Scrobble {
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    // Duration of the track in seconds
+    pub duration: Option<u64>,
+    // When the track started playing, in seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+// Reasons a batch of scrobbles wasn't accepted
+#[derive(Debug, Clone)]
+pub enum SubmitError {
+    // The service couldn't be reached or can't take scrobbles now, so try again later
+    Offline(String),
+    // The service refused the scrobbles, so sending them again won't help
+    Rejected(String),
+}
+
+// A service that scrobbles can be submitted to
+pub trait Backend {
+    // Name the scrobbles waiting for this service are kept under
+    fn name(&self) -> &'static str;
+    // Submit a batch of scrobbles, oldest first
+    fn submit(&self, scrobbles: &[Scrobble]) -> Result<(), SubmitError>;
+}
+
+pub fn eligible(listened: u64, duration: Option<u64>) -> bool {
+    // Apply the standard rules to how far playback has moved on through a track: tracks over
+    // 30 seconds long are scrobbled once half of them has played, or 4 minutes of them
+    match duration {
+        Some(duration) => duration > MIN_DURATION && listened >= (duration / 2).min(MAX_THRESHOLD),
+        None => listened >= MAX_THRESHOLD,
+    }
+}
+
+pub struct LastFm {
+    config: LastFmConfig,
+}
+
+impl LastFm {
+    pub fn new(config: LastFmConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Backend for LastFm {
+    fn name(&self) -> &'static str {
+        "lastfm"
+    }
+
+    fn submit(&self, scrobbles: &[Scrobble]) -> Result<(), SubmitError> {
+        // Build up a track.scrobble call, numbering each scrobble in the batch
+        let mut params = vec![
+            ("method".to_string(), "track.scrobble".to_string()),
+            ("api_key".to_string(), self.config.api_key.clone()),
+            ("sk".to_string(), self.config.session_key.clone()),
+        ];
+        for (i, scrobble) in scrobbles.iter().enumerate() {
+            params.push((format!("artist[{}]", i), scrobble.artist.clone()));
+            params.push((format!("track[{}]", i), scrobble.title.clone()));
+            params.push((format!("timestamp[{}]", i), scrobble.timestamp.to_string()));
+            if let Some(album) = &scrobble.album {
+                params.push((format!("album[{}]", i), album.clone()));
+            }
+            if let Some(duration) = scrobble.duration {
+                params.push((format!("duration[{}]", i), duration.to_string()));
+            }
+        }
+        // Sign the call with the parameters in order, followed by the secret
+        params.sort();
+        let mut signature = String::new();
+        for (key, value) in &params {
+            signature.push_str(key);
+            signature.push_str(value);
+        }
+        signature.push_str(&self.config.secret);
+        params.push((
+            "api_sig".to_string(),
+            format!("{:x}", md5::compute(signature)),
+        ));
+        params.push(("format".to_string(), "json".to_string()));
+        let form: Vec<(&str, &str)> = params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let response = ureq::post(&self.config.endpoint)
+            .timeout(Duration::from_secs(TIMEOUT))
+            .send_form(&form);
+        // Last.fm explains failures with an error code in the body
+        let (status, body) = read_response(response)?;
+        let reply: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+        if let Some(code) = reply.get("error").and_then(serde_json::Value::as_i64) {
+            let message = reply["message"].as_str().unwrap_or_default();
+            let message = format!("Last.fm error {}: {}", code, message);
+            // Codes for being unavailable, rate limited, or signed out are worth retrying
+            return Err(if matches!(code, 9 | 11 | 16 | 29) {
+                SubmitError::Offline(message)
+            } else {
+                SubmitError::Rejected(message)
+            });
+        }
+        check_status(status, &body)
+    }
+}
+
+pub struct ListenBrainz {
+    config: ListenBrainzConfig,
+}
+
+impl ListenBrainz {
+    pub fn new(config: ListenBrainzConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Backend for ListenBrainz {
+    fn name(&self) -> &'static str {
+        "listenbrainz"
+    }
+
+    fn submit(&self, scrobbles: &[Scrobble]) -> Result<(), SubmitError> {
+        // Turn each scrobble into a listen
+        let payload: Vec<serde_json::Value> = scrobbles
+            .iter()
+            .map(|scrobble| {
+                let mut metadata = json!({
+                    "artist_name": scrobble.artist,
+                    "track_name": scrobble.title,
+                    "additional_info": { "media_player": "synchron" },
+                });
+                if let Some(album) = &scrobble.album {
+                    metadata["release_name"] = json!(album);
+                }
+                if let Some(duration) = scrobble.duration {
+                    metadata["additional_info"]["duration"] = json!(duration);
+                }
+                json!({ "listened_at": scrobble.timestamp, "track_metadata": metadata })
+            })
+            .collect();
+        // A single listen is submitted as it happens, while a backlog is imported
+        let kind = if payload.len() == 1 {
+            "single"
+        } else {
+            "import"
+        };
+        let body = json!({ "listen_type": kind, "payload": payload });
+        let url = format!(
+            "{}/1/submit-listens",
+            self.config.endpoint.trim_end_matches('/')
+        );
+        let response = ureq::post(&url)
+            .timeout(Duration::from_secs(TIMEOUT))
+            .set("Authorization", &format!("Token {}", self.config.token))
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string());
+        let (status, body) = read_response(response)?;
+        check_status(status, &body)
+    }
+}
+
+fn read_response(
+    response: Result<ureq::Response, ureq::Error>,
+) -> Result<(u16, String), SubmitError> {
+    // Get the status and body of a response, even when the status is an error
+    match response {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => {
+            let status = response.status();
+            Ok((status, response.into_string().unwrap_or_default()))
+        }
+        Err(e) => Err(SubmitError::Offline(e.to_string())),
+    }
+}
+
+fn check_status(status: u16, body: &str) -> Result<(), SubmitError> {
+    // Retry when the service is down, signed out or rate limited, but not when the request is bad
+    let message = format!("HTTP {}: {}", status, body.trim());
+    match status {
+        200..=299 => Ok(()),
+        401 | 403 | 408 | 429 => Err(SubmitError::Offline(message)),
+        400..=499 => Err(SubmitError::Rejected(message)),
+        _ => Err(SubmitError::Offline(message)),
+    }
+}
+
+// Scrobbles that haven't been accepted yet, kept on disk so they survive going offline
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Cache {
+    // Scrobbles waiting for each backend, by its name, oldest first
+    pending: HashMap<String, Vec<Scrobble>>,
+    // What went wrong the last time a submission failed
+    #[serde(skip)]
+    last_error: Option<String>,
+    // Where the cache is kept, if it can be kept at all
+    #[serde(skip)]
+    path: Option<String>,
+}
+
+impl Cache {
+    fn open(path: Option<String>) -> Self {
+        // Read the cache from disk, starting afresh if there isn't one
+        let mut cache: Self = path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|cache| ron::from_str(&cache).ok())
+            .unwrap_or_default();
+        cache.path = path;
+        cache
+    }
+
+    fn update<F: FnOnce(&mut Self)>(&mut self, change: F) {
+        // Make a change on top of what's on disk, as another instance may have changed it since
+        if let Some(path) = self.path.clone() {
+            let lock = Lock::take(&path);
+            let mut latest = Self::open(Some(path));
+            change(&mut latest);
+            latest.write();
+            std::mem::drop(lock);
+            self.pending = latest.pending;
+        } else {
+            change(self);
+        }
+    }
+
+    fn write(&self) {
+        // Write the cache to disk, if it is kept on disk
+        if let (Some(path), Ok(cache)) = (&self.path, ron::ser::to_string(self)) {
+            if write_atomic(path, &cache).is_err() {
+                println!("ERROR: Failed to write scrobbles to disk");
+            }
+        }
+    }
+}
+
+// Keeps other instances away from the cache on disk until it is dropped
+struct Lock {
+    path: String,
+}
+
+impl Lock {
+    fn take(path: &str) -> Self {
+        // Wait for a lock file to be free, taking it over if it was left behind by a crash
+        let path = format!("{}.lock", path);
+        for _ in 0..LOCK_TRIES {
+            let file = OpenOptions::new().write(true).create_new(true).open(&path);
+            if file.is_ok() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Self { path }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+// Queues scrobbles and submits them in the background
+pub struct Scrobbler {
+    cache: Arc<Mutex<Cache>>,
+    // Names of the backends that are set up
+    backends: Vec<&'static str>,
+    // Wakes up the background thread when there is something new to submit
+    wake: Option<Sender<()>>,
+}
+
+impl Scrobbler {
+    pub fn new(config: &Scrobbling) -> Self {
+        // Set up the services in the config
+        let mut backends: Vec<Box<dyn Backend + Send>> = vec![];
+        if let Some(lastfm) = &config.lastfm {
+            backends.push(Box::new(LastFm::new(lastfm.clone())));
+        }
+        if let Some(listenbrainz) = &config.listenbrainz {
+            backends.push(Box::new(ListenBrainz::new(listenbrainz.clone())));
+        }
+        Self::spawn(backends, data_path(CACHE))
+    }
+
+    pub fn spawn(backends: Vec<Box<dyn Backend + Send>>, path: Option<String>) -> Self {
+        // Start submitting in the background, including anything left from last time
+        let cache = Arc::new(Mutex::new(Cache::open(path)));
+        let names = backends.iter().map(|b| b.name()).collect();
+        let mut wake = None;
+        if !backends.is_empty() {
+            let (tx, rx) = mpsc::channel();
+            let shared = cache.clone();
+            std::thread::spawn(move || loop {
+                submit_pending(&backends, &shared);
+                // Wait for a new scrobble, or try again after a while
+                if rx.recv_timeout(Duration::from_secs(RETRY))
+                    == Err(RecvTimeoutError::Disconnected)
+                {
+                    break;
+                }
+            });
+            wake = Some(tx);
+        }
+        Self {
+            cache,
+            backends: names,
+            wake,
+        }
+    }
+
+    pub fn queue(&self, scrobble: &Scrobble) {
+        // Keep the scrobble on disk until every backend has accepted it
+        if self.backends.is_empty() {
+            return;
+        }
+        self.cache.lock().unwrap().update(|cache| {
+            for name in &self.backends {
+                let pending = cache.pending.entry((*name).to_string()).or_default();
+                pending.push(scrobble.clone());
+            }
+        });
+        if let Some(wake) = &self.wake {
+            wake.send(()).ok();
+        }
+    }
+
+    pub fn status(&self) -> String {
+        // Describe how many scrobbles are waiting for each backend
+        if self.backends.is_empty() {
+            return "Scrobbling isn't set up\n".to_string();
+        }
+        let cache = self.cache.lock().unwrap();
+        let mut result = String::new();
+        for name in &self.backends {
+            let pending = cache.pending.get(*name).map_or(0, Vec::len);
+            result.push_str(&format!("{}: {} waiting\n", name, pending));
+        }
+        if let Some(error) = &cache.last_error {
+            result.push_str(&format!("Last error: {}\n", error));
+        }
+        result
+    }
+}
+
+fn submit_pending(backends: &[Box<dyn Backend + Send>], cache: &Mutex<Cache>) {
+    // Submit everything waiting in the cache, batch by batch
+    for backend in backends {
+        loop {
+            // Don't hold on to the cache while waiting for the service
+            let batch: Vec<Scrobble> = match cache.lock().unwrap().pending.get(backend.name()) {
+                Some(pending) => pending.iter().take(BATCH).cloned().collect(),
+                None => vec![],
+            };
+            if batch.is_empty() {
+                break;
+            }
+            let result = backend.submit(&batch);
+            let mut cache = cache.lock().unwrap();
+            match result {
+                Ok(()) => (),
+                // Refused scrobbles are dropped so they don't hold up the rest
+                Err(SubmitError::Rejected(message)) => {
+                    cache.last_error = Some(format!("{}: {}", backend.name(), message));
+                }
+                // Leave everything in the cache until the service is back
+                Err(SubmitError::Offline(message)) => {
+                    cache.last_error = Some(format!("{}: {}", backend.name(), message));
+                    break;
+                }
+            }
+            // Another instance may have submitted some of the batch already, so look for each one
+            cache.update(|cache| {
+                if let Some(pending) = cache.pending.get_mut(backend.name()) {
+                    pending.retain(|scrobble| !batch.contains(scrobble));
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::Receiver;
+    use std::time::Instant;
+
+    fn serve(responses: Vec<(u16, &'static str)>) -> (String, Receiver<(String, String)>) {
+        // Answer requests with each response in turn, passing on their headers and bodies
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (status, reply) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                tx.send((head, String::from_utf8(body).unwrap())).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Reply\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    reply.len(),
+                    reply
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn decode(form: &str) -> HashMap<String, String> {
+        // Read the fields of a url encoded form
+        let unescape = |text: &str| {
+            let bytes = text.replace('+', " ").into_bytes();
+            let mut result = vec![];
+            let mut i = 0;
+            while i < bytes.len() {
+                if bytes[i] == b'%' {
+                    let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                    result.push(u8::from_str_radix(hex, 16).unwrap());
+                    i += 3;
+                } else {
+                    result.push(bytes[i]);
+                    i += 1;
+                }
+            }
+            String::from_utf8(result).unwrap()
+        };
+        form.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (unescape(k), unescape(v)))
+            .collect()
+    }
+
+    fn scrobble(title: &str, timestamp: u64) -> Scrobble {
+        Scrobble {
+            artist: "Daft Punk".to_string(),
+            title: title.to_string(),
+            album: Some("Discovery".to_string()),
+            duration: Some(320),
+            timestamp,
+        }
+    }
+
+    fn lastfm(endpoint: &str) -> LastFm {
+        LastFm::new(LastFmConfig {
+            api_key: "key".to_string(),
+            secret: "secret".to_string(),
+            session_key: "session".to_string(),
+            endpoint: endpoint.to_string(),
+        })
+    }
+
+    fn listenbrainz(endpoint: &str) -> ListenBrainz {
+        ListenBrainz::new(ListenBrainzConfig {
+            token: "token".to_string(),
+            endpoint: endpoint.to_string(),
+        })
+    }
+
+    fn temporary(name: &str) -> String {
+        // A cache path of the test's own, with nothing left from earlier runs
+        let path =
+            std::env::temp_dir().join(format!("synchron-{}-{}.ron", name, std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        // Give the background thread a few seconds to catch up
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn eligibility() {
+        let tests = [
+            (0, Some(320), false),
+            (159, Some(320), false),
+            (160, Some(320), true),
+            (239, Some(600), false),
+            (240, Some(600), true),
+            (30, Some(30), false),
+            (14, Some(31), false),
+            (15, Some(31), true),
+            (239, None, false),
+            (240, None, true),
+        ];
+        for (listened, duration, expected) in tests {
+            assert_eq!(
+                eligible(listened, duration),
+                expected,
+                "{} of {:?}",
+                listened,
+                duration
+            );
+        }
+    }
+
+    #[test]
+    fn lastfm_request() {
+        let (url, requests) = serve(vec![(200, "{\"scrobbles\": {}}")]);
+        let batch = [scrobble("One More Time", 100), scrobble("Aerodynamic", 420)];
+        assert!(lastfm(&url).submit(&batch).is_ok());
+        let (_, body) = requests.recv().unwrap();
+        let form = decode(&body);
+        assert_eq!(form["method"], "track.scrobble");
+        assert_eq!(form["sk"], "session");
+        assert_eq!(form["artist[0]"], "Daft Punk");
+        assert_eq!(form["track[0]"], "One More Time");
+        assert_eq!(form["track[1]"], "Aerodynamic");
+        assert_eq!(form["timestamp[1]"], "420");
+        assert_eq!(form["album[1]"], "Discovery");
+        assert_eq!(form["duration[0]"], "320");
+        // The signature covers every other parameter, in order, then the secret
+        let mut params: Vec<(&String, &String)> = form
+            .iter()
+            .filter(|(k, _)| *k != "api_sig" && *k != "format")
+            .collect();
+        params.sort();
+        let mut signature = String::new();
+        for (key, value) in params {
+            signature.push_str(key);
+            signature.push_str(value);
+        }
+        signature.push_str("secret");
+        assert_eq!(form["api_sig"], format!("{:x}", md5::compute(signature)));
+    }
+
+    #[test]
+    fn lastfm_errors() {
+        let (url, _requests) = serve(vec![
+            (200, "{\"error\": 11, \"message\": \"Service Offline\"}"),
+            (400, "{\"error\": 6, \"message\": \"Invalid parameters\"}"),
+            (503, ""),
+        ]);
+        let backend = lastfm(&url);
+        let batch = [scrobble("One More Time", 100)];
+        assert!(matches!(backend.submit(&batch), Err(SubmitError::Offline(m)) if m.contains("11")));
+        assert!(matches!(backend.submit(&batch), Err(SubmitError::Rejected(m)) if m.contains('6')));
+        assert!(matches!(
+            backend.submit(&batch),
+            Err(SubmitError::Offline(_))
+        ));
+    }
+
+    #[test]
+    fn listenbrainz_request() {
+        let (url, requests) = serve(vec![(200, "{\"status\": \"ok\"}"); 2]);
+        let backend = listenbrainz(&format!("{}/", url));
+        assert!(backend.submit(&[scrobble("One More Time", 100)]).is_ok());
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /1/submit-listens "));
+        assert!(head.contains("Authorization: Token token"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["listen_type"], "single");
+        let listen = &body["payload"][0];
+        assert_eq!(listen["listened_at"], 100);
+        assert_eq!(listen["track_metadata"]["artist_name"], "Daft Punk");
+        assert_eq!(listen["track_metadata"]["track_name"], "One More Time");
+        assert_eq!(listen["track_metadata"]["release_name"], "Discovery");
+        assert_eq!(listen["track_metadata"]["additional_info"]["duration"], 320);
+        // A backlog is imported in one go
+        let batch = [scrobble("One More Time", 100), scrobble("Aerodynamic", 420)];
+        assert!(backend.submit(&batch).is_ok());
+        let (_, body) = requests.recv().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["listen_type"], "import");
+        assert_eq!(
+            body["payload"][1]["track_metadata"]["track_name"],
+            "Aerodynamic"
+        );
+    }
+
+    #[test]
+    fn listenbrainz_errors() {
+        let (url, _requests) = serve(vec![(401, "Invalid token"), (400, "Bad listen"), (500, "")]);
+        let backend = listenbrainz(&url);
+        let batch = [scrobble("One More Time", 100)];
+        assert!(
+            matches!(backend.submit(&batch), Err(SubmitError::Offline(m)) if m.contains("401"))
+        );
+        assert!(
+            matches!(backend.submit(&batch), Err(SubmitError::Rejected(m)) if m.contains("400"))
+        );
+        assert!(matches!(
+            backend.submit(&batch),
+            Err(SubmitError::Offline(_))
+        ));
+    }
+
+    #[test]
+    fn unreachable() {
+        // Nothing is listening once the listener is dropped
+        let url = format!(
+            "http://{}",
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        );
+        let batch = [scrobble("One More Time", 100)];
+        assert!(matches!(
+            listenbrainz(&url).submit(&batch),
+            Err(SubmitError::Offline(_))
+        ));
+    }
+
+    #[test]
+    fn retrying() {
+        let (url, requests) = serve(vec![
+            (503, "Down for maintenance"),
+            (200, "{\"status\": \"ok\"}"),
+        ]);
+        let path = temporary("retrying");
+        // A scrobble left from last time is submitted straight away, but the service is down
+        let mut cache = Cache::open(Some(path.clone()));
+        cache.update(|cache| {
+            let pending = cache.pending.entry("listenbrainz".to_string()).or_default();
+            pending.push(scrobble("One More Time", 100));
+        });
+        let scrobbler = Scrobbler::spawn(vec![Box::new(listenbrainz(&url))], Some(path.clone()));
+        requests.recv().unwrap();
+        wait_for(|| scrobbler.status().contains("Last error"));
+        assert_eq!(
+            scrobbler.status(),
+            "listenbrainz: 1 waiting\nLast error: listenbrainz: HTTP 503: Down for maintenance\n"
+        );
+        assert_eq!(
+            Cache::open(Some(path.clone())).pending["listenbrainz"].len(),
+            1
+        );
+        // The next scrobble goes along with the one left waiting
+        scrobbler.queue(&scrobble("Aerodynamic", 420));
+        let (_, body) = requests.recv().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["payload"].as_array().unwrap().len(), 2);
+        wait_for(|| scrobbler.status().starts_with("listenbrainz: 0 waiting"));
+        assert!(Cache::open(Some(path.clone())).pending["listenbrainz"].is_empty());
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn rejected() {
+        let (url, requests) = serve(vec![(400, "Bad listen")]);
+        let path = temporary("rejected");
+        let scrobbler = Scrobbler::spawn(vec![Box::new(listenbrainz(&url))], Some(path.clone()));
+        // Refused scrobbles are dropped rather than tried again
+        scrobbler.queue(&scrobble("One More Time", 100));
+        requests.recv().unwrap();
+        wait_for(|| scrobbler.status().contains("Last error"));
+        assert!(scrobbler.status().starts_with("listenbrainz: 0 waiting"));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn sharing() {
+        // Two instances keep each other's changes to the cache
+        let path = temporary("sharing");
+        let mut first = Cache::open(Some(path.clone()));
+        let mut second = Cache::open(Some(path.clone()));
+        let add = |scrobble: Scrobble| {
+            move |cache: &mut Cache| {
+                cache
+                    .pending
+                    .entry("lastfm".to_string())
+                    .or_default()
+                    .push(scrobble);
+            }
+        };
+        first.update(add(scrobble("One More Time", 100)));
+        second.update(add(scrobble("Aerodynamic", 420)));
+        assert_eq!(second.pending["lastfm"].len(), 2);
+        // Submitting in one instance takes the scrobble away from the other
+        second.update(|cache| {
+            cache
+                .pending
+                .get_mut("lastfm")
+                .unwrap()
+                .retain(|s| s.timestamp != 100);
+        });
+        first.update(add(scrobble("Digital Love", 720)));
+        let titles: Vec<&str> = first.pending["lastfm"]
+            .iter()
+            .map(|s| s.title.as_str())
+            .collect();
+        assert_eq!(titles, ["Aerodynamic", "Digital Love"]);
+        assert_eq!(Cache::open(Some(path.clone())).pending, first.pending);
+        assert!(!std::path::Path::new(&format!("{}.lock", path)).exists());
+        std::fs::remove_file(&path).ok();
+    }
+}
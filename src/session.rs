@@ -0,0 +1,51 @@
+// session.rs - keeping what was playing and how the panes were left between runs
+use crate::playlist::Snapshot;
+use crate::ui::State;
+use crate::util::{data_path, write_atomic};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Name of the file the session is kept in
+const SESSION: &str = "session.ron";
+
+// Everything needed to carry on from where the last run left off
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Session {
+    #[serde(default)]
+    pub playback: Option<Playback>,
+    // The pane that was open, and how each pane was left
+    #[serde(default)]
+    pub pane: Option<u8>,
+    #[serde(default)]
+    pub panes: HashMap<u8, State>,
+}
+
+// The play queue, and where playback was within it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Playback {
+    pub queue: Snapshot,
+    // Position in the current track, in milliseconds
+    pub position: u64,
+    pub volume: f64,
+    // Whether playback was stopped, which starts the track over
+    pub stopped: bool,
+}
+
+impl Session {
+    pub fn read() -> Self {
+        // Read the session from disk, starting afresh if there isn't one or it is damaged
+        data_path(SESSION)
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|session| ron::from_str(&session).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn write(&self) {
+        // Write the session to disk
+        if let (Some(path), Ok(session)) = (data_path(SESSION), ron::ser::to_string(self)) {
+            if write_atomic(&path, &session).is_err() {
+                println!("ERROR: Failed to write session to disk");
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// tags.rs - reading and writing tags in the formats different containers use
+use crate::track::Tag;
+use id3::frame::PictureType as Id3PictureType;
+use id3::Version;
+use lofty::{Accessor, AudioFile, MimeType, PictureType, TaggedFile, TaggedFileExt};
+
+// The tags of a file, in whichever format its container keeps them in
+pub enum TagFile {
+    // MP3 files
+    Id3(id3::Tag),
+    // Vorbis comments (FLAC, OGG, Opus), MP4 atoms (M4A) and APE tags, kept within their file
+    // so that edits are saved alongside everything else in it
+    Other(TaggedFile),
+}
+
+impl TagFile {
+    pub fn read(path: &str) -> Option<Self> {
+        // Read the tags that are already in a file
+        if !is_id3(path) {
+            if let Ok(file) = lofty::read_from_path(path) {
+                if file.primary_tag().or_else(|| file.first_tag()).is_some() {
+                    return Some(Self::Other(file));
+                }
+            }
+        }
+        // Fall back to id3, for mp3 files and anything not recognised
+        id3::Tag::read_from_path(path).ok().map(Self::Id3)
+    }
+
+    pub fn open(path: &str) -> Option<Self> {
+        // Read the tags of a file for editing, starting fresh ones if there are none
+        if let Some(tag) = Self::read(path) {
+            return Some(tag);
+        }
+        if is_id3(path) {
+            return Some(Self::Id3(id3::Tag::new()));
+        }
+        let mut file = lofty::read_from_path(path).ok()?;
+        file.insert_tag(lofty::Tag::new(file.primary_tag_type()));
+        Some(Self::Other(file))
+    }
+
+    pub fn write(&self, path: &str) -> Option<()> {
+        // Save the tags back to the file, leaving everything else in them intact
+        match self {
+            Self::Id3(tag) => tag.write_to_path(path, Version::Id3v24).ok(),
+            Self::Other(file) => file.save_to_path(path).ok(),
+        }
+    }
+
+    pub fn tag(&self) -> Tag {
+        // Convert into the tag format used throughout synchron
+        match self {
+            Self::Id3(tag) => Tag::from_id3(tag),
+            Self::Other(file) => file
+                .primary_tag()
+                .or_else(|| file.first_tag())
+                .map_or_else(Tag::default, Tag::from_lofty),
+        }
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        match self {
+            Self::Id3(tag) => tag.set_title(title),
+            Self::Other(file) => edit(file, |t| t.set_title(title.to_string())),
+        }
+    }
+
+    pub fn set_album(&mut self, album: &str) {
+        match self {
+            Self::Id3(tag) => tag.set_album(album),
+            Self::Other(file) => edit(file, |t| t.set_album(album.to_string())),
+        }
+    }
+
+    pub fn set_artist(&mut self, artist: &str) {
+        match self {
+            Self::Id3(tag) => tag.set_artist(artist),
+            Self::Other(file) => edit(file, |t| t.set_artist(artist.to_string())),
+        }
+    }
+
+    pub fn set_year(&mut self, year: i32) {
+        match self {
+            Self::Id3(tag) => tag.set_year(year),
+            Self::Other(file) => edit(file, |t| t.set_year(year.try_into().unwrap_or(0))),
+        }
+    }
+
+    pub fn cover(&self) -> Option<(&'static str, Vec<u8>)> {
+        // Find the embedded cover art, preferring the front cover, and its file extension
+        match self {
+            Self::Id3(tag) => {
+                let picture = tag
+                    .pictures()
+                    .find(|p| p.picture_type == Id3PictureType::CoverFront)
+                    .or_else(|| tag.pictures().next())?;
+                let extension = if picture.mime_type == "image/png" {
+                    "png"
+                } else {
+                    "jpg"
+                };
+                Some((extension, picture.data.clone()))
+            }
+            Self::Other(file) => {
+                let tag = file.primary_tag().or_else(|| file.first_tag())?;
+                let pictures = tag.pictures();
+                let picture = pictures
+                    .iter()
+                    .find(|p| p.pic_type() == PictureType::CoverFront)
+                    .or_else(|| pictures.first())?;
+                let extension = if picture.mime_type() == &MimeType::Png {
+                    "png"
+                } else {
+                    "jpg"
+                };
+                Some((extension, picture.data().to_vec()))
+            }
+        }
+    }
+}
+
+pub fn duration(path: &str) -> Option<u64> {
+    // Work out how long a file plays for, in seconds
+    let file = lofty::read_from_path(path).ok()?;
+    Some(file.properties().duration().as_secs())
+}
+
+fn edit<F: FnOnce(&mut lofty::Tag)>(file: &mut TaggedFile, f: F) {
+    // Change the tag that was read from the file, in place
+    let tag_type = if file.primary_tag().is_some() {
+        Some(file.primary_tag_type())
+    } else {
+        file.first_tag().map(lofty::Tag::tag_type)
+    };
+    if let Some(tag) = tag_type.and_then(|t| file.tag_mut(t)) {
+        f(tag);
+    }
+}
+
+fn is_id3(path: &str) -> bool {
+    // Work out if a file keeps its tags in id3, from its extension
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or_default();
+    extension.eq_ignore_ascii_case("mp3")
+}
@@ -1,7 +1,37 @@
 // track.rs - for managing track related activities
-use crate::util::expand_path;
-use id3::Version;
+use crate::chapters;
+use crate::cue;
+use crate::tags::{duration, TagFile};
+use crate::util::{datefmt, expand_path, is_file, timefmt};
+use lofty::{Accessor, ItemKey};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom};
+
+// Amount of the end of a file to check when fingerprinting it
+const FINGERPRINT_SIZE: u64 = 65536;
+
+// Columns that can be shown in tables of tracks
+pub const COLUMNS: [&str; 16] = [
+    "title",
+    "album",
+    "artist",
+    "year",
+    "track",
+    "disc",
+    "album_artist",
+    "genre",
+    "composer",
+    "duration",
+    "comment",
+    "path",
+    "plays",
+    "last_played",
+    "skips",
+    "rating",
+];
 
 // For holding tag information
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -10,6 +40,34 @@ pub struct Tag {
     pub album: String,
     pub artist: String,
     pub year: String,
+    #[serde(default)]
+    pub track_number: Option<u32>,
+    #[serde(default)]
+    pub total_tracks: Option<u32>,
+    #[serde(default)]
+    pub disc_number: Option<u32>,
+    #[serde(default)]
+    pub album_artist: Option<String>,
+    #[serde(default)]
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub composer: Option<String>,
+    // Duration in seconds
+    #[serde(default)]
+    pub duration: Option<u64>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub replay_gain: ReplayGain,
+}
+
+// ReplayGain values, with gains in dB and peaks as a fraction of full scale
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
 }
 
 impl Tag {
@@ -20,6 +78,60 @@ impl Tag {
             album: tag.album().unwrap_or("[unknown]").to_string(),
             artist: tag.artist().unwrap_or("[unknown]").to_string(),
             year: tag.year().unwrap_or(0).to_string(),
+            track_number: tag.track(),
+            total_tracks: tag.total_tracks(),
+            disc_number: tag.disc(),
+            album_artist: tag.album_artist().map(ToString::to_string),
+            genre: tag.genre().map(ToString::to_string),
+            composer: tag
+                .get("TCOM")
+                .and_then(|f| f.content().text())
+                .map(ToString::to_string),
+            // Length frame is in milliseconds
+            duration: tag.duration().map(|d| u64::from(d) / 1000),
+            comment: tag.comments().next().map(|c| c.text.clone()),
+            // ReplayGain is kept in user defined text frames
+            replay_gain: ReplayGain {
+                track_gain: replay_gain(extended_text(tag, "REPLAYGAIN_TRACK_GAIN")),
+                track_peak: replay_gain(extended_text(tag, "REPLAYGAIN_TRACK_PEAK")),
+                album_gain: replay_gain(extended_text(tag, "REPLAYGAIN_ALBUM_GAIN")),
+                album_peak: replay_gain(extended_text(tag, "REPLAYGAIN_ALBUM_PEAK")),
+            },
+        }
+    }
+
+    pub fn from_lofty(tag: &lofty::Tag) -> Self {
+        // Load from vorbis comments, mp4 atoms or an ape tag
+        Self {
+            title: tag
+                .title()
+                .unwrap_or_else(|| "[unknown]".into())
+                .to_string(),
+            album: tag
+                .album()
+                .unwrap_or_else(|| "[unknown]".into())
+                .to_string(),
+            artist: tag
+                .artist()
+                .unwrap_or_else(|| "[unknown]".into())
+                .to_string(),
+            year: tag.year().unwrap_or(0).to_string(),
+            track_number: tag.track(),
+            total_tracks: tag.track_total(),
+            disc_number: tag.disk(),
+            album_artist: tag
+                .get_string(&ItemKey::AlbumArtist)
+                .map(ToString::to_string),
+            genre: tag.genre().map(|g| g.to_string()),
+            composer: tag.get_string(&ItemKey::Composer).map(ToString::to_string),
+            duration: None,
+            comment: tag.comment().map(|c| c.to_string()),
+            replay_gain: ReplayGain {
+                track_gain: replay_gain(tag.get_string(&ItemKey::ReplayGainTrackGain)),
+                track_peak: replay_gain(tag.get_string(&ItemKey::ReplayGainTrackPeak)),
+                album_gain: replay_gain(tag.get_string(&ItemKey::ReplayGainAlbumGain)),
+                album_peak: replay_gain(tag.get_string(&ItemKey::ReplayGainAlbumPeak)),
+            },
         }
     }
 }
@@ -32,75 +144,266 @@ impl Default for Tag {
             album: "[unknown]".to_string(),
             artist: "[unknown]".to_string(),
             year: "0".to_string(),
+            track_number: None,
+            total_tracks: None,
+            disc_number: None,
+            album_artist: None,
+            genre: None,
+            composer: None,
+            duration: None,
+            comment: None,
+            replay_gain: ReplayGain::default(),
         }
     }
 }
 
+// Statistics about a track's life in the library
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Stats {
+    // Number of times the track has been played to the end
+    pub plays: u32,
+    // When the track was added to the library, in seconds since the unix epoch
+    pub added: Option<u64>,
+    // When the track last finished playing, in seconds since the unix epoch
+    #[serde(default)]
+    pub last_played: Option<u64>,
+    // Number of times the track has been skipped part way through
+    #[serde(default)]
+    pub skips: u32,
+    // Star rating out of 5, where 0 means unrated
+    #[serde(default)]
+    pub rating: u8,
+    // Where listening got up to in an audiobook, in seconds
+    #[serde(default)]
+    pub position: u64,
+}
+
+// A chapter of a long track, such as an audiobook
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    // Where the chapter starts, in milliseconds
+    pub start: u64,
+}
+
+// A named place in a track to come back to
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Bookmark {
+    pub name: String,
+    // Where the bookmark is, in seconds
+    pub position: u64,
+}
+
+// The part of a file that a track takes up, for albums ripped to one file with a cue sheet
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct Range {
+    // Where the track starts in its file, in milliseconds
+    pub start: u64,
+    // Where the track ends, or nothing if it runs to the end of the file
+    pub end: Option<u64>,
+}
+
 // Track struct to handle file reading, and tag extraction
 #[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
 pub struct Track {
     pub path: String,
     pub tag: Tag,
+    // Size and checksum of the file, for recognising it once it has been moved
+    #[serde(default)]
+    pub fingerprint: Option<(u64, u32)>,
+    #[serde(default)]
+    pub stats: Stats,
+    // Chapter markers read from the file, in the order they start
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    // Bookmarks made while listening, in the order they come in the track
+    #[serde(default)]
+    pub bookmarks: Vec<Bookmark>,
+    // Part of the file this track takes up, when a cue sheet splits the file into tracks
+    #[serde(default)]
+    pub range: Option<Range>,
 }
 
 impl Track {
-    pub fn load(path: &str) -> Self {
+    pub fn load(path: &str) -> Option<Self> {
         // Expand provided path, read the tags and create new instance
+        // Only local files can be loaded, so streams and other URIs are turned away
+        if !path.starts_with("file://") && path.contains("://") {
+            return None;
+        }
         let path = Track::format_path(path);
-        let path = expand_path(&path).expect("File not found");
-        let tag = id3::Tag::read_from_path(&path).unwrap_or_else(|_| id3::Tag::new());
+        let path = expand_path(&path)?;
+        // Make sure the file can actually be read, and isn't a folder
+        if !is_file(&path) {
+            return None;
+        }
+        File::open(&path).ok()?;
+        let mut tag = TagFile::read(&path).map(|t| t.tag()).unwrap_or_default();
+        tag.duration = duration(&path).or(tag.duration);
+        let fingerprint = fingerprint(&path);
+        let chapters = chapters::read(&path);
         let path = format!("file://{}", path);
-        Self {
+        Some(Self {
             path,
-            tag: Tag::from_id3(&tag),
-        }
+            tag,
+            fingerprint,
+            stats: Stats::default(),
+            chapters,
+            bookmarks: vec![],
+            range: None,
+        })
     }
 
-    pub fn set_title(&mut self, title: &str) {
-        // Set the title of this track
-        let path = Track::format_path(&self.path);
-        if let Ok(mut tag) = id3::Tag::read_from_path(&path) {
-            tag.set_title(title);
-            self.tag.title = title.to_string();
-            tag.write_to_path(path, Version::Id3v24).ok();
+    pub fn load_all(path: &str) -> Vec<Self> {
+        // Load a file as the tracks its cue sheet marks out in it, or as one track without one
+        // Cue sheets beside audiobooks mark out their chapters instead
+        let track = Self::load(path);
+        let sheet = track
+            .as_ref()
+            .filter(|t| !t.is_audiobook())
+            .and_then(|t| cue::find(&Track::format_path(&t.path)));
+        match (track, sheet) {
+            (Some(track), Some((sheet, file))) if file.tracks.len() > 1 => {
+                track.split(&sheet, &file)
+            }
+            (track, _) => track.into_iter().collect(),
         }
     }
 
-    pub fn set_album(&mut self, album: &str) {
-        // Set the title of this track
-        let path = Track::format_path(&self.path);
-        if let Ok(mut tag) = id3::Tag::read_from_path(&path) {
-            tag.set_album(album);
-            self.tag.album = album.to_string();
-            tag.write_to_path(path, Version::Id3v24).ok();
+    fn split(&self, sheet: &cue::Sheet, file: &cue::File) -> Vec<Self> {
+        // Turn a file into a track for each part of it, with tags from the cue sheet
+        let length = self.tag.duration.map(|d| d * 1000);
+        let total = u32::try_from(file.tracks.len()).ok();
+        let mut result = vec![];
+        for (i, part) in file.tracks.iter().enumerate() {
+            // Each track runs up to where the next one starts, and the last to the end of the file
+            let end = file.tracks.get(i + 1).map(|t| t.start);
+            if matches!(end, Some(end) if end <= part.start) {
+                continue;
+            }
+            let mut tag = self.tag.clone();
+            tag.title = part
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Track {}", part.number));
+            tag.artist = part
+                .performer
+                .clone()
+                .or_else(|| sheet.performer.clone())
+                .unwrap_or(tag.artist);
+            tag.album = sheet.title.clone().unwrap_or(tag.album);
+            tag.album_artist = sheet.performer.clone().or(tag.album_artist);
+            tag.year = sheet.date.clone().unwrap_or(tag.year);
+            tag.genre = sheet.genre.clone().or(tag.genre);
+            tag.track_number = Some(part.number);
+            tag.total_tracks = total;
+            tag.duration = end
+                .or(length)
+                .map(|end| end.saturating_sub(part.start) / 1000);
+            result.push(Self {
+                tag,
+                chapters: vec![],
+                range: Some(Range {
+                    start: part.start,
+                    end,
+                }),
+                ..self.clone()
+            });
         }
+        result
     }
 
-    pub fn set_artist(&mut self, artist: &str) {
+    pub fn is_audiobook(&self) -> bool {
+        // Audiobooks are recognised by their genre, or the extension audiobooks use in mp4
+        let genre = self.tag.genre.as_deref().unwrap_or_default().to_lowercase();
+        genre.contains("audiobook")
+            || genre.contains("audio book")
+            || self.path.to_lowercase().ends_with(".m4b")
+    }
+
+    pub fn set_title(&mut self, title: &str) -> Result<(), String> {
         // Set the title of this track
+        self.edit(|tag| tag.set_title(title))?;
+        self.tag.title = title.to_string();
+        Ok(())
+    }
+
+    pub fn set_album(&mut self, album: &str) -> Result<(), String> {
+        // Set the album of this track
+        self.edit(|tag| tag.set_album(album))?;
+        self.tag.album = album.to_string();
+        Ok(())
+    }
+
+    pub fn set_artist(&mut self, artist: &str) -> Result<(), String> {
+        // Set the artist of this track
+        self.edit(|tag| tag.set_artist(artist))?;
+        self.tag.artist = artist.to_string();
+        Ok(())
+    }
+
+    pub fn set_year(&mut self, year: &str) -> Result<(), String> {
+        // Set the year of this track
+        let number = year.parse().unwrap_or(0);
+        self.edit(|tag| tag.set_year(number))?;
+        self.tag.year = year.to_string();
+        Ok(())
+    }
+
+    fn edit<F: FnOnce(&mut TagFile)>(&mut self, f: F) -> Result<(), String> {
+        // Make a change to the tags in this track's file
         let path = Track::format_path(&self.path);
-        if let Ok(mut tag) = id3::Tag::read_from_path(&path) {
-            tag.set_artist(artist);
-            self.tag.artist = artist.to_string();
-            tag.write_to_path(path, Version::Id3v24).ok();
+        // Tracks split out of a file share its tags, and would take them from its cue sheet again
+        if self.range.is_some() {
+            return Err(format!("Tracks from a cue sheet can't be tagged: {}", path));
         }
+        let mut tag =
+            TagFile::open(&path).ok_or_else(|| format!("Failed to read tags: {}", path))?;
+        f(&mut tag);
+        tag.write(&path)
+            .ok_or_else(|| format!("Failed to write tags: {}", path))?;
+        // Changing the tags changes the file, so it needs recognising again
+        self.fingerprint = fingerprint(&path);
+        Ok(())
     }
 
-    pub fn set_year(&mut self, year: &str) {
-        // Set the title of this track
+    pub fn update(&mut self) {
         let path = Track::format_path(&self.path);
-        if let Ok(mut tag) = id3::Tag::read_from_path(&path) {
-            tag.set_year(year.parse().unwrap_or(0));
-            self.tag.year = year.to_string();
-            tag.write_to_path(path, Version::Id3v24).ok();
+        // Tracks split out of a file take their tags from its cue sheet again
+        if let Some(range) = self.range {
+            let found = Track::load_all(&path)
+                .into_iter()
+                .find(|t| t.range.map(|r| r.start) == Some(range.start));
+            if let Some(track) = found {
+                self.tag = track.tag;
+                self.range = track.range;
+                self.fingerprint = track.fingerprint.or(self.fingerprint);
+            }
+            return;
+        }
+        if let Some(tag) = TagFile::read(&path) {
+            self.tag = tag.tag();
+            self.tag.duration = duration(&path).or(self.tag.duration);
         }
+        self.chapters = chapters::read(&path);
+        self.fingerprint = fingerprint(&path).or(self.fingerprint);
     }
 
-    pub fn update(&mut self) {
+    pub fn extract_art(&self) -> Option<String> {
+        // Extract embedded cover art into the cache, and get the URI to it
         let path = Track::format_path(&self.path);
-        if let Ok(tag) = id3::Tag::read_from_path(&path) {
-            self.tag = Tag::from_id3(&tag);
+        let (extension, data) = TagFile::read(&path)?.cover()?;
+        // Name the cached file after the track it came from
+        let path_base = expand_path("~/.cache").unwrap_or_else(|| "~/.cache".to_string());
+        let dir = format!("{}/synchron/art", path_base);
+        std::fs::create_dir_all(&dir).ok()?;
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        let art = format!("{}/{:x}.{}", dir, hasher.finish(), extension);
+        if !std::path::Path::new(&art).exists() {
+            std::fs::write(&art, data).ok()?;
         }
+        Some(format!("file://{}", art))
     }
 
     pub fn format_path(path: &str) -> String {
@@ -119,8 +422,95 @@ impl Track {
         )
     }
 
+    pub fn column(&self, name: &str) -> String {
+        // Get the value of a field, as shown in a table column
+        let tag = &self.tag;
+        match name {
+            "title" => tag.title.clone(),
+            "album" => tag.album.clone(),
+            "artist" => tag.artist.clone(),
+            "year" => tag.year.clone(),
+            "track" => match (tag.track_number, tag.total_tracks) {
+                (Some(number), Some(total)) => format!("{}/{}", number, total),
+                (Some(number), None) => number.to_string(),
+                _ => String::new(),
+            },
+            "disc" => tag.disc_number.map(|d| d.to_string()).unwrap_or_default(),
+            "album_artist" => tag.album_artist.clone().unwrap_or_default(),
+            "genre" => tag.genre.clone().unwrap_or_default(),
+            "composer" => tag.composer.clone().unwrap_or_default(),
+            "duration" => tag.duration.map(timefmt).unwrap_or_default(),
+            "comment" => tag.comment.clone().unwrap_or_default(),
+            "path" => Track::format_path(&self.path),
+            "plays" => self.stats.plays.to_string(),
+            "last_played" => self.stats.last_played.map(datefmt).unwrap_or_default(),
+            "skips" => self.stats.skips.to_string(),
+            "rating" => {
+                let rating = usize::from(self.stats.rating.min(5));
+                if rating == 0 {
+                    String::new()
+                } else {
+                    format!("{}{}", "★".repeat(rating), "☆".repeat(5 - rating))
+                }
+            }
+            _ => String::new(),
+        }
+    }
+
     pub fn format(&self) -> String {
         let (path, title, album, artist, year) = self.format_elements();
         format!("{} | {} | {} | {} | {}", path, title, album, artist, year)
     }
 }
+
+fn fingerprint(path: &str) -> Option<(u64, u32)> {
+    // Identify a file by its size and a checksum of its end, which stay the same when it moves
+    let mut file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let start = size.saturating_sub(FINGERPRINT_SIZE);
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer).ok()?;
+    Some((size, crc32fast::hash(&buffer)))
+}
+
+fn extended_text<'a>(tag: &'a id3::Tag, description: &str) -> Option<&'a str> {
+    // Find a user defined text frame, whose descriptions are written in either case
+    tag.extended_texts()
+        .find(|t| t.description.eq_ignore_ascii_case(description))
+        .map(|t| t.value.as_str())
+}
+
+fn replay_gain(value: Option<&str>) -> Option<f64> {
+    // Read a ReplayGain value, with gains written along with their unit, such as "-6.48 dB"
+    let value = value?
+        .trim()
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    value.trim().parse().ok().filter(|v: &f64| v.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranged_edits() {
+        // Tracks from a cue sheet are refused rather than only changed in the library
+        let mut track = Track {
+            path: "/music/album.flac".to_string(),
+            range: Some(Range {
+                start: 1000,
+                end: None,
+            }),
+            ..Track::default()
+        };
+        let error = track.set_title("New title").unwrap_err();
+        assert_eq!(
+            error,
+            "Tracks from a cue sheet can't be tagged: /music/album.flac"
+        );
+        assert_eq!(track.tag.title, Tag::default().title);
+        assert!(track.set_year("2001").is_err());
+        assert_eq!(track.tag.year, Tag::default().year);
+    }
+}
@@ -1,10 +1,12 @@
 // ui.rs - controls and renders the TUI
-use crate::audio::{LoopStatus, Manager, PlaybackStatus};
+use crate::audio::{Import, LoopStatus, Manager, PlaybackStatus};
 use crate::config::{Pane, PULSE};
+use crate::session::Session;
 use crate::track::Track;
 use crate::util::{
     align_sides, artist_tracks, expand_path, form_library_tree, format_artist_track,
-    format_playlist, format_table, is_file, list_dir, pad_table, timefmt,
+    format_bookmarks, format_history, format_playlist, format_podcasts, format_stations,
+    format_table, is_file, list_dir, pad_table, timefmt,
 };
 pub use crossterm::{
     cursor,
@@ -14,6 +16,7 @@ pub use crossterm::{
     terminal::{self, ClearType},
     Command, Result,
 };
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
@@ -37,7 +40,8 @@ impl Size {
     }
 }
 
-#[derive(PartialEq, Debug)]
+// How a pane is left, which is kept between runs, apart from what is read again on startup
+#[derive(PartialEq, Debug, Deserialize, Serialize)]
 pub enum State {
     Library {
         selection: usize,
@@ -46,6 +50,7 @@ pub enum State {
     Files {
         selection: usize,
         dir: String,
+        #[serde(skip)]
         list: Vec<String>,
     },
     SortedLibrary {
@@ -58,6 +63,29 @@ pub enum State {
         playlist: String,
         track: HashMap<String, usize>,
     },
+    Search {
+        query: String,
+        #[serde(skip)]
+        results: Vec<usize>,
+        selection: usize,
+        // Whether the query is a filter expression rather than a fuzzy search
+        filter: bool,
+    },
+    History {
+        // Counted from the most recent listen
+        selection: usize,
+    },
+    Radio {
+        selection: usize,
+    },
+    Podcasts {
+        depth: u8,
+        feed: usize,
+        episode: usize,
+    },
+    Bookmarks {
+        selection: usize,
+    },
     Empty,
 }
 
@@ -78,10 +106,31 @@ impl State {
         matches!(self, Self::Playlists { .. })
     }
 
+    pub fn is_search(&self) -> bool {
+        matches!(self, Self::Search { .. })
+    }
+
+    pub fn is_history(&self) -> bool {
+        matches!(self, Self::History { .. })
+    }
+
+    pub fn is_radio(&self) -> bool {
+        matches!(self, Self::Radio { .. })
+    }
+
+    pub fn is_podcasts(&self) -> bool {
+        matches!(self, Self::Podcasts { .. })
+    }
+
+    pub fn is_bookmarks(&self) -> bool {
+        matches!(self, Self::Bookmarks { .. })
+    }
+
     pub fn get_selection(&self) -> usize {
         match self {
             Self::Library { selection, .. } => *selection,
             Self::Files { selection, .. } => *selection,
+            Self::Search { selection, .. } => *selection,
             _ => unreachable!(),
         }
     }
@@ -96,6 +145,10 @@ pub struct Ui {
     size: Size,
     active: bool,
     library_updated: bool,
+    import: Option<Arc<Mutex<Import>>>,
+    // Podcast work going on in the background, and the message it finishes with
+    task: Option<(String, Arc<Mutex<Option<String>>>)>,
+    message: Option<String>,
 }
 
 impl Ui {
@@ -150,23 +203,54 @@ impl Ui {
                         track: playlist_ptrs.clone(),
                         playlist: playlist.to_string(),
                     },
+                    Pane::Search => State::Search {
+                        query: String::new(),
+                        results: vec![],
+                        selection: 0,
+                        filter: false,
+                    },
+                    Pane::History => State::History { selection: 0 },
+                    Pane::Radio => State::Radio { selection: 0 },
+                    Pane::Podcasts => State::Podcasts {
+                        depth: 0,
+                        feed: 0,
+                        episode: 0,
+                    },
+                    Pane::Bookmarks => State::Bookmarks { selection: 0 },
                     Pane::Empty => State::Empty,
                 },
             );
         }
-        let ptr = mgmt.config.open_on_pane;
+        // Carry the panes on from how they were left, as long as they are still set up the same
+        let size = Size::screen()?;
+        let session = Session::read();
+        let rows = size.height.saturating_sub(2).into();
+        for (key, saved) in session.panes {
+            if let Some(state) = states.get_mut(&key) {
+                restore_state(state, saved, &mgmt, rows);
+            }
+        }
+        let ptr = session
+            .pane
+            .filter(|p| states.contains_key(p))
+            .unwrap_or(mgmt.config.open_on_pane);
         std::mem::drop(mgmt);
         // Form struct
-        Ok(Self {
+        let mut ui = Self {
             stdout: std::io::stdout(),
             mgmt: m,
             states,
             ptr,
             play_ptr: ptr,
-            size: Size::screen()?,
+            size,
             active: true,
             library_updated: false,
-        })
+            import: None,
+            task: None,
+            message: None,
+        };
+        ui.fix_podcasts();
+        Ok(ui)
     }
 
     pub fn init(&mut self) -> Result<()> {
@@ -192,10 +276,19 @@ impl Ui {
                     Event::Mouse(..) => (),
                 }
                 self.render()?;
+            } else if self.import_finished() {
+                // Show the newly imported tracks
+                self.library_updated = true;
+                self.render()?;
+            } else if self.task_finished() || self.error_reported() {
+                self.render()?;
             } else if self.mgmt.lock().unwrap().updated {
                 self.mgmt.lock().unwrap().updated = false;
                 self.render()?;
-            } else if status == PlaybackStatus::Playing {
+            } else if status == PlaybackStatus::Playing
+                || self.import.is_some()
+                || self.task.is_some()
+            {
                 // Rerender the status line if playing, to keep up with the position of the song
                 let status_idx = self.size.height.saturating_sub(1);
                 queue!(
@@ -210,7 +303,93 @@ impl Ui {
         Ok(())
     }
 
+    fn import_finished(&mut self) -> bool {
+        // Check if a background import has just finished, leaving a summary of it
+        let progress = if let Some(import) = &self.import {
+            import.lock().unwrap().clone()
+        } else {
+            return false;
+        };
+        if !progress.finished {
+            return false;
+        }
+        self.import = None;
+        self.message = Some(match progress.failed.as_slice() {
+            [] => format!("Imported {} new tracks", progress.added),
+            [first, ..] => format!(
+                "Imported {} new tracks, {} failed (such as {})",
+                progress.added,
+                progress.failed.len(),
+                first
+            ),
+        });
+        true
+    }
+
+    fn import(&mut self) {
+        // Import the selected file or folder into the library in the background
+        if let State::Files {
+            selection,
+            list,
+            dir,
+        } = self.state()
+        {
+            if self.import.is_some() || list[*selection] == ".." {
+                return;
+            }
+            let path = format!("{}/{}", dir, list[*selection]);
+            let progress = Arc::new(Mutex::new(Import::default()));
+            self.import = Some(progress.clone());
+            let mgmt = self.mgmt.clone();
+            std::thread::spawn(move || {
+                Manager::import(&mgmt, &path, |p| *progress.lock().unwrap() = p.clone());
+            });
+        }
+    }
+
+    fn task_finished(&mut self) -> bool {
+        // Check if background podcast work has just finished, leaving its message
+        let message = if let Some((_, result)) = &self.task {
+            result.lock().unwrap().take()
+        } else {
+            return false;
+        };
+        if message.is_none() {
+            return false;
+        }
+        self.task = None;
+        self.message = message;
+        self.fix_podcasts();
+        true
+    }
+
+    fn error_reported(&mut self) -> bool {
+        // Check for an error from playback, and show it until the next key press
+        let error = self.mgmt.lock().unwrap().take_error();
+        if let Some(error) = &error {
+            self.message = Some(format!("ERROR: {}", error));
+        }
+        error.is_some()
+    }
+
+    fn spawn_task<F>(&mut self, description: String, task: F)
+    where
+        F: FnOnce(&Arc<Mutex<Manager>>) -> String + Send + 'static,
+    {
+        // Fetch or download podcasts in the background, one thing at a time
+        if let Some((busy, _)) = &self.task {
+            self.message = Some(format!("Still busy: {}", busy));
+            return;
+        }
+        let result = Arc::new(Mutex::new(None));
+        self.task = Some((description, result.clone()));
+        let mgmt = self.mgmt.clone();
+        std::thread::spawn(move || *result.lock().unwrap() = Some(task(&mgmt)));
+    }
+
     pub fn on_key(&mut self, e: KeyEvent) {
+        // Messages are dismissed on the next key press
+        self.message = None;
         // Handle key event
         match (e.modifiers, e.code) {
             // Mode switching
@@ -234,10 +413,16 @@ impl Ui {
             (KMod::NONE, KCode::Char('c')) => self.mgmt.lock().unwrap().play(),
             // [v] : Pause playback
             (KMod::NONE, KCode::Char('v')) => self.mgmt.lock().unwrap().pause(),
-            // [d] : Delete from library / Delete playlist
+            // [d] : Delete from library / playlist / station / podcast / bookmark
             (KMod::NONE, KCode::Char('d')) => {
                 if self.state().is_playlists() {
                     self.delete_playlist();
+                } else if self.state().is_radio() {
+                    self.delete_station();
+                } else if self.state().is_podcasts() {
+                    self.delete_feed();
+                } else if self.state().is_bookmarks() {
+                    self.delete_bookmark();
                 } else {
                     self.remove();
                 }
@@ -246,6 +431,16 @@ impl Ui {
             (KMod::NONE, KCode::Char('e')) => self.tag_edit().unwrap_or(()),
             // [Enter] : Play selection / Add track to library
             (KMod::NONE, KCode::Enter) => self.select(),
+            // [i] : Import selected file or folder into library
+            (KMod::NONE, KCode::Char('i')) => self.import(),
+            // [/] : Search the library
+            (KMod::NONE, KCode::Char('/')) => self.search(),
+            // [f] : Filter the library with an expression
+            (KMod::NONE, KCode::Char('f')) => self.filter(),
+            // [*] : Rate selected track
+            (KMod::NONE | KMod::SHIFT, KCode::Char('*')) => self.rate(),
+            // [u] : Queue selected track
+            (KMod::NONE, KCode::Char('u')) => self.queue(),
             // [/\] : Move up selection in library
             (KMod::NONE, KCode::Up) => self.selection_up(),
             // [\/] : Move down selection in library
@@ -294,10 +489,32 @@ impl Ui {
             (KMod::NONE, KCode::Char('a')) => self.add_to_playlist(),
             // [r] : Remove from playlist
             (KMod::NONE, KCode::Char('r')) => self.remove_from_playlist(),
-            // [n] : New playlist
-            (KMod::NONE, KCode::Char('n')) => self.create_playlist(),
+            // [n] : New playlist / New station / New podcast
+            (KMod::NONE, KCode::Char('n')) => {
+                if self.state().is_radio() {
+                    self.create_station();
+                } else if self.state().is_podcasts() {
+                    self.add_feed();
+                } else {
+                    self.create_playlist();
+                }
+            }
+            // [s] : New smart playlist
+            (KMod::NONE, KCode::Char('s')) => self.create_smart_playlist(),
             // [k] : Rename playlist
             (KMod::NONE, KCode::Char('k')) => self.rename_playlist(),
+            // [g] : Download selected episode
+            (KMod::NONE, KCode::Char('g')) => self.download_episode(),
+            // [y] : Mark selected episode as played or unplayed
+            (KMod::NONE, KCode::Char('y')) => self.toggle_played(),
+            // [R] : Refresh podcasts
+            (KMod::NONE | KMod::SHIFT, KCode::Char('R')) => self.refresh_feeds(),
+            // [b] : Bookmark the current position
+            (KMod::NONE, KCode::Char('b')) => self.create_bookmark(),
+            // [[] : Previous chapter
+            (KMod::NONE, KCode::Char('[')) => self.mgmt.lock().unwrap().previous_chapter(),
+            // []] : Next chapter
+            (KMod::NONE, KCode::Char(']')) => self.mgmt.lock().unwrap().next_chapter(),
             // [;] or [:] : Command mode
             (KMod::NONE, KCode::Char(':' | ';')) => (),
             // Spam
@@ -327,22 +544,260 @@ impl Ui {
             if name.is_empty() {
                 return;
             }
-            self.states.iter_mut().for_each(|(_, s)| {
-                if let State::Playlists {
-                    track, playlist: p, ..
-                } = s
-                {
-                    track.insert(name.clone(), 0);
-                    if p.is_empty() {
-                        // Fix empty track pointer
-                        *p = name.clone();
-                    }
+            self.add_playlist_pointers(&name);
+            self.mgmt.lock().unwrap().new_playlist(&name);
+        }
+    }
+
+    fn create_smart_playlist(&mut self) {
+        // Create a playlist from a filter expression, that keeps up with the library
+        if let Ok(Some(name)) = self.get_input("Smart playlist name: ") {
+            if name.is_empty() {
+                return;
+            }
+            if let Ok(Some(rule)) = self.get_input("Rule: ") {
+                let result = self.mgmt.lock().unwrap().new_smart_playlist(&name, &rule);
+                match result {
+                    Ok(()) => self.add_playlist_pointers(&name),
+                    Err(e) => self.message = Some(format!("Invalid rule: {}", e)),
+                }
+            }
+        }
+    }
+
+    fn create_station(&mut self) {
+        // Add an internet radio station by its URL
+        if let Ok(Some(url)) = self.get_input("Station URL: ") {
+            if url.is_empty() {
+                return;
+            }
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                self.message = Some(format!("Expected an http(s) URL: {}", url));
+                return;
+            }
+            // Stations are named after their URL unless given a name
+            if let Ok(Some(name)) = self.get_input("Station name: ") {
+                let name = if name.is_empty() { url.clone() } else { name };
+                self.mgmt.lock().unwrap().add_station(&name, &url);
+            }
+        }
+    }
+
+    fn delete_station(&mut self) {
+        // Delete the selected radio station, after confirming it
+        if let State::Radio { selection } = self.state() {
+            let selection = *selection;
+            let name = match self.mgmt.lock().unwrap().database.stations.get(selection) {
+                Some(station) => station.name.clone(),
+                None => return,
+            };
+            let warning = format!(
+                "WARNING: Are you sure you want '{}' to be deleted? (y/n): ",
+                name
+            );
+            if let Ok(Some(confirm)) = self.get_input(&warning) {
+                if confirm == "y" {
+                    self.mgmt.lock().unwrap().remove_station(selection);
+                    self.selection_up();
+                }
+            }
+        }
+    }
+
+    fn add_feed(&mut self) {
+        // Follow a podcast by the URL or path of its feed
+        if let Ok(Some(url)) = self.get_input("Feed URL or file: ") {
+            if url.is_empty() {
+                return;
+            }
+            self.spawn_task(
+                format!("Fetching {}", url),
+                move |m| match Manager::add_feed(m, &url) {
+                    Ok(title) => format!("Added podcast: {}", title),
+                    Err(e) => e,
+                },
+            );
+        }
+    }
+
+    fn delete_feed(&mut self) {
+        // Stop following the selected podcast, after confirming it
+        if let State::Podcasts { depth: 0, feed, .. } = self.state() {
+            let feed = *feed;
+            let title = match self.mgmt.lock().unwrap().database.podcasts.get(feed) {
+                Some(podcast) => podcast.title.clone(),
+                None => return,
+            };
+            let warning = format!(
+                "WARNING: Are you sure you want '{}' to be deleted? (y/n): ",
+                title
+            );
+            if let Ok(Some(confirm)) = self.get_input(&warning) {
+                if confirm == "y" {
+                    self.mgmt.lock().unwrap().remove_feed(feed);
+                    self.fix_podcasts();
+                }
+            }
+        }
+    }
+
+    fn refresh_feeds(&mut self) {
+        // Check every podcast for new episodes in the background
+        if self.state().is_podcasts() {
+            self.spawn_task("Refreshing podcasts".to_string(), |m| {
+                let (new, failed) = Manager::refresh_feeds(m);
+                match failed.as_slice() {
+                    [] => format!("Found {} new episodes", new),
+                    [first, ..] => format!(
+                        "Found {} new episodes, {} podcasts failed ({})",
+                        new,
+                        failed.len(),
+                        first
+                    ),
                 }
             });
-            self.mgmt.lock().unwrap().new_playlist(&name);
         }
     }
 
+    fn download_episode(&mut self) {
+        // Download the selected episode in the background
+        if let Some((feed, episode)) = self.selected_episode() {
+            let mgmt = self.mgmt.lock().unwrap();
+            let title = mgmt.database.podcasts[feed].episodes[episode].title.clone();
+            std::mem::drop(mgmt);
+            self.spawn_task(format!("Downloading {}", title), move |m| {
+                match Manager::download_episode(m, feed, episode) {
+                    Ok(path) => format!("Downloaded to {}", path),
+                    Err(e) => e,
+                }
+            });
+        }
+    }
+
+    fn toggle_played(&mut self) {
+        // Mark the selected episode as played, or as not played yet
+        if let Some((feed, episode)) = self.selected_episode() {
+            let mut mgmt = self.mgmt.lock().unwrap();
+            let played = mgmt.database.podcasts[feed].episodes[episode].played;
+            mgmt.set_played(feed, episode, !played);
+        }
+    }
+
+    fn selected_episode(&self) -> Option<(usize, usize)> {
+        // Find the feed and episode selected in the podcasts pane
+        if let State::Podcasts {
+            depth: 1,
+            feed,
+            episode,
+        } = self.state()
+        {
+            let mgmt = self.mgmt.lock().unwrap();
+            mgmt.database.podcasts.get(*feed)?.episodes.get(*episode)?;
+            Some((*feed, *episode))
+        } else {
+            None
+        }
+    }
+
+    fn fix_podcasts(&mut self) {
+        // Keep podcast selections within the podcasts and episodes, which may have changed
+        let lengths: Vec<usize> = self
+            .mgmt
+            .lock()
+            .unwrap()
+            .database
+            .podcasts
+            .iter()
+            .map(|f| f.episodes.len())
+            .collect();
+        for state in self.states.values_mut() {
+            if let State::Podcasts {
+                depth,
+                feed,
+                episode,
+            } = state
+            {
+                *feed = (*feed).min(lengths.len().saturating_sub(1));
+                let episodes = lengths.get(*feed).copied().unwrap_or(0);
+                *episode = (*episode).min(episodes.saturating_sub(1));
+                if lengths.is_empty() {
+                    *depth = 0;
+                }
+            }
+        }
+    }
+
+    fn podcast_lengths(&self) -> (usize, usize) {
+        // Count the podcasts, and the episodes of the selected one
+        let mgmt = self.mgmt.lock().unwrap();
+        let feed = match self.state() {
+            State::Podcasts { feed, .. } => *feed,
+            _ => 0,
+        };
+        let episodes = mgmt
+            .database
+            .podcasts
+            .get(feed)
+            .map_or(0, |f| f.episodes.len());
+        (mgmt.database.podcasts.len(), episodes)
+    }
+
+    fn create_bookmark(&mut self) {
+        // Bookmark where playback is in the current track, named after the chapter by default
+        let mgmt = self.mgmt.lock().unwrap();
+        if mgmt.current_track().is_none() {
+            self.message = Some("No track loaded to bookmark".to_string());
+            return;
+        }
+        let chapter = mgmt.chapter().map(|(_, c)| c.title.clone());
+        std::mem::drop(mgmt);
+        let initial = chapter.unwrap_or_default();
+        if let Ok(Some(name)) = self.prompt("Bookmark name: ", &initial, false) {
+            if !name.is_empty() {
+                self.mgmt.lock().unwrap().add_bookmark(&name);
+            }
+        }
+    }
+
+    fn delete_bookmark(&mut self) {
+        // Delete the selected bookmark, after confirming it
+        if let State::Bookmarks { selection } = self.state() {
+            let selection = *selection;
+            let mgmt = self.mgmt.lock().unwrap();
+            let name = match mgmt.bookmarks().get(selection) {
+                Some((id, index)) => mgmt.database.tracks[id].bookmarks[*index].name.clone(),
+                None => return,
+            };
+            std::mem::drop(mgmt);
+            let warning = format!(
+                "WARNING: Are you sure you want '{}' to be deleted? (y/n): ",
+                name
+            );
+            if let Ok(Some(confirm)) = self.get_input(&warning) {
+                if confirm == "y" {
+                    self.mgmt.lock().unwrap().remove_bookmark(selection);
+                    self.selection_up();
+                }
+            }
+        }
+    }
+
+    fn add_playlist_pointers(&mut self, name: &str) {
+        // Start off the track pointers of a new playlist
+        self.states.iter_mut().for_each(|(_, s)| {
+            if let State::Playlists {
+                track, playlist: p, ..
+            } = s
+            {
+                track.insert(name.to_string(), 0);
+                if p.is_empty() {
+                    // Fix empty track pointer
+                    *p = name.to_string();
+                }
+            }
+        });
+    }
+
     fn delete_playlist(&mut self) {
         // Delete playlist
         if let State::Playlists {
@@ -450,6 +905,104 @@ impl Ui {
         }
     }
 
+    fn search_pane(&self) -> Option<u8> {
+        // Find the pane that search results are shown in
+        self.states
+            .iter()
+            .find(|(_, s)| s.is_search())
+            .map(|(ptr, _)| *ptr)
+    }
+
+    fn search(&mut self) {
+        // Switch to the search pane, then type in a query to search the library for
+        if let Some(ptr) = self.search_pane() {
+            self.ptr = ptr;
+            let (old, was_filter) = if let State::Search { query, filter, .. } = self.state() {
+                (query.clone(), *filter)
+            } else {
+                return;
+            };
+            // Filter expressions aren't carried over into fuzzy searches
+            let initial = if was_filter { "" } else { &old };
+            // Cancelling the search brings back the previous results
+            if !matches!(self.prompt("Search: ", initial, true), Ok(Some(_))) {
+                self.set_query(&old, was_filter);
+            }
+        }
+    }
+
+    fn filter(&mut self) {
+        // Show the tracks that pass a filter expression in the search pane
+        if let Some(ptr) = self.search_pane() {
+            // The query is kept by the search pane, so switch to it first
+            let previous = self.ptr;
+            self.ptr = ptr;
+            let applied = match self.get_input("Filter: ") {
+                Ok(Some(expression)) => self.set_query(&expression, true),
+                _ => false,
+            };
+            // Go back to where we were if the expression is invalid or cancelled
+            if !applied {
+                self.ptr = previous;
+            }
+        }
+    }
+
+    fn set_query(&mut self, new: &str, is_filter: bool) -> bool {
+        // Update the search query along with the tracks that match it, false if it is invalid
+        let mgmt = self.mgmt.lock().unwrap();
+        let found = if is_filter {
+            match mgmt.filter(new) {
+                Ok(found) => found,
+                Err(e) => {
+                    self.message = Some(format!("Invalid filter: {}", e));
+                    return false;
+                }
+            }
+        } else {
+            mgmt.search(new)
+        };
+        std::mem::drop(mgmt);
+        if let State::Search {
+            query,
+            results,
+            selection,
+            filter,
+        } = self.state_mut()
+        {
+            *query = new.to_string();
+            *results = found;
+            *selection = 0;
+            *filter = is_filter;
+        }
+        true
+    }
+
+    fn search_offset(&self) -> usize {
+        // Work out how far the search results are scrolled, to keep the selection on screen
+        let rows = self.size.height.saturating_sub(3) as usize;
+        self.state().get_selection().saturating_sub(rows)
+    }
+
+    fn rate(&mut self) {
+        // Give the selected track a star rating
+        if let Some(id) = self.get_selected_id() {
+            if let Ok(Some(rating)) = self.get_input("Rating (0-5): ") {
+                match rating.parse() {
+                    Ok(rating) if rating <= 5 => self.mgmt.lock().unwrap().set_rating(id, rating),
+                    _ => self.message = Some(format!("Invalid rating: {}", rating)),
+                }
+            }
+        }
+    }
+
+    fn queue(&mut self) {
+        // Queue the selected track to play after the current one
+        if let Some(id) = self.get_selected_id() {
+            self.mgmt.lock().unwrap().queue(id);
+        }
+    }
+
     fn remove_from_playlist(&mut self) {
         let mut fix_selection = false;
         if let State::Playlists {
@@ -461,10 +1014,13 @@ impl Ui {
             if playlist.is_empty() {
                 return;
             }
-            let length = self.mgmt.lock().unwrap().database.playlists[playlist].len();
-            if length == 0 {
+            let mgmt = self.mgmt.lock().unwrap();
+            // Smart playlists only change along with the library
+            let length = mgmt.database.playlists[playlist].len();
+            if length == 0 || mgmt.database.smart_playlists.contains_key(playlist) {
                 return;
             }
+            std::mem::drop(mgmt);
             // Differentiate between deleting playlists and deleting tracks from playlists
             if depth == &1 {
                 self.mgmt
@@ -494,12 +1050,26 @@ impl Ui {
                 depth: 1,
                 ..
             } => artist_tracks(&self.mgmt.lock().unwrap().library_tree, artist)[track[artist]],
+            State::Search {
+                results, selection, ..
+            } => *results.get(*selection)?,
+            State::History { selection } => {
+                let mgmt = self.mgmt.lock().unwrap();
+                let idx = mgmt.history.len().checked_sub(selection + 1)?;
+                let id = mgmt.history[idx].id;
+                // Tracks may have been removed from the library since they were played
+                if !mgmt.database.tracks.contains_key(&id) {
+                    return None;
+                }
+                id
+            }
             _ => return None,
         })
     }
 
     fn deepen(&mut self) {
         // Switch focus in the sorted library view
+        let feeds_len = self.mgmt.lock().unwrap().database.podcasts.len();
         match self.state_mut() {
             State::SortedLibrary { depth, .. } => {
                 if depth == &1 {
@@ -520,6 +1090,16 @@ impl Ui {
                     *depth += 1;
                 }
             }
+            State::Podcasts { depth, .. } => {
+                if feeds_len == 0 {
+                    return;
+                }
+                if depth == &1 {
+                    *depth = 0;
+                } else {
+                    *depth += 1;
+                }
+            }
             _ => (),
         }
     }
@@ -560,6 +1140,10 @@ impl Ui {
     }
 
     fn get_input(&mut self, prompt: &str) -> Result<Option<String>> {
+        self.prompt(prompt, "", false)
+    }
+
+    fn prompt(&mut self, prompt: &str, initial: &str, live: bool) -> Result<Option<String>> {
         // If too few rows, don't bother doing prompt
         if self.size.height < 3 {
             return Ok(None);
@@ -569,7 +1153,7 @@ impl Ui {
         self.size.height -= 1;
         self.render()?;
         // Get user input
-        let mut out = String::new();
+        let mut out = initial.to_string();
         let mut entering = true;
         while entering {
             execute!(
@@ -584,9 +1168,18 @@ impl Ui {
             if event::poll(std::time::Duration::from_millis(PULSE))? {
                 match event::read()? {
                     Event::Key(k) => match (k.modifiers, k.code) {
-                        (KMod::NONE | KMod::SHIFT, KCode::Char(c)) => out.push(c),
+                        (KMod::NONE | KMod::SHIFT, KCode::Char(c)) => {
+                            out.push(c);
+                            // Live prompts update the search results as they are typed
+                            if live {
+                                self.set_query(&out, false);
+                            }
+                        }
                         (KMod::NONE, KCode::Backspace) => {
                             let _ = out.pop();
+                            if live {
+                                self.set_query(&out, false);
+                            }
                         }
                         (KMod::NONE, KCode::Enter) => {
                             entering = false;
@@ -694,6 +1287,7 @@ impl Ui {
         }
     }
 
+    #[allow(clippy::too_many_lines)]
     fn select(&mut self) {
         // Play the selected track
         match self.state() {
@@ -729,6 +1323,27 @@ impl Ui {
                 self.play_ptr = self.ptr;
                 mgmt.play();
             }
+            State::Search {
+                results, selection, ..
+            } => {
+                // The search results become the playlist context
+                if !results.is_empty() {
+                    let (lookup, ptr) = (results.clone(), *selection);
+                    self.play_results(lookup, ptr);
+                }
+            }
+            State::Radio { .. } | State::Podcasts { .. } | State::Bookmarks { .. } => {
+                self.select_listed();
+            }
+            State::History { .. } => {
+                // Replay the track, without disturbing the playlist context
+                if let Some(id) = self.get_selected_id() {
+                    let mut mgmt = self.mgmt.lock().unwrap();
+                    mgmt.load(id);
+                    self.play_ptr = self.ptr;
+                    mgmt.play();
+                }
+            }
             State::Files {
                 selection,
                 list,
@@ -739,9 +1354,15 @@ impl Ui {
                 let file = &list[selection];
                 let dir = dir.to_owned() + "/" + file;
                 if is_file(&dir) {
-                    mgmt.add_library(Track::load(&dir));
-                    // Trigger library tree rerender
-                    self.library_updated = true;
+                    let tracks = Track::load_all(&dir);
+                    if tracks.is_empty() {
+                        self.message = Some(format!("Failed to import: {}", dir));
+                    }
+                    for track in tracks {
+                        mgmt.add_library(track);
+                        // Trigger library tree rerender
+                        self.library_updated = true;
+                    }
                 } else {
                     let list = list_dir(&dir, !mgmt.config.show_hidden_files);
                     *self.states.get_mut(&self.ptr).unwrap() = State::Files {
@@ -776,6 +1397,43 @@ impl Ui {
         }
     }
 
+    fn select_listed(&mut self) {
+        // Tune into the selected station, open the selected podcast or play the selected episode
+        // Bookmarks are jumped to, loading their track if need be
+        match self.state() {
+            State::Radio { selection } => {
+                self.mgmt.lock().unwrap().play_station(*selection);
+                self.play_ptr = self.ptr;
+            }
+            State::Bookmarks { selection } => {
+                self.mgmt.lock().unwrap().play_bookmark(*selection);
+                self.play_ptr = self.ptr;
+            }
+            State::Podcasts { depth: 0, .. } => self.deepen(),
+            _ => {
+                if let Some((feed, episode)) = self.selected_episode() {
+                    // Episodes are picked up from where they were left
+                    self.mgmt.lock().unwrap().play_episode(feed, episode);
+                    self.play_ptr = self.ptr;
+                }
+            }
+        }
+    }
+
+    fn play_results(&mut self, lookup: Vec<usize>, ptr: usize) {
+        // Play a list of tracks as the playlist context, starting from one of them
+        let mut mgmt = self.mgmt.lock().unwrap();
+        mgmt.playlist.name = None;
+        let tracks = lookup
+            .iter()
+            .map(|x| mgmt.database.tracks[x].clone())
+            .collect();
+        mgmt.load(lookup[ptr]);
+        mgmt.playlist.set(ptr, tracks, lookup);
+        self.play_ptr = self.ptr;
+        mgmt.play();
+    }
+
     fn track_up(&mut self) {
         // Move track upwards
         let mut mgmt = self.mgmt.lock().unwrap();
@@ -920,7 +1578,11 @@ impl Ui {
                     *selection -= 1
                 }
             }
-            State::Files { selection, .. } => {
+            State::Files { selection, .. }
+            | State::Search { selection, .. }
+            | State::History { selection }
+            | State::Radio { selection }
+            | State::Bookmarks { selection } => {
                 if *selection > 0 {
                     *selection -= 1
                 }
@@ -963,13 +1625,30 @@ impl Ui {
                     *track.get_mut(playlist).unwrap() = track[playlist].saturating_sub(1);
                 }
             }
+            State::Podcasts {
+                depth,
+                feed,
+                episode,
+            } => {
+                if *depth == 0 && *feed > 0 {
+                    *feed -= 1;
+                    *episode = 0;
+                } else if *depth == 1 && *episode > 0 {
+                    *episode -= 1;
+                }
+            }
             _ => (),
         }
     }
 
+    #[allow(clippy::too_many_lines)]
     fn selection_down(&mut self) {
         // Move the current selection down
         let tracks_len = self.mgmt.lock().unwrap().database.tracks.len();
+        let history_len = self.mgmt.lock().unwrap().history.len();
+        let stations_len = self.mgmt.lock().unwrap().database.stations.len();
+        let bookmarks_len = self.mgmt.lock().unwrap().bookmarks().len();
+        let podcast_lengths = self.podcast_lengths();
         let artists_len = self.mgmt.lock().unwrap().library_tree.len();
         // If in sorted library, get list of tracks and artists
         let (track_list, artist_list) = if let State::SortedLibrary { artist, .. } = self.state() {
@@ -1014,6 +1693,28 @@ impl Ui {
                     *selection += 1
                 }
             }
+            State::Search {
+                selection, results, ..
+            } => {
+                if *selection + 1 < results.len() {
+                    *selection += 1;
+                }
+            }
+            State::History { selection } => {
+                if *selection + 1 < history_len {
+                    *selection += 1;
+                }
+            }
+            State::Radio { selection } => {
+                if *selection + 1 < stations_len {
+                    *selection += 1;
+                }
+            }
+            State::Bookmarks { selection } => {
+                if *selection + 1 < bookmarks_len {
+                    *selection += 1;
+                }
+            }
             State::SortedLibrary {
                 artist,
                 track,
@@ -1051,6 +1752,19 @@ impl Ui {
                     *track.get_mut(playlist).unwrap() = track[playlist] + 1;
                 }
             }
+            State::Podcasts {
+                depth,
+                feed,
+                episode,
+            } => {
+                let (feeds, episodes) = podcast_lengths;
+                if *depth == 0 && *feed + 1 < feeds {
+                    *feed += 1;
+                    *episode = 0;
+                } else if *depth == 1 && *episode + 1 < episodes {
+                    *episode += 1;
+                }
+            }
             _ => (),
         }
     }
@@ -1081,7 +1795,11 @@ impl Ui {
                 *selection = 0;
                 *offset = 0;
             }
-            State::Files { selection, .. } => {
+            State::Files { selection, .. }
+            | State::Search { selection, .. }
+            | State::History { selection }
+            | State::Radio { selection }
+            | State::Bookmarks { selection } => {
                 *selection = 0;
             }
             State::SortedLibrary {
@@ -1115,13 +1833,28 @@ impl Ui {
                     *track.get_mut(playlist).unwrap() = 0;
                 }
             }
+            State::Podcasts {
+                depth,
+                feed,
+                episode,
+            } => {
+                if *depth == 0 {
+                    *feed = 0;
+                }
+                *episode = 0;
+            }
             _ => (),
         }
     }
 
+    #[allow(clippy::too_many_lines)]
     fn selection_bottom(&mut self) {
         // Move the selection to the top of the library
         let tracks_len = self.mgmt.lock().unwrap().database.tracks.len();
+        let history_len = self.mgmt.lock().unwrap().history.len();
+        let stations_len = self.mgmt.lock().unwrap().database.stations.len();
+        let bookmarks_len = self.mgmt.lock().unwrap().bookmarks().len();
+        let podcast_lengths = self.podcast_lengths();
         // If in sorted library, get list of tracks in artist
         let (track_list, artist_list) = if let State::SortedLibrary { artist, .. } = self.state() {
             let mgmt = self.mgmt.lock().unwrap();
@@ -1162,6 +1895,20 @@ impl Ui {
             } => {
                 *selection = list.len().saturating_sub(1);
             }
+            State::Search {
+                selection, results, ..
+            } => {
+                *selection = results.len().saturating_sub(1);
+            }
+            State::History { selection } => {
+                *selection = history_len.saturating_sub(1);
+            }
+            State::Radio { selection } => {
+                *selection = stations_len.saturating_sub(1);
+            }
+            State::Bookmarks { selection } => {
+                *selection = bookmarks_len.saturating_sub(1);
+            }
             State::SortedLibrary {
                 depth,
                 artist,
@@ -1192,17 +1939,74 @@ impl Ui {
                     *track.get_mut(playlist).unwrap() = tracks.saturating_sub(1);
                 }
             }
+            State::Podcasts {
+                depth,
+                feed,
+                episode,
+            } => {
+                let (feeds, episodes) = podcast_lengths;
+                if *depth == 0 {
+                    *feed = feeds.saturating_sub(1);
+                    *episode = 0;
+                } else {
+                    *episode = episodes.saturating_sub(1);
+                }
+            }
             _ => (),
         }
     }
 
     pub fn update_library(&mut self) {
+        // Pick up changes made to the library from elsewhere, such as watched folders
+        if std::mem::take(&mut self.mgmt.lock().unwrap().library_updated) {
+            self.library_updated = true;
+        }
+        if self.library_updated {
+            // Keep the selection within the library, in case tracks were removed from under it
+            let mgmt = self.mgmt.lock().unwrap();
+            let length = mgmt.database.display.simple.len();
+            for state in self.states.values_mut() {
+                match state {
+                    State::Library { selection, .. } => {
+                        *selection = (*selection).min(length.saturating_sub(1));
+                    }
+                    // Search again, so that results never point at removed tracks
+                    State::Search {
+                        query,
+                        results,
+                        selection,
+                        filter,
+                    } => {
+                        *results = if *filter {
+                            mgmt.filter(query).unwrap_or_default()
+                        } else {
+                            mgmt.search(query)
+                        };
+                        *selection = (*selection).min(results.len().saturating_sub(1));
+                    }
+                    // Smart playlists may have shrunk under their track pointers
+                    State::Playlists { track, .. } => {
+                        for (name, ptr) in track.iter_mut() {
+                            let length = mgmt.database.playlists.get(name).map_or(0, Vec::len);
+                            *ptr = (*ptr).min(length.saturating_sub(1));
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            std::mem::drop(mgmt);
+        }
         // Prevent rendering with outdated library tree
         if self.library_updated && self.state().is_sorted_library() {
             let mut mgmt = self.mgmt.lock().unwrap();
             let tracks = &mgmt.database.tracks;
             mgmt.library_tree = form_library_tree(tracks);
             let artists: Vec<String> = mgmt.library_tree.keys().map(|x| x.to_string()).collect();
+            let lengths: HashMap<String, usize> = mgmt
+                .library_tree
+                .iter()
+                .map(|(artist, albums)| (artist.clone(), albums.values().map(Vec::len).sum()))
+                .collect();
             std::mem::drop(mgmt);
             if let State::SortedLibrary {
                 track,
@@ -1216,6 +2020,10 @@ impl Ui {
                     }
                 }
                 track.drain_filter(|t, _| !artists.contains(t));
+                // Keep track selections within each artist's tracks
+                for (artist, ptr) in track.iter_mut() {
+                    *ptr = (*ptr).min(lengths[artist].saturating_sub(1));
+                }
                 if !artists.contains(&artist_ptr) {
                     *artist_ptr = artists
                         .get(0)
@@ -1233,18 +2041,32 @@ impl Ui {
         let mgmt = self.mgmt.lock().unwrap();
         // Update library tree if need be
         // Obtain render data for the current state
-        let ((keys, tracks), paths, artist_track, playlists): (
+        let ((keys, tracks), paths, artist_track, playlists, search, history, listing): (
             TrackList,
             FileList,
             SortedList,
             OptionList,
+            OptionList,
+            OptionList,
+            OptionList,
         ) = match self.state() {
             State::Library { offset, .. } => {
                 // Obtain list of tracks
                 let keys = mgmt.database.display.simple.clone();
                 let tracks: Vec<&Track> = keys.iter().map(|x| &mgmt.database.tracks[x]).collect();
-                let table = pad_table(format_table(&tracks, *offset), self.size.width as usize);
-                ((Some(keys), Some(table)), None, None, None)
+                let table = pad_table(
+                    format_table(&tracks, *offset, &mgmt.config.columns),
+                    self.size.width as usize,
+                );
+                (
+                    (Some(keys), Some(table)),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                )
             }
             State::SortedLibrary {
                 artist,
@@ -1265,12 +2087,12 @@ impl Ui {
                     id_playing,
                     self.ptr == self.play_ptr,
                 );
-                ((None, None), None, Some(table), None)
+                ((None, None), None, Some(table), None, None, None, None)
             }
             State::Files { dir, .. } => {
                 // Obtain list of files
                 let files = list_dir(dir, !mgmt.config.show_hidden_files);
-                ((None, None), Some(files), None, None)
+                ((None, None), Some(files), None, None, None, None, None)
             }
             State::Playlists {
                 playlist,
@@ -1278,22 +2100,104 @@ impl Ui {
                 depth,
                 ..
             } => {
+                // Configs from before smart playlists won't have an icon for them
+                let smart_icon = mgmt
+                    .config
+                    .indicators
+                    .get("smart_playlist_icon")
+                    .unwrap_or(&mgmt.config.indicators["playlist_icon"]);
                 let playlists = format_playlist(
                     &mgmt.database.playlists,
                     &mgmt.database.display.playlists,
                     *depth,
                     &mgmt.database.tracks,
                     (&playlist, track),
-                    mgmt.playlist.ptr,
+                    mgmt.playlist.context_ptr(),
                     &mgmt.playlist.name,
                     self.size.width,
-                    &mgmt.config.indicators["playlist_icon"],
+                    (&mgmt.config.indicators["playlist_icon"], smart_icon),
+                    &mgmt.database.smart_playlists,
+                    &mgmt.config.columns,
+                );
+                ((None, None), None, None, Some(playlists), None, None, None)
+            }
+            State::Search {
+                query,
+                results,
+                filter,
+                ..
+            } => {
+                // Obtain the results, scrolled so that the selection stays on screen
+                let tracks: Vec<&Track> =
+                    results.iter().map(|x| &mgmt.database.tracks[x]).collect();
+                let kind = if *filter { "Filter" } else { "Search" };
+                let mut lines = vec![format!("{}: {} ({} results)", kind, query, results.len())];
+                if tracks.is_empty() && query.is_empty() {
+                    // Print out placeholder
+                    lines.push("[press / to search the library]".to_string());
+                } else if tracks.is_empty() {
+                    lines.push("[no results]".to_string());
+                }
+                lines.extend(pad_table(
+                    format_table(&tracks, self.search_offset(), &mgmt.config.columns),
+                    self.size.width as usize,
+                ));
+                ((None, None), None, None, None, Some(lines), None, None)
+            }
+            State::History { selection } => {
+                let lines = format_history(
+                    &mgmt.history,
+                    &mgmt.database.tracks,
+                    *selection,
+                    self.size.height.saturating_sub(1) as usize,
+                    self.size.width as usize,
                 );
-                ((None, None), None, None, Some(playlists))
+                ((None, None), None, None, None, None, Some(lines), None)
             }
-            State::Empty => ((None, None), None, None, None),
+            State::Radio { selection } => {
+                let lines = format_stations(
+                    &mgmt.database.stations,
+                    *selection,
+                    mgmt.station,
+                    self.size.height.saturating_sub(1) as usize,
+                    self.size.width as usize,
+                );
+                ((None, None), None, None, None, None, None, Some(lines))
+            }
+            State::Podcasts {
+                depth,
+                feed,
+                episode,
+            } => {
+                let lines = format_podcasts(
+                    &mgmt.database.podcasts,
+                    *depth,
+                    (*feed, *episode),
+                    mgmt.episode,
+                    self.size.height.saturating_sub(1) as usize,
+                    self.size.width as usize,
+                );
+                ((None, None), None, None, None, None, None, Some(lines))
+            }
+            State::Bookmarks { selection } => {
+                let lines = format_bookmarks(
+                    &mgmt.database.tracks,
+                    &mgmt.bookmarks(),
+                    *selection,
+                    mgmt.bookmark(),
+                    self.size.height.saturating_sub(1) as usize,
+                    self.size.width as usize,
+                );
+                ((None, None), None, None, None, None, None, Some(lines))
+            }
+            State::Empty => ((None, None), None, None, None, None, None, None),
         };
         std::mem::drop(mgmt);
+        // Radio stations, podcasts and bookmarks are listed just as they were formatted
+        let listed = matches!(
+            self.state(),
+            State::Radio { .. } | State::Podcasts { .. } | State::Bookmarks { .. }
+        );
         // Do render
         for line in 0..self.size.height {
             // Go to line and clear it
@@ -1312,7 +2216,7 @@ impl Ui {
                 // Render library view
                 let selection = self.state().get_selection();
                 if let Some(row) = tracks.as_ref().unwrap().get(line as usize) {
-                    let is_selected = selection == line.into();
+                    let is_selected = selection == usize::from(line);
                     let this_id = keys
                         .as_ref()
                         .unwrap()
@@ -1342,7 +2246,7 @@ impl Ui {
                     // Add padding
                     let row = format!("{:<pad$}", row, pad = self.size.width as usize);
                     // Set up formatting for list
-                    if selection == line.into() {
+                    if selection == usize::from(line) {
                         queue!(self.stdout, SetBg(Color::DarkGrey))?;
                     }
                     queue!(self.stdout, Print(row))?;
@@ -1360,6 +2264,28 @@ impl Ui {
                 if let Some(row) = playlists.as_ref().unwrap().get(line as usize) {
                     queue!(self.stdout, Print(row))?;
                 }
+            } else if line != status_idx && self.state().is_history() {
+                if let Some(row) = history.as_ref().unwrap().get(line as usize) {
+                    queue!(self.stdout, Print(row))?;
+                }
+            } else if line != status_idx && listed {
+                if let Some(row) = listing.as_ref().unwrap().get(line as usize) {
+                    queue!(self.stdout, Print(row))?;
+                }
+            } else if line != status_idx && self.state().is_search() {
+                if let Some(row) = search.as_ref().unwrap().get(line as usize) {
+                    // The first line holds the query, with the results below it
+                    let selection = self.state().get_selection();
+                    let is_selected = line > 0
+                        && self.get_selected_id().is_some()
+                        && selection + 1 == self.search_offset() + line as usize;
+                    if is_selected {
+                        queue!(self.stdout, SetBg(Color::DarkGrey))?;
+                    }
+                    queue!(self.stdout, Print(row))?;
+                    // Reset formatting for next row
+                    queue!(self.stdout, SetBg(Color::Reset))?;
+                }
             } else if line == status_idx {
                 // Render status line
                 self.rerender_status()?;
@@ -1372,14 +2298,40 @@ impl Ui {
         // Render status line
         let mgmt = self.mgmt.lock().unwrap();
         // Form left hand side
-        let lhs = if let Some(current) = mgmt.playlist.current() {
-            let pb = mgmt.metadata.lock().unwrap().playback_status;
-            let icon = match pb {
+        let lhs = if let Some(message) = &self.message {
+            message.to_string()
+        } else if let Some(import) = &self.import {
+            let import = import.lock().unwrap();
+            format!("Importing: {}/{}", import.done, import.total)
+        } else if let Some((description, _)) = &self.task {
+            format!("{}...", description)
+        } else if mgmt.playlist.current().is_some()
+            || mgmt.station.is_some()
+            || mgmt.episode.is_some()
+        {
+            let md = mgmt.metadata.lock().unwrap();
+            let icon = match md.playback_status {
                 PlaybackStatus::Playing => &mgmt.config.indicators["playing"],
                 PlaybackStatus::Paused => &mgmt.config.indicators["paused"],
                 PlaybackStatus::Stopped => &mgmt.config.indicators["stopped"],
             };
-            format!("{}{} - {}", icon, current.tag.title, current.tag.artist)
+            if let Some(current) = mgmt.playlist.current() {
+                format!(
+                    "{}{} - {}{}",
+                    icon,
+                    current.tag.title,
+                    current.tag.artist,
+                    format_place(&mgmt)
+                )
+            } else if let (Some(station), Some(title)) = (&md.station, &md.stream_title) {
+                // Show what the radio station says it is playing
+                format!("{}{} - {}", icon, title, station)
+            } else if mgmt.episode.is_some() {
+                // Show the episode along with its podcast
+                format!("{}{} - {}", icon, md.tag.title, md.tag.artist)
+            } else {
+                format!("{}{}", icon, md.tag.title)
+            }
         } else {
             "No track loaded".to_string()
         };
@@ -1416,11 +2368,12 @@ impl Ui {
             mgmt.metadata.lock().unwrap().position
         };
         let rhs = format!(
-            "{}/{} {}% {} {} {}",
+            "{}/{} {}% {}{} {} {}",
             timefmt(position),
             timefmt(duration),
             volume,
             volume_icon,
+            format_gain(&mgmt),
             loop_icon,
             shuffle_icon
         );
@@ -1449,10 +2402,176 @@ impl Ui {
     }
 
     pub fn clean(&mut self) -> Result<()> {
-        // Clean up before leaving
-        self.mgmt.lock().unwrap().database.write();
+        // Clean up before leaving, noting down the unfinished listen
+        let mut mgmt = self.mgmt.lock().unwrap();
+        mgmt.record_listen(false);
+        mgmt.database.write();
+        // Keep what was playing and how the panes were left, to carry on from next time
+        let session = Session {
+            playback: Some(mgmt.playback()),
+            pane: Some(self.ptr),
+            panes: std::mem::take(&mut self.states),
+        };
+        session.write();
+        std::mem::drop(mgmt);
         execute!(self.stdout, terminal::LeaveAlternateScreen, cursor::Show)?;
         terminal::disable_raw_mode()?;
         Ok(())
     }
 }
+
+fn format_place(mgmt: &Manager) -> String {
+    // Show the chapter playing and the last bookmark passed, for audiobooks
+    let mut result = String::new();
+    if let Some((index, chapter)) = mgmt.chapter() {
+        let chapters = mgmt.current_track().map_or(0, |t| t.chapters.len());
+        result.push_str(&format!(
+            " | {} ({}/{})",
+            chapter.title,
+            index + 1,
+            chapters
+        ));
+    }
+    if let Some((id, index)) = mgmt.bookmark() {
+        // Configs from before bookmarks won't have an icon for them
+        let icon = mgmt.config.indicators.get("bookmark_icon");
+        let name = &mgmt.database.tracks[&id].bookmarks[index].name;
+        result.push_str(&format!(
+            " | {}{}",
+            icon.map_or("Bookmark: ", String::as_str),
+            name
+        ));
+    }
+    result
+}
+
+fn format_gain(mgmt: &Manager) -> String {
+    // Show the ReplayGain being applied, when it is turned on
+    if let Some(gain) = mgmt.gain() {
+        // Configs from before ReplayGain won't have an icon for it
+        let icon = mgmt.config.indicators.get("replay_gain_icon");
+        format!(" {}{:+.1} dB", icon.map_or("RG ", String::as_str), gain)
+    } else {
+        String::new()
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+fn restore_state(state: &mut State, saved: State, mgmt: &Manager, rows: usize) {
+    // Put a pane back how it was left, keeping its selections within what is there now
+    match (state, saved) {
+        (
+            State::Library { selection, offset },
+            State::Library {
+                selection: old_selection,
+                offset: old_offset,
+            },
+        ) => {
+            // Keep the same track selected, scrolling so that it is on the screen
+            let length = mgmt.database.display.simple.len();
+            let position = (old_selection + old_offset).min(length.saturating_sub(1));
+            *selection = old_selection.min(position).min(rows);
+            *offset = position - *selection;
+        }
+        (
+            State::Files {
+                selection,
+                dir,
+                list,
+            },
+            State::Files {
+                selection: old_selection,
+                dir: old_dir,
+                ..
+            },
+        ) => {
+            // Folders may have been moved or removed since
+            if std::fs::read_dir(&old_dir).is_ok() {
+                *list = list_dir(&old_dir, !mgmt.config.show_hidden_files);
+                *dir = old_dir;
+                *selection = old_selection.min(list.len().saturating_sub(1));
+            }
+        }
+        (
+            State::SortedLibrary {
+                depth,
+                artist,
+                track,
+            },
+            State::SortedLibrary {
+                depth: old_depth,
+                artist: old_artist,
+                track: old_track,
+            },
+        ) => {
+            for (name, ptr) in old_track {
+                if let Some(albums) = mgmt.library_tree.get(&name) {
+                    let length: usize = albums.values().map(Vec::len).sum();
+                    track.insert(name, ptr.min(length.saturating_sub(1)));
+                }
+            }
+            if mgmt.library_tree.contains_key(&old_artist) {
+                *artist = old_artist;
+                *depth = old_depth;
+            }
+        }
+        (
+            State::Playlists {
+                depth,
+                playlist,
+                track,
+            },
+            State::Playlists {
+                depth: old_depth,
+                playlist: old_playlist,
+                track: old_track,
+            },
+        ) => {
+            for (name, ptr) in old_track {
+                if let Some(ids) = mgmt.database.playlists.get(&name) {
+                    track.insert(name, ptr.min(ids.len().saturating_sub(1)));
+                }
+            }
+            if mgmt.database.playlists.contains_key(&old_playlist) {
+                *playlist = old_playlist;
+                *depth = old_depth;
+            }
+        }
+        (
+            State::Search {
+                query,
+                results,
+                selection,
+                filter,
+            },
+            State::Search {
+                query: old_query,
+                selection: old_selection,
+                filter: old_filter,
+                ..
+            },
+        ) => {
+            // Search again, as the library may have changed since
+            *results = if old_filter {
+                mgmt.filter(&old_query).unwrap_or_default()
+            } else {
+                mgmt.search(&old_query)
+            };
+            *selection = old_selection.min(results.len().saturating_sub(1));
+            *query = old_query;
+            *filter = old_filter;
+        }
+        (State::History { selection }, State::History { selection: old }) => {
+            *selection = old.min(mgmt.history.len().saturating_sub(1));
+        }
+        (State::Radio { selection }, State::Radio { selection: old }) => {
+            *selection = old.min(mgmt.database.stations.len().saturating_sub(1));
+        }
+        (State::Bookmarks { selection }, State::Bookmarks { selection: old }) => {
+            *selection = old.min(mgmt.bookmarks().len().saturating_sub(1));
+        }
+        // Podcast selections are kept within the podcasts once the panes are set up
+        (state @ State::Podcasts { .. }, saved @ State::Podcasts { .. }) => *state = saved,
+        _ => (),
+    }
+}
@@ -1,9 +1,17 @@
 // util.rs - common utilities for helping out around the project
+use crate::config::Station;
+use crate::history::Entry;
+use crate::podcast::Feed;
 use crate::track::Track;
 use crate::ui::{Color, SetBg, SetFg};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use unicode_width::UnicodeWidthStr;
 
+// File extensions of audio formats that can be imported
+pub const AUDIO_EXTENSIONS: [&str; 13] = [
+    "mp3", "flac", "ogg", "oga", "opus", "m4a", "m4b", "mp4", "aac", "wav", "aiff", "ape", "wv",
+];
+
 // Help text
 pub const HELP: &str = "Synchron:
     About:
@@ -17,11 +25,14 @@ pub const HELP: &str = "Synchron:
         -V, --version : Prints the version installed.
         -c, --cli     : Enters into CLI mode which displays a prompt that waits
                         for commands to be entered.
+        [files]       : Files, folders and playlist files to play straight away.
     Examples:
         synchron -h   : Show help message and exit.
         synchron -V   : Show version and exit.
         synchron      : Opens in the default TUI mode.
-        synchron -c   : Opens in CLI mode and awaits for your instructions.";
+        synchron -c   : Opens in CLI mode and awaits for your instructions.
+        synchron song.mp3 album/ list.m3u
+                      : Opens in the default TUI mode, playing the files given.";
 
 // Utility macro for easy dbus property addition
 #[macro_export]
@@ -46,6 +57,20 @@ pub fn expand_path(path: &str) -> Option<String> {
     full_path.into_os_string().into_string().ok()
 }
 
+pub fn data_path(name: &str) -> Option<String> {
+    // Find where a data file is kept, next to the database
+    let path_base = expand_path("~/.local/share")?;
+    std::fs::create_dir_all(format!("{}/synchron/", path_base)).ok();
+    Some(format!("{}/synchron/{}", path_base, name))
+}
+
+pub fn write_atomic(path: &str, contents: &str) -> std::io::Result<()> {
+    // Write to a temporary file first, so a crash can't leave half a file behind
+    let temporary = format!("{}.tmp", path);
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)
+}
+
 pub fn attempt_open(path: &str) -> Option<String> {
     // Attempt to open a file from an unstandardised path
     let path = expand_path(path)?;
@@ -72,43 +97,33 @@ pub fn pad_table(table: Vec<Vec<String>>, limit: usize) -> Vec<String> {
     if length + inner < limit {
         // Columns will fit, distribute spacing between them
         let total = limit - length;
-        let gaps = if inner == 0 {
-            [0, 0, 0]
+        let gaps: Vec<usize> = if inner == 0 {
+            vec![]
         } else {
             let gap = total / inner;
-            let mut left_over = total % inner;
-            let mut gaps = [gap, gap, gap];
-            for i in gaps.iter_mut().take(2) {
-                if left_over != 0 {
-                    *i += 1;
-                    left_over -= 1;
-                }
-            }
-            gaps
+            let left_over = total % inner;
+            (0..inner)
+                .map(|i| if i < left_over { gap + 1 } else { gap })
+                .collect()
         };
         // Format columns into strings
         for record in table {
             let mut row = String::new();
-            for i in 0..4 {
-                if record.len() > i {
-                    row.push_str(&record[i]);
-                    if record.len() > i + 1 {
-                        row.push_str(&" ".repeat(gaps[i]));
-                    }
+            for (i, cell) in record.iter().enumerate() {
+                row.push_str(cell);
+                if let Some(gap) = gaps.get(i) {
+                    row.push_str(&" ".repeat(*gap));
                 }
             }
-            if record.len() > 4 {
-                row.push_str(&record[4]);
-            }
             result.push(row);
         }
     } else {
         // Recalculate padding with new column amount (rely on recursion)
         result = match table[0].len() {
-            4 | 2 => pad_table(remove_column(table, 1), limit),
-            3 => pad_table(remove_column(table, 2), limit),
+            0 => vec![],
             1 => (0..table.len()).map(|_| "...".to_string()).collect(),
-            _ => vec![],
+            3 => pad_table(remove_column(table, 2), limit),
+            _ => pad_table(remove_column(table, 1), limit),
         }
     }
     result
@@ -122,30 +137,26 @@ pub fn remove_column(mut table: Vec<Vec<String>>, column: usize) -> Vec<Vec<Stri
     table
 }
 
-pub fn format_table(tracks: &[&Track], offset: usize) -> Vec<Vec<String>> {
+pub fn format_table(tracks: &[&Track], offset: usize, columns: &[String]) -> Vec<Vec<String>> {
     // Format a list of tracks into a table
     let mut result = vec![];
-    let tracks: Vec<(String, &String, &String, &String, &String)> =
-        tracks.iter().map(|x| x.format_elements()).collect();
-    // Sort into columns
-    let columns: Vec<Vec<&String>> = vec![
-        tracks.iter().map(|x| x.1).collect(),
-        tracks.iter().map(|x| x.2).collect(),
-        tracks.iter().map(|x| x.3).collect(),
-        tracks.iter().map(|x| x.4).collect(),
-    ];
+    let rows: Vec<Vec<String>> = tracks
+        .iter()
+        .map(|x| columns.iter().map(|c| x.column(c)).collect())
+        .collect();
     // Find the longest item in each column
     let mut limits = vec![];
-    for column in &columns {
-        limits.push(find_longest(column));
+    for i in 0..columns.len() {
+        let column: Vec<&String> = rows.iter().map(|x| &x[i]).collect();
+        limits.push(find_longest(&column));
     }
     // Reform back into rows, taking into account the maximum column size
-    for i in offset..tracks.len() {
-        let mut row = vec![];
-        row.push(align_left(columns[0][i], limits[0]));
-        row.push(align_left(columns[1][i], limits[1]));
-        row.push(align_left(columns[2][i], limits[2]));
-        row.push(align_left(columns[3][i], limits[3]));
+    for record in rows.iter().skip(offset) {
+        let row = record
+            .iter()
+            .zip(&limits)
+            .map(|(value, limit)| align_left(value, *limit))
+            .collect();
         result.push(row);
     }
     result
@@ -194,10 +205,83 @@ pub fn timefmt(duration: u64) -> String {
     format!("{}:{:02}", minutes, seconds)
 }
 
+pub fn datefmt(time: u64) -> String {
+    // Format a time in seconds since the unix epoch as a date (YYYY-MM-DD, in UTC)
+    let days = i64::try_from(time / 86400).unwrap_or(0) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{}-{:02}-{:02}", year, month, day)
+}
+
+pub fn clockfmt(time: u64) -> String {
+    // Format a time in seconds since the unix epoch as a time of day (HH:MM, in UTC)
+    format!("{:02}:{:02}", time % 86400 / 3600, time % 3600 / 60)
+}
+
+pub fn now() -> u64 {
+    // Get the current time, in seconds since the unix epoch
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
 pub fn is_file(path: &str) -> bool {
     std::path::Path::new(path).is_file()
 }
 
+pub fn is_audio(path: &str) -> bool {
+    // Check if a file looks like a supported audio format, from its extension
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or_default()
+        .to_lowercase();
+    AUDIO_EXTENSIONS.contains(&extension.as_str())
+}
+
+pub fn find_audio(path: &str) -> Vec<String> {
+    // Find all the audio files within a folder and its subfolders
+    let mut result = vec![];
+    let mut folders = vec![];
+    if let Some(path) = expand_path(path) {
+        if is_file(&path) {
+            // Files that are asked for directly are always included
+            return vec![path];
+        }
+        folders.push(path);
+    }
+    // Symlinks can lead back into a folder already searched, so each one is only searched once
+    let mut visited = HashSet::new();
+    while let Some(folder) = folders.pop() {
+        let canonical = std::fs::canonicalize(&folder).unwrap_or_else(|_| folder.clone().into());
+        if !visited.insert(canonical) {
+            continue;
+        }
+        // Unreadable folders are skipped over
+        for entry in std::fs::read_dir(&folder).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = format!("{}/{}", folder, name);
+            if entry.path().is_dir() {
+                folders.push(path);
+            } else if is_audio(&path) {
+                result.push(path);
+            }
+        }
+    }
+    result.sort();
+    result
+}
+
 pub fn list_dir(path: &str, no_hidden: bool) -> Vec<String> {
     let mut files: Vec<String> = std::fs::read_dir(path)
         .unwrap()
@@ -215,7 +299,9 @@ pub fn form_library_tree(
     // Create a library tree from a list of tracks
     let mut result: BTreeMap<String, BTreeMap<String, Vec<usize>>> = BTreeMap::new();
     for (id, track) in tracks {
-        if let Some(albums) = result.get_mut(&track.tag.artist) {
+        // Albums are kept together under their album artist where there is one
+        let artist = track.tag.album_artist.as_ref().unwrap_or(&track.tag.artist);
+        if let Some(albums) = result.get_mut(artist) {
             if let Some(tracks) = albums.get_mut(&track.tag.album) {
                 // Add it to existing entry if known
                 tracks.push(*id);
@@ -225,13 +311,22 @@ pub fn form_library_tree(
             }
         } else {
             // Create new key value pair
-            result.insert(track.tag.artist.clone(), BTreeMap::new());
+            result.insert(artist.clone(), BTreeMap::new());
             result
-                .get_mut(&track.tag.artist)
+                .get_mut(artist)
                 .unwrap()
                 .insert(track.tag.album.clone(), vec![*id]);
         }
     }
+    // Order the tracks on each album by disc, then track number
+    for albums in result.values_mut() {
+        for album in albums.values_mut() {
+            album.sort_by_key(|id| {
+                let tag = &tracks[id].tag;
+                (tag.disc_number, tag.track_number, tag.title.clone(), *id)
+            });
+        }
+    }
     result
 }
 
@@ -371,11 +466,15 @@ pub fn format_playlist(
     up_ptr: Option<usize>,
     playing_playlist: &Option<String>,
     width: u16,
-    icon: &str,
+    icons: (&str, &str),
+    smart: &HashMap<String, String>,
+    columns: &[String],
 ) -> Vec<String> {
     let (selection, track_ptr) = selection;
     let mut result = vec![];
     let longest = find_longest_no_ref(display);
+    // Smart playlists are told apart by their icon
+    let icon_width = icons.0.width().max(icons.1.width());
     if playlist.is_empty() {
         return vec![
             "No playlists have been created yet".to_string(),
@@ -385,6 +484,11 @@ pub fn format_playlist(
     let this = &playlist[selection];
     // Format lhs
     for name in display.iter() {
+        let icon = if smart.contains_key(name) {
+            align_left(icons.1, icon_width)
+        } else {
+            align_left(icons.0, icon_width)
+        };
         if name == selection && focus == 0 {
             result.push(format!(
                 "{}{} {}{} {} {} {}│{}",
@@ -421,7 +525,7 @@ pub fn format_playlist(
         for _ in 0..left {
             result.push(format!(
                 "{} {}│{}",
-                " ".repeat(longest + icon.width() + 3),
+                " ".repeat(longest + icon_width + 3),
                 SetFg(Color::DarkBlue),
                 SetFg(Color::Reset)
             ));
@@ -430,8 +534,8 @@ pub fn format_playlist(
     // Generate rhs table
     let tracks: Vec<&Track> = this.iter().map(|x| &lookup[x]).collect();
     let table = pad_table(
-        format_table(&tracks, 0), // NOTE: CHANGE OFFSET HERE WHEN IMPLEMENTING IN FUTURE
-        (width as usize).saturating_sub(longest + icon.width() + 6),
+        format_table(&tracks, 0, columns), // NOTE: CHANGE OFFSET HERE WHEN IMPLEMENTING IN FUTURE
+        (width as usize).saturating_sub(longest + icon_width + 6),
     );
     // Format rhs
     for c in 0..std::cmp::max(result.len(), this.len()) {
@@ -458,3 +562,547 @@ pub fn format_playlist(
     }
     result
 }
+
+pub fn fuzzy_score(query: &str, text: &str) -> Option<usize> {
+    // Score how well a word matches some text, None if it doesn't match at all
+    let query = query.to_lowercase();
+    let text = text.to_lowercase();
+    // Exact matches come first, then the text starting with the word
+    if text == query {
+        return Some(3000);
+    }
+    if text.starts_with(&query) {
+        return Some(2000);
+    }
+    if let Some(idx) = text.find(&query) {
+        // Whole substrings beat scattered letters, especially at the start of a word
+        let word_start = idx == 0 || !text[..idx].ends_with(char::is_alphanumeric);
+        return Some(1000 + if word_start { 500 } else { 0 } - idx.min(100));
+    }
+    // Otherwise every letter has to appear in order, with runs of letters scoring higher
+    let mut score = 0;
+    let mut run = 0;
+    let mut chars = text.chars();
+    for q in query.chars() {
+        let mut skipped = false;
+        loop {
+            let c = chars.next()?;
+            if c == q {
+                break;
+            }
+            skipped = true;
+        }
+        run = if skipped { 1 } else { run + 1 };
+        score += run;
+    }
+    Some(score)
+}
+
+pub fn search(tracks: &HashMap<usize, Track>, query: &str) -> Vec<usize> {
+    // Find the tracks matching a query, best matches first
+    let words: Vec<&str> = query.split_whitespace().collect();
+    if words.is_empty() {
+        return vec![];
+    }
+    let mut results: Vec<(usize, usize)> = tracks
+        .iter()
+        .filter_map(|(id, track)| {
+            // Titles are weighted above artists, albums and then file names
+            // Folders are left out, as long paths would match almost anything
+            let name = track.path.rsplit('/').next().unwrap_or_default();
+            let fields = [
+                (track.tag.title.as_str(), 4),
+                (track.tag.artist.as_str(), 3),
+                (track.tag.album.as_str(), 2),
+                (name, 1),
+            ];
+            let mut total = 0;
+            // Every word has to match at least one of the fields
+            for word in &words {
+                total += fields
+                    .iter()
+                    .filter_map(|(field, weight)| Some(fuzzy_score(word, field)? * weight))
+                    .max()?;
+            }
+            Some((*id, total))
+        })
+        .collect();
+    results.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    results.into_iter().map(|(id, _)| id).collect()
+}
+
+pub fn format_history_entry(entry: &Entry, lookup: &HashMap<usize, Track>) -> String {
+    // Format a listen as its time, a tick if it was completed, the track and time listened
+    let name = lookup.get(&entry.id).map_or_else(
+        || "[removed track]".to_string(),
+        |t| format!("{} - {}", t.tag.title, t.tag.artist),
+    );
+    format!(
+        "{} {} {} ({})",
+        clockfmt(entry.started),
+        if entry.completed { "✓" } else { " " },
+        name,
+        timefmt(entry.listened)
+    )
+}
+
+pub fn format_history(
+    history: &[Entry],
+    lookup: &HashMap<usize, Track>,
+    selection: usize,
+    rows: usize,
+    width: usize,
+) -> Vec<String> {
+    // Format the history newest first and grouped by day, scrolled to keep the selection in view
+    let mut result = vec![];
+    let mut selected = 0;
+    let mut day = String::new();
+    for (c, entry) in history.iter().rev().enumerate() {
+        let date = datefmt(entry.started);
+        if date != day {
+            result.push(format!(
+                "{}{}{}",
+                SetFg(Color::DarkBlue),
+                date,
+                SetFg(Color::Reset)
+            ));
+            day = date;
+        }
+        let row = align_left(&format!(" {}", format_history_entry(entry, lookup)), width);
+        if c == selection {
+            selected = result.len();
+            result.push(format!(
+                "{}{}{}",
+                SetBg(Color::DarkGrey),
+                row,
+                SetBg(Color::Reset)
+            ));
+        } else {
+            result.push(row);
+        }
+    }
+    if result.is_empty() {
+        return vec!["[nothing has been played yet]".to_string()];
+    }
+    let offset = selected.saturating_sub(rows.saturating_sub(1));
+    result.into_iter().skip(offset).collect()
+}
+
+pub fn format_stations(
+    stations: &[Station],
+    selection: usize,
+    playing: Option<usize>,
+    rows: usize,
+    width: usize,
+) -> Vec<String> {
+    // Format the radio stations with their URLs, scrolled to keep the selection in view
+    if stations.is_empty() {
+        return vec!["[no stations, press n to add one]".to_string()];
+    }
+    let mut result = vec![];
+    for (i, station) in stations.iter().enumerate() {
+        result.push(format_row(
+            &station.name,
+            &station.url,
+            i == selection,
+            playing == Some(i),
+            width,
+        ));
+    }
+    let offset = selection.saturating_sub(rows.saturating_sub(1));
+    result.into_iter().skip(offset).collect()
+}
+
+pub fn format_bookmarks(
+    tracks: &HashMap<usize, Track>,
+    bookmarks: &[(usize, usize)],
+    selection: usize,
+    playing: Option<(usize, usize)>,
+    rows: usize,
+    width: usize,
+) -> Vec<String> {
+    // Format every bookmark with its track and position, scrolled to keep the selection in view
+    if bookmarks.is_empty() {
+        return vec!["[no bookmarks, press b to add one while playing]".to_string()];
+    }
+    let mut result = vec![];
+    for (i, (id, index)) in bookmarks.iter().enumerate() {
+        let track = &tracks[id];
+        let bookmark = &track.bookmarks[*index];
+        let name = format!(
+            "{} ({} - {})",
+            bookmark.name, track.tag.title, track.tag.artist
+        );
+        result.push(format_row(
+            &name,
+            &timefmt(bookmark.position),
+            i == selection,
+            playing == Some((*id, *index)),
+            width,
+        ));
+    }
+    let offset = selection.saturating_sub(rows.saturating_sub(1));
+    result.into_iter().skip(offset).collect()
+}
+
+pub fn format_podcasts(
+    feeds: &[Feed],
+    depth: u8,
+    selection: (usize, usize),
+    playing: Option<(usize, usize)>,
+    rows: usize,
+    width: usize,
+) -> Vec<String> {
+    // Format the podcasts, or the episodes of the selected one, keeping the selection in view
+    let (feed, episode) = selection;
+    if feeds.is_empty() {
+        return vec!["[no podcasts, press n to add one]".to_string()];
+    }
+    if let (1, Some(podcast)) = (depth, feeds.get(feed)) {
+        let playing = playing.filter(|p| p.0 == feed).map(|p| p.1);
+        return format_episodes(podcast, episode, playing, rows, width);
+    }
+    let mut result = vec![];
+    for (i, podcast) in feeds.iter().enumerate() {
+        let unplayed = format!("{} unplayed", podcast.unplayed());
+        let is_playing = playing.map(|p| p.0) == Some(i);
+        result.push(format_row(
+            &podcast.title,
+            &unplayed,
+            i == feed,
+            is_playing,
+            width,
+        ));
+    }
+    let offset = feed.saturating_sub(rows.saturating_sub(1));
+    result.into_iter().skip(offset).collect()
+}
+
+fn format_episodes(
+    podcast: &Feed,
+    selection: usize,
+    playing: Option<usize>,
+    rows: usize,
+    width: usize,
+) -> Vec<String> {
+    // Format the episodes of a podcast below its title
+    let mut result = vec![];
+    for (i, item) in podcast.episodes.iter().enumerate() {
+        // Episodes are marked as new, started or played
+        let marker = if item.played {
+            "  "
+        } else if item.position > 0 {
+            "◐ "
+        } else {
+            "● "
+        };
+        let mut info = String::new();
+        if item.download.is_some() {
+            info.push_str("↓ ");
+        }
+        match item.duration {
+            Some(duration) if item.position > 0 && !item.played => info.push_str(&format!(
+                "{}/{} ",
+                timefmt(item.position),
+                timefmt(duration)
+            )),
+            Some(duration) => info.push_str(&format!("{} ", timefmt(duration))),
+            None if item.position > 0 && !item.played => {
+                info.push_str(&format!("{} ", timefmt(item.position)));
+            }
+            None => (),
+        }
+        if let Some(published) = item.published {
+            info.push_str(&datefmt(published));
+        }
+        let title = format!("{}{}", marker, item.title);
+        result.push(format_row(
+            &title,
+            info.trim_end(),
+            i == selection,
+            playing == Some(i),
+            width,
+        ));
+    }
+    if result.is_empty() {
+        result.push("[no episodes]".to_string());
+    }
+    // The podcast's title stays at the top, above its episodes
+    let offset = selection.saturating_sub(rows.saturating_sub(2));
+    let mut lines = vec![format!(
+        "{}{}{}",
+        SetFg(Color::DarkBlue),
+        align_left(&podcast.title, width),
+        SetFg(Color::Reset)
+    )];
+    lines.extend(result.into_iter().skip(offset));
+    lines
+}
+
+fn format_row(lhs: &str, rhs: &str, selected: bool, playing: bool, width: usize) -> String {
+    // Format a row with text on either side, highlighting the selection and what's playing
+    let space = align_sides(lhs, rhs, width, 1).max(1);
+    let row = format!("{}{}{}", lhs, " ".repeat(space), rhs);
+    let row = align_left(&row, width);
+    let bg = if selected {
+        Color::DarkGrey
+    } else {
+        Color::Reset
+    };
+    let fg = if playing { Color::Green } else { Color::Reset };
+    format!(
+        "{}{}{}{}{}",
+        SetBg(bg),
+        SetFg(fg),
+        row,
+        SetBg(Color::Reset),
+        SetFg(Color::Reset)
+    )
+}
+
+pub fn uri_to_path(uri: &str) -> Option<String> {
+    // Turn a file URI or plain path into a path, turning away streams and other remote URIs
+    if let Some(path) = uri.strip_prefix("file://") {
+        Some(percent_decode(path))
+    } else if uri.contains("://") {
+        None
+    } else {
+        Some(uri.to_string())
+    }
+}
+
+pub fn percent_encode(path: &str) -> String {
+    // Encode a path for use in a URI, leaving the slashes between folders alone
+    let mut result = String::new();
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            result.push(char::from(byte));
+        } else {
+            result.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    result
+}
+
+pub fn percent_decode(uri: &str) -> String {
+    // Decode the escaped bytes in a URI
+    let bytes = uri.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = uri
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                result.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                result.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).to_string()
+}
+
+pub fn xml_elements<'a>(xml: &'a str, name: &str) -> Vec<(&'a str, &'a str)> {
+    // Find every element with this name, as the attributes of its opening tag and its contents
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut result = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        // Skip over elements whose names only start with this one
+        if !rest.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()) {
+            continue;
+        }
+        let end = rest.find('>').unwrap_or(rest.len());
+        let attributes = &rest[..end];
+        rest = rest.get(end + 1..).unwrap_or("");
+        if let Some(attributes) = attributes.strip_suffix('/') {
+            result.push((attributes, ""));
+        } else {
+            let end = rest.find(&close).unwrap_or(rest.len());
+            result.push((attributes, &rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    result
+}
+
+pub fn xml_text(xml: &str, name: &str) -> Option<String> {
+    // Find the text inside the first element with this name
+    let (_, contents) = xml_elements(xml, name).into_iter().next()?;
+    let contents = contents.trim();
+    let cdata = contents
+        .strip_prefix("<![CDATA[")
+        .and_then(|c| c.strip_suffix("]]>"));
+    Some(match cdata {
+        Some(text) => text.trim().to_string(),
+        None => xml_unescape(contents),
+    })
+}
+
+pub fn xml_attribute(attributes: &str, name: &str) -> Option<String> {
+    // Find the value of an attribute in an opening tag, in either kind of quotes
+    let mut rest = attributes;
+    while let Some(start) = rest.find(name) {
+        let whole = matches!(rest[..start].chars().next_back(), Some(c) if c.is_whitespace());
+        rest = &rest[start + name.len()..];
+        if let (true, Some(value)) = (whole, rest.trim_start().strip_prefix('=')) {
+            let value = value.trim_start();
+            if let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') {
+                let value = &value[1..];
+                let end = value.find(quote)?;
+                return Some(xml_unescape(&value[..end]));
+            }
+        }
+    }
+    None
+}
+
+pub fn xml_escape(text: &str) -> String {
+    // Escape the characters that have a meaning in XML
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub fn xml_unescape(text: &str) -> String {
+    // Turn XML entities back into the characters they stand for, including numbered ones
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(';').unwrap_or(0);
+        let entity = &rest[1..end.max(1)];
+        let character = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "amp" => Some('&'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        if let Some(character) = character {
+            result.push(character);
+            rest = &rest[end + 1..];
+        } else {
+            // Leave stray ampersands as they are
+            result.push('&');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elements() {
+        let xml = "<list><item a=\"1\">One</item><items>Not one</items>\
+            <item/><item b='2' /><item\n c=\"3\">Three <b>bold</b></item></list>";
+        assert_eq!(
+            xml_elements(xml, "item"),
+            [
+                (" a=\"1\"", "One"),
+                ("", ""),
+                (" b='2' ", ""),
+                ("\n c=\"3\"", "Three <b>bold</b>"),
+            ]
+        );
+        assert!(xml_elements(xml, "missing").is_empty());
+        // Unclosed elements run to the end
+        assert_eq!(xml_elements("<a>open", "a"), [("", "open")]);
+    }
+
+    #[test]
+    fn text() {
+        let tests = [
+            ("<t>Plain</t>", Some("Plain")),
+            ("<t>\n  Spaced out\n</t>", Some("Spaced out")),
+            (
+                "<t>Q&amp;A &lt;1&gt; &quot;x&quot; &apos;y&apos;</t>",
+                Some("Q&A <1> \"x\" 'y'"),
+            ),
+            (
+                "<t>&#8211; &#x201C;&#X201D;</t>",
+                Some("\u{2013} \u{201c}\u{201d}"),
+            ),
+            (
+                "<t>AT&T &bogus; &#xZZ; & done</t>",
+                Some("AT&T &bogus; &#xZZ; & done"),
+            ),
+            ("<t><![CDATA[Tom & <Jerry>]]></t>", Some("Tom & <Jerry>")),
+            ("<t> <![CDATA[ &amp; ]]> </t>", Some("&amp;")),
+            ("<t/>", Some("")),
+            ("<t>First</t><t>Second</t>", Some("First")),
+            ("<title>Not t</title>", None),
+        ];
+        for (xml, expected) in tests {
+            assert_eq!(xml_text(xml, "t").as_deref(), expected, "{}", xml);
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        let attributes =
+            " url=\"https://a/b?x=1&amp;y=2\" type='audio/mpeg' data-url=\"no\" length = \"5\"";
+        let tests = [
+            ("url", Some("https://a/b?x=1&y=2")),
+            ("type", Some("audio/mpeg")),
+            ("length", Some("5")),
+            ("data-url", Some("no")),
+            ("rl", None),
+            ("missing", None),
+        ];
+        for (name, expected) in tests {
+            assert_eq!(
+                xml_attribute(attributes, name).as_deref(),
+                expected,
+                "{}",
+                name
+            );
+        }
+        // Attributes without quotes aren't valid XML
+        assert_eq!(xml_attribute(" a=b", "a"), None);
+    }
+
+    #[test]
+    fn escaping() {
+        let text = "Tom & Jerry's \"<Show>\"";
+        assert_eq!(
+            xml_escape(text),
+            "Tom &amp; Jerry&apos;s &quot;&lt;Show&gt;&quot;"
+        );
+        assert_eq!(xml_unescape(&xml_escape(text)), text);
+        assert_eq!(xml_unescape("&amp;amp;"), "&amp;");
+    }
+
+    #[test]
+    fn atomic_writes() {
+        // Files are replaced whole, and the temporary file doesn't stay behind
+        let path = std::env::temp_dir().join(format!("synchron-atomic-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_atomic(path, "first").unwrap();
+        write_atomic(path, "second").unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "second");
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+// watch.rs - keeping the library in sync with the folders it is kept in
+use crate::audio::Manager;
+use crate::track::{Range, Track};
+use crate::util::{expand_path, find_audio, form_library_tree};
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind};
+use notify::{RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+// Time to wait for a burst of changes to settle before rescanning
+const SETTLE: u64 = 1000;
+
+// A track whose file has disappeared, with what to recognise it by if the file turns up elsewhere
+type Missing = (usize, Option<(u64, u32)>, Option<Range>);
+
+pub fn spawn(m: &Arc<Mutex<Manager>>) {
+    // Scan the watched folders, then keep watching them for changes
+    let roots: Vec<String> = m
+        .lock()
+        .unwrap()
+        .config
+        .library_roots
+        .iter()
+        .filter_map(|r| expand_path(r))
+        .collect();
+    if roots.is_empty() {
+        return;
+    }
+    let m = m.clone();
+    std::thread::spawn(move || {
+        sync(&m, &roots);
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = match notify::recommended_watcher(tx) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                println!("ERROR: Failed to watch library folders: {}", e);
+                return;
+            }
+        };
+        for root in &roots {
+            if let Err(e) = watcher.watch(Path::new(root), RecursiveMode::Recursive) {
+                println!("ERROR: Failed to watch {}: {}", root, e);
+            }
+        }
+        // Gather up bursts of changes, and rescan once they have settled
+        while let Ok(event) = rx.recv() {
+            let mut changed = is_change(&event);
+            while let Ok(event) = rx.recv_timeout(Duration::from_millis(SETTLE)) {
+                changed |= is_change(&event);
+            }
+            if changed {
+                sync(&m, &roots);
+            }
+        }
+    });
+}
+
+fn is_change(event: &notify::Result<notify::Event>) -> bool {
+    // Files that have finished being written, been moved or been deleted
+    matches!(
+        event.as_ref().map(|e| e.kind),
+        Ok(EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Name(_))
+            | EventKind::Remove(_))
+    )
+}
+
+pub fn sync(m: &Arc<Mutex<Manager>>, roots: &[String]) {
+    // Bring the library in line with the files in the watched folders
+    let files: Vec<String> = roots.iter().flat_map(|r| find_audio(r)).collect();
+    let mgmt = m.lock().unwrap();
+    let known: HashSet<String> = mgmt
+        .database
+        .tracks
+        .values()
+        .map(|t| Track::format_path(&t.path))
+        .collect();
+    // Find tracks in the watched folders whose files have disappeared
+    let mut missing: Vec<Missing> = mgmt
+        .database
+        .tracks
+        .iter()
+        .filter(|(_, t)| {
+            let path = Track::format_path(&t.path);
+            let watched = roots.iter().any(|r| path.starts_with(&format!("{}/", r)));
+            watched && !Path::new(&path).exists()
+        })
+        .map(|(id, t)| (*id, t.fingerprint, t.range))
+        .collect();
+    std::mem::drop(mgmt);
+    let mut changed = !missing.is_empty();
+    // Only hold onto the manager while adding, so that it is free while reading tags
+    for file in files.iter().filter(|f| !known.contains(*f)) {
+        // Files with a cue sheet hold several tracks, each recognised by its part of the file
+        for track in Track::load_all(file) {
+            let mut mgmt = m.lock().unwrap();
+            let moved = missing
+                .iter()
+                .position(|(_, f, r)| f.is_some() && *f == track.fingerprint && *r == track.range);
+            if let Some(idx) = moved {
+                // Moved files keep their place in the library and playlists
+                let (id, _, _) = missing.remove(idx);
+                mgmt.relink(id, track);
+            } else {
+                mgmt.insert_library(track);
+            }
+            changed = true;
+        }
+    }
+    // Anything left over has been deleted
+    let mut mgmt = m.lock().unwrap();
+    for (id, _, _) in missing {
+        mgmt.remove_library(id);
+    }
+    if changed {
+        mgmt.refresh_smart_playlists();
+        mgmt.library_tree = form_library_tree(&mgmt.database.tracks);
+        mgmt.library_updated = true;
+        mgmt.updated = true;
+    }
+}